@@ -0,0 +1,311 @@
+use std::cell::RefCell;
+
+use crate::aarch64::inst::{is_arith_imm, is_mem_offset};
+use crate::aarch64::{Context, FrameLayout, Func, Inst, Label, Memory, RegOrImm, Register};
+
+/// Registers addresses and sp adjustments too large for an immediate are
+/// computed in. Neither holds a value from one instruction to the next.
+const SCRATCH: [usize; 2] = [16, 17];
+
+/// Lay out the frame of `func` with `slots` bytes of stack slots, saving the
+/// callee-saved registers in `saved`, and insert the instructions setting it
+/// up at the start of the prologue, and the ones tearing it down again before
+/// returning and before each tail call. Stack slots too far from sp for a
+/// load or store are then addressed through a scratch register.
+///
+/// The frame record and the saved registers are pushed first, so their
+/// offsets stay small however large the frame is, and sp is lowered past
+/// the stack slots last. Tearing down resets sp from x29 when there is a
+/// frame record.
+pub fn insert_frame<'m>(ctx: &'m Context<'m>, func: &'m Func<'m>, slots: u64, saved: &[usize]) {
+    let labels = std::iter::once(func.prologue())
+        .chain(func.body().iter().copied())
+        .chain(std::iter::once(func.epilogue()))
+        .collect::<Vec<_>>();
+    let record = labels
+        .iter()
+        .any(|label| label.insts().iter().any(|inst| needs_record(inst)));
+    let frame = FrameLayout::new(slots, saved, record);
+    let saved_size = frame.saved_size();
+    {
+        let mut setup = vec![];
+        if record {
+            setup.extend([
+                ctx.sub(ctx.sp(), ctx.sp(), RegOrImm::Imm(saved_size + 16)),
+                ctx.stp(
+                    ctx.x(29),
+                    ctx.x(30),
+                    Memory::Stack {
+                        offset: saved_size as i64,
+                    },
+                ),
+                ctx.add(ctx.x(29), ctx.sp(), RegOrImm::Imm(saved_size)),
+            ]);
+        } else {
+            setup.extend(move_sp(ctx, saved_size, true));
+        }
+        for (i, regs) in frame.saved().chunks(2).enumerate() {
+            let slot = Memory::Stack {
+                offset: 16 * i as i64,
+            };
+            setup.push(match regs {
+                [a, b] => ctx.stp(ctx.x(*a), ctx.x(*b), slot),
+                [a] => ctx.str(ctx.x(*a), slot),
+                _ => unreachable!(),
+            });
+        }
+        setup.extend(move_sp(ctx, frame.slots_size(), true));
+        func.prologue().insts_mut().splice(0..0, setup);
+    }
+
+    let teardown = || frame_teardown(ctx, &frame);
+    func.epilogue().insts_mut().extend(teardown());
+    func.epilogue().insts_mut().push(ctx.ret());
+    for label in func.tail_calls().iter() {
+        let mut insts = label.insts_mut();
+        let branch = insts.len() - 1;
+        insts.splice(branch..branch, teardown());
+    }
+
+    for label in labels {
+        legalize_stack_offsets(ctx, label);
+    }
+    func.set_frame(frame);
+}
+
+/// Whether `inst` needs the frame record: a call overwrites the link
+/// register, and the arguments passed on the stack are read through x29.
+fn needs_record(inst: &Inst<'_>) -> bool {
+    match inst {
+        Inst::Bl { .. } => true,
+        Inst::Ldr {
+            src: Memory::BaseOffset { register, .. },
+            ..
+        } => matches!(*register.borrow(), Register::Physical(29)),
+        _ => false,
+    }
+}
+
+/// Lower sp by `size` bytes if `lower` is set, or raise it otherwise: with
+/// one instruction if the immediate fits, with two if it fits in 24 bits,
+/// and through a scratch register otherwise.
+fn move_sp<'m>(ctx: &'m Context<'m>, size: u64, lower: bool) -> Vec<&'m Inst<'m>> {
+    let op = |imm| {
+        if lower {
+            ctx.sub(ctx.sp(), ctx.sp(), imm)
+        } else {
+            ctx.add(ctx.sp(), ctx.sp(), imm)
+        }
+    };
+    if size == 0 {
+        vec![]
+    } else if is_arith_imm(size) {
+        vec![op(RegOrImm::Imm(size))]
+    } else if size < 1 << 24 {
+        vec![
+            op(RegOrImm::Imm(size & !0xfff)),
+            op(RegOrImm::Imm(size & 0xfff)),
+        ]
+    } else {
+        let scratch = ctx.x(SCRATCH[0]);
+        vec![
+            ctx.mov(scratch, RegOrImm::Imm(size)),
+            op(RegOrImm::Reg(RefCell::new(scratch))),
+        ]
+    }
+}
+
+/// Free the stack slots, restore the saved registers, the frame pointer and
+/// the link register, and pop the rest of the frame. With a frame record sp
+/// is reset from x29, however large the frame.
+fn frame_teardown<'m>(ctx: &'m Context<'m>, frame: &FrameLayout) -> Vec<&'m Inst<'m>> {
+    let saved_size = frame.saved_size();
+    let mut insts = vec![];
+    match frame.record_offset() {
+        Some(_) if frame.slots_size() > 0 => {
+            insts.push(ctx.sub(ctx.sp(), ctx.x(29), RegOrImm::Imm(saved_size)))
+        }
+        Some(_) => {}
+        None => insts.extend(move_sp(ctx, frame.slots_size(), false)),
+    }
+    for (i, regs) in frame.saved().chunks(2).enumerate() {
+        let slot = Memory::Stack {
+            offset: 16 * i as i64,
+        };
+        insts.push(match regs {
+            [a, b] => ctx.ldp(ctx.x(*a), ctx.x(*b), slot),
+            [a] => ctx.ldr(ctx.x(*a), slot),
+            _ => unreachable!(),
+        });
+    }
+    if frame.record_offset().is_some() {
+        insts.push(ctx.ldp(
+            ctx.x(29),
+            ctx.x(30),
+            Memory::Stack {
+                offset: saved_size as i64,
+            },
+        ));
+        insts.push(ctx.add(ctx.sp(), ctx.sp(), RegOrImm::Imm(saved_size + 16)));
+    } else {
+        insts.extend(move_sp(ctx, saved_size, false));
+    }
+    insts
+}
+
+/// Rewrite the loads and stores of stack slots in `label` whose offset from
+/// sp does not fit the instruction, adding the part of the offset above 4095
+/// to sp in a register first. A load computes the address in its own
+/// destination; a store in a scratch register other than its source.
+fn legalize_stack_offsets<'m>(ctx: &'m Context<'m>, label: &'m Label<'m>) {
+    let old = std::mem::take(&mut *label.insts_mut());
+    let mut insts = Vec::with_capacity(old.len());
+    for inst in old {
+        match inst {
+            Inst::Ldr {
+                dst,
+                src: Memory::Stack { offset },
+                size,
+            } if !is_mem_offset(*offset, *size) => {
+                let dst = *dst.borrow();
+                let src = stack_address(ctx, dst, *offset, &mut insts);
+                insts.push(ctx.ldr_sized(dst, src, *size));
+            }
+            Inst::Str {
+                src,
+                dst: Memory::Stack { offset },
+                size,
+            } if !is_mem_offset(*offset, *size) => {
+                let src = *src.borrow();
+                let scratch = match src {
+                    Register::Physical(r) if *r == SCRATCH[0] as u64 => ctx.x(SCRATCH[1]),
+                    _ => ctx.x(SCRATCH[0]),
+                };
+                let dst = stack_address(ctx, scratch, *offset, &mut insts);
+                insts.push(ctx.str_sized(src, dst, *size));
+            }
+            _ => insts.push(inst),
+        }
+    }
+    *label.insts_mut() = insts;
+}
+
+/// Push the instructions setting `base` to sp plus the high part of `offset`
+/// to `insts`, and return the memory operand for the rest of it.
+fn stack_address<'m>(
+    ctx: &'m Context<'m>,
+    base: &'m Register,
+    offset: i64,
+    insts: &mut Vec<&'m Inst<'m>>,
+) -> Memory<'m> {
+    let high = offset as u64 & !0xfff;
+    if is_arith_imm(high) {
+        insts.push(ctx.add(base, ctx.sp(), RegOrImm::Imm(high)));
+    } else {
+        insts.push(ctx.mov(base, RegOrImm::Imm(high)));
+        insts.push(ctx.add(base, ctx.sp(), RegOrImm::Reg(RefCell::new(base))));
+    }
+    Memory::BaseOffset {
+        register: RefCell::new(base),
+        offset: offset & 0xfff,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::Machine;
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    #[test]
+    fn large_frames() {
+        // Every variable gets its own stack slot at -O0, putting the last
+        // ones more than 32760 bytes above sp.
+        let n = 4200;
+        let mut src = String::from("func f(a: Int64) : Int64 {\nvar v0: Int64 = a;\n");
+        for i in 1..n {
+            src += &format!("var v{}: Int64 = v{} + {};\n", i, i - 1, i);
+        }
+        src += &format!("return v{} + v0;\n}}\n", n - 1);
+        src += "func g(x: Int64) : Int64 {\nreturn f(x) + f(x + 1) + x;\n}\n";
+        let f = |a: u64| 2 * a + (n * (n - 1) / 2);
+
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            let module = compile_source(&src, 0, regalloc);
+            let mut out = vec![];
+            module.dump(&mut out).unwrap();
+            let asm = String::from_utf8(out).unwrap();
+
+            // sp is lowered in two steps, and far slots are addressed
+            // through a register.
+            assert!(
+                asm.contains(", lsl #12\n\tsub\tsp, sp, #"),
+                "{:?}",
+                regalloc
+            );
+            assert!(
+                asm.contains("\tadd\tx16, sp, #8, lsl #12\n"),
+                "{:?}",
+                regalloc
+            );
+            assert!(asm.contains("\t// frame of "), "{:?}", regalloc);
+
+            let mut machine = Machine::new(module);
+            assert_eq!(machine.call("f", &[5]), f(5));
+            assert_eq!(machine.call("g", &[5]), f(5) + f(6) + 5);
+        }
+    }
+
+    #[test]
+    fn leaf_functions() {
+        let mut src = String::from(
+            "func add(a: Int64, b: Int64) : Int64 {
+                return a + b;
+            }
+            func busy(a: Int64) : Int64 {\n",
+        );
+        // More values live at once than there are caller-saved registers.
+        for i in 0..20 {
+            src += &format!("var v{i}: Int64 = a * {};\n", i + 1);
+        }
+        // Each is used twice, so none is folded into its use.
+        src += "return v0";
+        for i in 1..20 {
+            src += &format!(" + v{i}");
+        }
+        for i in 0..20 {
+            src += &format!(" | v{i}");
+        }
+        src += ";\n}\n";
+        src += "func caller(x: Int64) : Int64 {\nreturn add(busy(x), x) + 1;\n}\n";
+
+        let busy = (1..=20).fold(3 * 210, |acc, i| acc | (3 * i));
+        for regalloc in [RegAlloc::Linear, RegAlloc::Coloring] {
+            let module = compile_source(&src, 1, regalloc);
+            let mut out = vec![];
+            module.dump(&mut out).unwrap();
+            let asm = String::from_utf8(out).unwrap();
+            let function = |name: &str| {
+                let start = asm.find(&format!("_{name}:\n")).unwrap();
+                let end = start + asm[start..].find("\tret\n").unwrap();
+                &asm[start..end]
+            };
+
+            // A leaf using no stack has no frame at all, and one spilling
+            // or using callee-saved registers no frame record.
+            assert!(asm.contains("\t// no frame\n_add:"), "{}", asm);
+            assert!(!function("add").contains("sp"), "{}", asm);
+            assert!(
+                function("busy").contains("\tstp\tx19, x20, [sp]\n"),
+                "{}",
+                asm
+            );
+            assert!(!function("busy").contains("x29"), "{}", asm);
+            assert!(function("caller").contains("\tadd\tx29, sp, #"), "{}", asm);
+
+            let mut machine = Machine::new(module);
+            assert_eq!(machine.call("add", &[2, 3]), 5);
+            assert_eq!(machine.call("busy", &[3]), busy);
+            assert_eq!(machine.call("caller", &[3]), busy + 3 + 1);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::cell::RefCell;
+
+use super::{FunctionCG, Operand};
+use crate::aarch64::inst::Shift;
+use crate::aarch64::{ConditionCode, RegOrImm, Register};
+use crate::ir::{self, Value};
+
+// Patterns covering an IR instruction together with operands computed just
+// for it. Such an operand is folded: it gets no register of its own and is
+// only lowered as part of its user. Only pure instructions in the same block
+// as their single user are folded, so the values they read are still there
+// when the user is reached.
+
+/// The instructions of `func` folded into their users.
+pub fn folded_instructions<'m>(func: &'m ir::Func<'m>) -> Vec<&'m ir::Inst<'m>> {
+    let mut folded = vec![];
+    for block in func.blocks().iter() {
+        for inst in block.instructions().iter() {
+            if let Some((mul, _)) = match_multiply_add(inst) {
+                folded.push(mul);
+            } else if let Some((shift, _)) = match_shifted_operand(inst) {
+                folded.push(shift);
+            } else if let Some(cmp) = match_compare_branch(inst) {
+                folded.push(cmp);
+            }
+        }
+    }
+    folded
+}
+
+/// `val` as an instruction whose only use is in `user`, in the same block.
+fn single_use<'m>(val: &'m dyn ir::Value<'m>, user: &'m ir::Inst<'m>) -> Option<&'m ir::Inst<'m>> {
+    let inst = val.as_inst()?;
+    let users = inst.users();
+    let only_user = users.len() == 1 && std::ptr::eq(users[0], user);
+    (only_user && inst.parent() == user.parent()).then_some(inst)
+}
+
+/// An `add` or `sub` of a multiplication, as `madd` or `msub`: the
+/// multiplication and the value it is added to or subtracted from.
+fn match_multiply_add<'m>(
+    inst: &'m ir::Inst<'m>,
+) -> Option<(&'m ir::Inst<'m>, &'m dyn ir::Value<'m>)> {
+    let mul = |val| single_use(val, inst).filter(|i| matches!(&*i.kind(), ir::InstKind::Mul(_, _)));
+    match *inst.kind() {
+        ir::InstKind::Add(lhs, rhs) => mul(rhs)
+            .map(|m| (m, lhs))
+            .or_else(|| mul(lhs).map(|m| (m, rhs))),
+        ir::InstKind::Sub(lhs, rhs) => mul(rhs).map(|m| (m, lhs)),
+        _ => None,
+    }
+}
+
+/// An arithmetic or bitwise instruction with an operand shifted by a
+/// constant, which goes in its second operand: the shift, and the other
+/// operand.
+fn match_shifted_operand<'m>(
+    inst: &'m ir::Inst<'m>,
+) -> Option<(&'m ir::Inst<'m>, &'m dyn ir::Value<'m>)> {
+    let shift = |val| single_use(val, inst).filter(|i| shift_of(i).is_some());
+    match *inst.kind() {
+        ir::InstKind::Add(lhs, rhs)
+        | ir::InstKind::And(lhs, rhs)
+        | ir::InstKind::Or(lhs, rhs)
+        | ir::InstKind::Xor(lhs, rhs) => shift(rhs)
+            .map(|s| (s, lhs))
+            .or_else(|| shift(lhs).map(|s| (s, rhs))),
+        ir::InstKind::Sub(lhs, rhs) => shift(rhs).map(|s| (s, lhs)),
+        _ => None,
+    }
+}
+
+/// The shift and its amount, if `inst` shifts by a nonzero constant. An
+/// arithmetic shift of a narrow integer needs it sign-extended first, so it
+/// is left alone.
+fn shift_of(inst: &ir::Inst<'_>) -> Option<(Shift, u64)> {
+    let (shift, amount) = match &*inst.kind() {
+        ir::InstKind::LShl(_, amount) => (Shift::Lsl, *amount),
+        ir::InstKind::LShr(_, amount) => (Shift::Lsr, *amount),
+        ir::InstKind::AShr(_, amount) if inst.ty().bits() == 64 => (Shift::Asr, *amount),
+        _ => return None,
+    };
+    let amount = amount.as_constant()?.value() & 63;
+    (amount != 0).then_some((shift, amount))
+}
+
+/// The comparison a `CJump` branches on, which sets the flags for the
+/// branch itself.
+fn match_compare_branch<'m>(inst: &'m ir::Inst<'m>) -> Option<&'m ir::Inst<'m>> {
+    let ir::InstKind::CJump(cond, _, _) = *inst.kind() else {
+        return None;
+    };
+    single_use(cond, inst).filter(|i| is_comparison(i))
+}
+
+fn is_comparison(inst: &ir::Inst<'_>) -> bool {
+    matches!(
+        &*inst.kind(),
+        ir::InstKind::Eq(_, _)
+            | ir::InstKind::Ne(_, _)
+            | ir::InstKind::Gt(_, _)
+            | ir::InstKind::Ge(_, _)
+            | ir::InstKind::Lt(_, _)
+            | ir::InstKind::Le(_, _)
+    )
+}
+
+/// The block a `CJump` to `ifbb` and `elsebb` from `block` joins at, if
+/// either successor is the join or a block doing nothing but jumping there,
+/// along with the blocks the phis take their values from on each edge.
+fn match_select<'m>(
+    block: &'m ir::BasicBlock<'m>,
+    ifbb: &'m ir::BasicBlock<'m>,
+    elsebb: &'m ir::BasicBlock<'m>,
+) -> Option<SelectShape<'m>> {
+    let forward = |bb: &'m ir::BasicBlock<'m>| match bb.instructions().as_slice() {
+        [inst] => match *inst.kind() {
+            ir::InstKind::Jump(target) if bb.phis().is_empty() => Some(target),
+            _ => None,
+        },
+        _ => None,
+    };
+    let shape = match (forward(ifbb), forward(elsebb)) {
+        (Some(a), Some(b)) if a == b && ifbb != elsebb => (a, ifbb, elsebb),
+        (Some(a), _) if a == elsebb => (a, ifbb, block),
+        (_, Some(b)) if b == ifbb => (b, block, elsebb),
+        _ => return None,
+    };
+    (!shape.0.phis().is_empty()).then_some(shape)
+}
+
+/// The join block, and the predecessors of it whose incoming values are
+/// taken when the condition holds and when it does not.
+type SelectShape<'m> = (
+    &'m ir::BasicBlock<'m>,
+    &'m ir::BasicBlock<'m>,
+    &'m ir::BasicBlock<'m>,
+);
+
+impl<'m, 'cg> FunctionCG<'m, 'cg> {
+    /// Lower `inst` with a pattern covering it and its folded operands, if
+    /// one applies.
+    pub(super) fn select_pattern(&mut self, inst: &'m ir::Inst<'m>) -> bool {
+        if let Some((mul, addend)) = match_multiply_add(inst) {
+            let (lhs, rhs) = mul.kind().binary_operands().unwrap();
+            let dst = self.new_vreg();
+            self.value_map.insert(inst, Operand::Reg(dst));
+            let src1 = self.get_reg(lhs);
+            let src2 = self.get_reg(rhs);
+            let src3 = self.get_reg(addend);
+            match *inst.kind() {
+                ir::InstKind::Add(_, _) => self.emit(self.ctx.madd(dst, src1, src2, src3)),
+                _ => self.emit(self.ctx.msub(dst, src1, src2, src3)),
+            }
+            self.truncate(dst, inst.ty());
+            return true;
+        }
+
+        if let Some((shift_inst, other)) = match_shifted_operand(inst) {
+            let (shift, amount) = shift_of(shift_inst).unwrap();
+            let (shifted, _) = shift_inst.kind().binary_operands().unwrap();
+            let dst = self.new_vreg();
+            self.value_map.insert(inst, Operand::Reg(dst));
+            let src1 = self.get_reg(other);
+            let src2 = RegOrImm::Shifted {
+                reg: RefCell::new(self.get_reg(shifted)),
+                shift,
+                amount,
+            };
+            self.emit(match *inst.kind() {
+                ir::InstKind::Add(_, _) => self.ctx.add(dst, src1, src2),
+                ir::InstKind::Sub(_, _) => self.ctx.sub(dst, src1, src2),
+                ir::InstKind::And(_, _) => self.ctx.and(dst, src1, src2),
+                ir::InstKind::Or(_, _) => self.ctx.orr(dst, src1, src2),
+                ir::InstKind::Xor(_, _) => self.ctx.eor(dst, src1, src2),
+                _ => unreachable!(),
+            });
+            self.truncate(dst, inst.ty());
+            return true;
+        }
+
+        let ir::InstKind::CJump(cond, ifbb, elsebb) = *inst.kind() else {
+            return false;
+        };
+        if ifbb == elsebb {
+            return false;
+        }
+        let block = self.curr_block.unwrap();
+        if let Some((join, if_pred, else_pred)) = match_select(block, ifbb, elsebb) {
+            let cc = self.emit_condition(inst, cond);
+            self.emit_select(cc, join, if_pred, else_pred);
+            return true;
+        }
+        if match_compare_branch(inst).is_some() {
+            let iflabel = self.edge_label(ifbb);
+            let elselabel = self.edge_label(elsebb);
+            let cc = self.emit_condition(inst, cond);
+            self.emit(self.ctx.b_cond(cc, iflabel));
+            self.emit(self.ctx.b(elselabel));
+            return true;
+        }
+        false
+    }
+
+    /// Set the flags for the condition of the `CJump` `inst`, and return
+    /// the condition code holding when it is true.
+    fn emit_condition(
+        &mut self,
+        inst: &'m ir::Inst<'m>,
+        cond: &'m dyn ir::Value<'m>,
+    ) -> ConditionCode {
+        if let Some(cmp) = match_compare_branch(inst) {
+            return self.emit_compare(cmp);
+        }
+        let cond = self.get_reg(cond);
+        self.emit(self.ctx.cmp(cond, RegOrImm::Imm(0)));
+        ConditionCode::NE
+    }
+
+    /// Set the phis of `join` to their incoming values from `if_pred` when
+    /// `cc` holds and from `else_pred` otherwise, then jump to `join`.
+    fn emit_select(
+        &mut self,
+        cc: ConditionCode,
+        join: &'m ir::BasicBlock<'m>,
+        if_pred: &'m ir::BasicBlock<'m>,
+        else_pred: &'m ir::BasicBlock<'m>,
+    ) {
+        let phis = join.phis();
+        // Like the copies on an edge, the phis are set in parallel.
+        let parallel = phis.len() > 1;
+        let mut temps = vec![];
+        for phi in phis.iter() {
+            let dst = self.get_reg(*phi);
+            let tmp = if parallel { self.new_vreg() } else { dst };
+            let a = phi.incoming_value(if_pred).unwrap();
+            let b = phi.incoming_value(else_pred).unwrap();
+            self.emit_conditional_value(tmp, cc, a, b);
+            temps.push((dst, tmp));
+        }
+        if parallel {
+            for (dst, tmp) in temps {
+                self.emit(self.ctx.mov(dst, RegOrImm::Reg(RefCell::new(tmp))));
+            }
+        }
+        let label = self.block_map.get(&join).unwrap();
+        self.emit(self.ctx.b(label));
+    }
+
+    /// Set `dst` to `a` if `cc` holds and to `b` otherwise: with `cset` or
+    /// `cinc` for constants one apart, and `csel` for anything else.
+    fn emit_conditional_value(
+        &mut self,
+        dst: &'m Register,
+        cc: ConditionCode,
+        a: &'m dyn ir::Value<'m>,
+        b: &'m dyn ir::Value<'m>,
+    ) {
+        let (cc, low) = match (self.get_reg_or_imm(a), self.get_reg_or_imm(b)) {
+            (RegOrImm::Imm(a), RegOrImm::Imm(b)) if a == b.wrapping_add(1) => (cc, b),
+            (RegOrImm::Imm(a), RegOrImm::Imm(b)) if b == a.wrapping_add(1) => (cc.invert(), a),
+            _ if a == b => {
+                let src = self.get_reg_or_imm(a);
+                self.emit(self.ctx.mov(dst, src));
+                return;
+            }
+            _ => {
+                let src1 = self.get_reg(a);
+                let src2 = self.get_reg(b);
+                self.emit(self.ctx.csel(dst, src1, src2, cc));
+                return;
+            }
+        };
+        if low == 0 {
+            self.emit(self.ctx.cset(dst, cc));
+        } else {
+            let src = self.new_vreg();
+            self.emit(self.ctx.mov(src, RegOrImm::Imm(low)));
+            self.emit(self.ctx.cinc(dst, src, cc));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::Machine;
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    const SRC: &str = "
+        func max(a: Int64, b: Int64) : Int64 {
+            var m: Int64 = b;
+            if a > b {
+                m = a;
+            }
+            return m;
+        }
+        func positive(a: Int64) : Int64 {
+            var s: Int64 = 0;
+            if 0 < a {
+                s = 1;
+            }
+            return s;
+        }
+        func pick(a: Int64, b: Int64) : Int64 {
+            var s: Int64;
+            if a == 3 {
+                s = 5;
+            } else {
+                s = 4;
+            }
+            return s + (b << 3) - a * b;
+        }
+        func count(n: Int64) : Int64 {
+            var i: Int64 = 0;
+            var s: Int64 = 0;
+            while i < n {
+                s = s + i * i;
+                i = i + 1;
+            }
+            return s ^ (n >> 1);
+        }";
+
+    #[test]
+    fn patterns() {
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            for opt_level in 1..=2 {
+                let module = compile_source(SRC, opt_level, regalloc);
+                let mut out = vec![];
+                module.dump(&mut out).unwrap();
+                let asm = String::from_utf8(out).unwrap();
+                let function = |name: &str| {
+                    let start = asm.find(&format!("_{name}:\n")).unwrap();
+                    let end = start + asm[start..].find("\tret\n").unwrap();
+                    &asm[start..end]
+                };
+
+                assert!(function("max").contains("\tcsel\t"), "{}", asm);
+                assert!(function("max").contains(", gt\n"), "{}", asm);
+                assert!(function("positive").contains("\tcmp\t"), "{}", asm);
+                assert!(function("positive").contains(", #0\n"), "{}", asm);
+                assert!(function("positive").contains("\tcset\t"), "{}", asm);
+                assert!(function("pick").contains("\tcinc\t"), "{}", asm);
+                assert!(function("pick").contains(", lsl #3\n"), "{}", asm);
+                assert!(function("pick").contains("\tmsub\t"), "{}", asm);
+                assert!(function("count").contains("\tmadd\t"), "{}", asm);
+                assert!(
+                    function("count").contains("\tb.lt\t")
+                        || function("count").contains("\tb.ge\t"),
+                    "{}",
+                    asm
+                );
+                assert!(function("count").contains(", asr #1\n"), "{}", asm);
+                assert!(!asm.contains("\tmul\t"), "{}", asm);
+
+                let mut machine = Machine::new(module);
+                for (a, b) in [(3, 7), (7, 3), (-2i64 as u64, 1)] {
+                    assert_eq!(
+                        machine.call("max", &[a, b]),
+                        (a as i64).max(b as i64) as u64
+                    );
+                    assert_eq!(machine.call("positive", &[a]), (a as i64 > 0) as u64);
+                    let s = if a == 3 { 5 } else { 4 };
+                    let pick = (s + (b << 3)).wrapping_sub(a.wrapping_mul(b));
+                    assert_eq!(machine.call("pick", &[a, b]), pick);
+                }
+                for n in [0u64, 1, 5, 10] {
+                    let s: u64 = (0..n).map(|i| i * i).sum();
+                    assert_eq!(machine.call("count", &[n]), s ^ (n >> 1));
+                }
+            }
+        }
+    }
+}
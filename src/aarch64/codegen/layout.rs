@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::aarch64::{Context, Func, Inst, Label};
+
+/// Reorder the body of `func` so that control falls through to the next
+/// label as often as possible. Branches through labels doing nothing but
+/// branching on are retargeted first, and labels no longer reached are
+/// dropped.
+///
+/// Labels are then placed in chains, each followed by its hottest unplaced
+/// successor: the one in the most deeply nested loop, or else the one
+/// following it before. A loop entered at a header ending in a conditional
+/// branch is rotated, so that the header goes after the rest of the loop and
+/// each iteration takes a single branch back to the top.
+///
+/// Every label is made to end in a branch to its former successor, so the
+/// order is free; the branches to the label placed next are left for the
+/// peephole pass to remove.
+pub fn run<'m>(ctx: &'m Context<'m>, func: &'m Func<'m>) {
+    let body = func.body().clone();
+    if body.is_empty() {
+        return;
+    }
+    for (i, label) in body.iter().enumerate() {
+        if !matches!(label.insts().last(), Some(Inst::B { .. } | Inst::Ret)) {
+            let next = body.get(i + 1).copied().unwrap_or(func.epilogue());
+            label.add_instruction(ctx.b(next));
+        }
+    }
+    thread_branches(ctx, &body, func.epilogue());
+
+    // The prologue falls through to the first label, which stays first.
+    let index: HashMap<*const Label<'m>, usize> = body
+        .iter()
+        .enumerate()
+        .map(|(i, label)| (*label as *const Label<'m>, i))
+        .collect();
+    let succs: Vec<Vec<usize>> = body
+        .iter()
+        .map(|label| {
+            branch_targets(label)
+                .iter()
+                .filter_map(|target| index.get(&(*target as *const Label<'m>)).copied())
+                .collect()
+        })
+        .collect();
+    let mut placement = Placement::new(&body, succs);
+    placement.place_all();
+    *func.body_mut() = placement.order.iter().map(|i| body[*i]).collect();
+}
+
+/// The labels the branches at the end of `label` go to, the conditional one
+/// first.
+fn branch_targets<'m>(label: &Label<'m>) -> Vec<&'m Label<'m>> {
+    label
+        .insts()
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::B { label } => Some(*label),
+            _ => inst.conditional_target(),
+        })
+        .collect()
+}
+
+/// Make branches to a label holding nothing but a branch to another label
+/// of the function go straight there.
+fn thread_branches<'m>(ctx: &'m Context<'m>, body: &[&'m Label<'m>], epilogue: &'m Label<'m>) {
+    let is_local = |label: &Label<'m>| {
+        std::ptr::eq(label, epilogue) || body.iter().any(|l| std::ptr::eq(*l, label))
+    };
+    let forward = |label: &'m Label<'m>| match label.insts().as_slice() {
+        [Inst::B { label: target }] if is_local(target) => Some(*target),
+        _ => None,
+    };
+    // Follow the chain of forwarding labels, stopping at a cycle.
+    let destination = |label: &'m Label<'m>| {
+        let mut dest = label;
+        for _ in 0..body.len() {
+            match forward(dest) {
+                Some(next) => dest = next,
+                None => break,
+            }
+        }
+        dest
+    };
+
+    for label in body {
+        let mut insts = label.insts_mut();
+        for inst in insts.iter_mut() {
+            let target = match inst {
+                Inst::B { label } => *label,
+                _ => match inst.conditional_target() {
+                    Some(label) => label,
+                    None => continue,
+                },
+            };
+            let dest = destination(target);
+            if !std::ptr::eq(dest, target) {
+                *inst = retarget(ctx, inst, dest);
+            }
+        }
+    }
+}
+
+/// The same branch as `branch`, to `target`.
+fn retarget<'m>(ctx: &'m Context<'m>, branch: &'m Inst<'m>, target: &'m Label<'m>) -> &'m Inst<'m> {
+    match branch {
+        Inst::B { .. } => ctx.b(target),
+        Inst::BCond { cond, .. } => ctx.b_cond(*cond, target),
+        Inst::Cbnz { src, .. } => ctx.cbnz(*src.borrow(), target),
+        Inst::Cbz { src, .. } => ctx.cbz(*src.borrow(), target),
+        _ => unreachable!(),
+    }
+}
+
+/// Greedy chaining of the labels, by index into the body.
+struct Placement {
+    succs: Vec<Vec<usize>>,
+    conditional: Vec<bool>,
+    // The labels in each loop, by header.
+    loops: HashMap<usize, HashSet<usize>>,
+    depth: Vec<usize>,
+    placed: Vec<bool>,
+    rotated: HashSet<usize>,
+    order: Vec<usize>,
+}
+
+impl Placement {
+    fn new(body: &[&Label<'_>], succs: Vec<Vec<usize>>) -> Placement {
+        let n = body.len();
+        let conditional = body
+            .iter()
+            .map(|label| {
+                label
+                    .insts()
+                    .iter()
+                    .any(|inst| inst.is_conditional_branch())
+            })
+            .collect();
+        let loops = find_loops(&succs);
+        let mut depth = vec![0; n];
+        for blocks in loops.values() {
+            for b in blocks {
+                depth[*b] += 1;
+            }
+        }
+        Placement {
+            succs,
+            conditional,
+            loops,
+            depth,
+            placed: vec![false; n],
+            rotated: HashSet::new(),
+            order: vec![],
+        }
+    }
+
+    /// Place the labels reachable from the first one, starting there.
+    fn place_all(&mut self) {
+        self.rotated.insert(0);
+        self.chain(0);
+        for (i, reachable) in reachable(&self.succs).into_iter().enumerate() {
+            if reachable && !self.placed[i] {
+                self.chain(i);
+            }
+        }
+    }
+
+    fn chain(&mut self, mut label: usize) {
+        while !self.placed[label] {
+            if let Some(first) = self.rotation_start(label) {
+                // The rest of the loop goes first, and reaching the header
+                // from its end places it.
+                self.rotated.insert(label);
+                self.chain(first);
+                if self.placed[label] {
+                    return;
+                }
+            }
+            self.placed[label] = true;
+            self.order.push(label);
+            match self.best_successor(label, |_| true) {
+                Some(next) => label = next,
+                None => return,
+            }
+        }
+    }
+
+    /// The label to start the loop headed by `header` at, if it is to be
+    /// rotated.
+    fn rotation_start(&self, header: usize) -> Option<usize> {
+        if self.rotated.contains(&header) || !self.conditional[header] {
+            return None;
+        }
+        let blocks = self.loops.get(&header)?;
+        self.best_successor(header, |s| s != header && blocks.contains(&s))
+    }
+
+    fn best_successor(&self, label: usize, allowed: impl Fn(usize) -> bool) -> Option<usize> {
+        self.succs[label]
+            .iter()
+            .copied()
+            .filter(|s| !self.placed[*s] && allowed(*s))
+            .max_by_key(|s| (self.depth[*s], *s == label + 1, std::cmp::Reverse(*s)))
+    }
+}
+
+/// Which labels control can reach from the first one.
+fn reachable(succs: &[Vec<usize>]) -> Vec<bool> {
+    let mut seen = vec![false; succs.len()];
+    let mut stack = vec![0];
+    while let Some(i) = stack.pop() {
+        if !std::mem::replace(&mut seen[i], true) {
+            stack.extend(succs[i].iter().copied());
+        }
+    }
+    seen
+}
+
+/// The natural loops of the graph, by header: for each edge back to a label
+/// on the depth-first search path, the header and the labels reaching the
+/// edge without going through the header.
+fn find_loops(succs: &[Vec<usize>]) -> HashMap<usize, HashSet<usize>> {
+    let n = succs.len();
+    let mut preds = vec![vec![]; n];
+    for (i, ss) in succs.iter().enumerate() {
+        for s in ss {
+            preds[*s].push(i);
+        }
+    }
+
+    let mut back_edges = vec![];
+    let mut visited = vec![false; n];
+    let mut on_path = vec![false; n];
+    // (label, index of the next successor to visit)
+    let mut path = vec![(0, 0)];
+    visited[0] = true;
+    on_path[0] = true;
+    while let Some((i, k)) = path.last_mut() {
+        let i = *i;
+        if let Some(&s) = succs[i].get(*k) {
+            *k += 1;
+            if on_path[s] {
+                back_edges.push((i, s));
+            } else if !visited[s] {
+                visited[s] = true;
+                on_path[s] = true;
+                path.push((s, 0));
+            }
+        } else {
+            on_path[i] = false;
+            path.pop();
+        }
+    }
+
+    let mut loops: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for (latch, header) in back_edges {
+        let blocks = loops
+            .entry(header)
+            .or_insert_with(|| HashSet::from([header]));
+        let mut stack = vec![latch];
+        while let Some(b) = stack.pop() {
+            if blocks.insert(b) {
+                stack.extend(preds[b].iter().copied());
+            }
+        }
+    }
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::Machine;
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    fn dump(src: &str, opt_level: u8, regalloc: RegAlloc) -> String {
+        let module = compile_source(src, opt_level, regalloc);
+        let mut out = vec![];
+        module.dump(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn rotated_loops() {
+        let src = include_str!("../../../tests/sum.toy");
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            let asm = dump(src, 1, regalloc);
+            let lines: Vec<&str> = asm.lines().collect();
+            // The loop condition is tested at the bottom, branching back up.
+            let test = lines
+                .iter()
+                .position(|l| l.starts_with("\tb.gt\t"))
+                .unwrap();
+            let header = format!("{}:", &lines[test]["\tb.gt\t".len()..]);
+            assert!(lines[..test].contains(&header.as_str()), "{}", asm);
+            // Jumping into the loop is the only unconditional branch.
+            let branches = lines.iter().filter(|l| l.starts_with("\tb\t")).count();
+            assert_eq!(branches, 1, "{}", asm);
+
+            let module = compile_source(src, 1, regalloc);
+            let mut machine = Machine::new(module);
+            assert_eq!(machine.call("sum", &[10]), 55);
+            assert_eq!(machine.call("sum", &[0]), 0);
+        }
+    }
+
+    #[test]
+    fn nested_loops() {
+        let src = "
+            func count(n: Int64) : Int64 {
+                var total : Int64 = 0;
+                var i : Int64 = 0;
+                while i < n {
+                    var j : Int64 = 0;
+                    while j < i {
+                        if (i + j) % 3 == 0 {
+                            total = total + 1;
+                        }
+                        j = j + 1;
+                    }
+                    i = i + 1;
+                }
+                return total;
+            }
+        ";
+        let expected = |n: u64| {
+            (0..n)
+                .flat_map(|i| (0..i).map(move |j| (i + j) % 3 == 0))
+                .filter(|b| *b)
+                .count() as u64
+        };
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            for opt_level in 0..=2 {
+                let asm = dump(src, opt_level, regalloc);
+                let lines: Vec<&str> = asm.lines().collect();
+                for pair in lines.windows(2) {
+                    if let Some(target) = pair[0].strip_prefix("\tb\t") {
+                        assert_ne!(pair[1], format!("{target}:"), "{}", asm);
+                    }
+                }
+
+                let module = compile_source(src, opt_level, regalloc);
+                let mut machine = Machine::new(module);
+                for n in [0, 1, 5, 12] {
+                    assert_eq!(machine.call("count", &[n]), expected(n));
+                }
+            }
+        }
+    }
+}
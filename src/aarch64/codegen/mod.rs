@@ -1,12 +1,23 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
-
-use self::regalloc::NaiveRegisterAllocator;
-use crate::aarch64::{ConditionCode, Context, Func, Label, Memory, Module, RegOrImm, Register};
-use crate::ir;
-
+use std::collections::{HashMap, HashSet};
+
+pub use self::regalloc::RegAlloc;
+use self::regalloc::{ColoringAllocator, LinearScanAllocator, NaiveRegisterAllocator};
+use crate::aarch64::inst::{is_arith_imm, is_logical_imm, is_mov_imm};
+use crate::aarch64::{
+    ConditionCode, Context, Func, Inst, Label, Memory, Module, RegOrImm, Register,
+};
+use crate::ir::{self, Value};
+
+mod frame;
+mod isel;
+mod layout;
+mod peephole;
 mod regalloc;
 
+/// Number of arguments passed in registers, in x0 to x7.
+const ARG_REGS: usize = 8;
+
 pub struct Codegen<'m> {
     unit: &'m Module<'m>,
     ctx: &'m Context<'m>,
@@ -27,19 +38,23 @@ impl<'m> Codegen<'m> {
         self.unit
     }
 
-    pub fn visit_unit(&mut self, unit: &'m ir::Module<'m>, no_regalloc: bool) {
+    /// Lower `unit`, running `regalloc` on each function, or leaving the
+    /// virtual registers in place if it is `None`.
+    pub fn visit_unit(&mut self, unit: &'m ir::Module<'m>, regalloc: Option<RegAlloc>) {
         let functions_ir = unit.functions();
         let mut functions = self.unit.functions_mut();
 
         for func in functions_ir.iter() {
             let f = self.ctx.new_func(func.name().to_string());
+            f.set_global(!func.has_attribute(ir::Attribute::Internal));
             f.set_prologue(self.ctx.new_label(format!("_{}", func.name())));
             f.set_epilogue(self.ctx.new_label(format!("{}_epilogue", func.name())));
             self.func_map.insert(func.name().to_owned(), f.prologue());
             functions.push(f);
 
             let mut codegen = FunctionCG::new(self.ctx, &self.func_map, func, f);
-            codegen.visit_function(no_regalloc);
+            codegen.visit_function(regalloc);
+            expand_wide_immediates(self.ctx, f);
         }
     }
 }
@@ -49,9 +64,14 @@ struct FunctionCG<'m, 'cg> {
     func_map: &'cg HashMap<String, &'m Label<'m>>,
     func_ir: &'m ir::Func<'m>,
     target: &'m Func<'m>,
-    value_map: HashMap<&'m dyn ir::Value, Operand<'m>>,
+    value_map: HashMap<&'m dyn ir::Value<'m>, Operand<'m>>,
     block_map: HashMap<&'m ir::BasicBlock<'m>, &'m Label<'m>>,
+    curr_block: Option<&'m ir::BasicBlock<'m>>,
     curr_label: Option<&'m Label<'m>>,
+    // Labels for critical edges carrying phi copies, placed after the body.
+    edge_labels: Vec<&'m Label<'m>>,
+    // Instructions lowered as part of their user rather than on their own.
+    folded: HashSet<&'m dyn ir::Value<'m>>,
     next_vreg_id: u64,
     next_stack_offset: i64,
 }
@@ -70,56 +90,145 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
             target: func,
             value_map: HashMap::new(),
             block_map: HashMap::new(),
+            curr_block: None,
             curr_label: None,
+            edge_labels: Vec::new(),
+            folded: HashSet::new(),
             next_vreg_id: 0,
             next_stack_offset: 0,
         }
     }
 
-    fn visit_function(&'cg mut self, no_regalloc: bool) {
+    fn visit_function(&'cg mut self, regalloc: Option<RegAlloc>) {
         // https://developer.arm.com/documentation/102374/0102/Procedure-Call-Standard
         // X0-X7 -- Parameter and Result Registers
-        for (reg, param) in self.func_ir.params().iter().enumerate() {
+        //
+        // Parameters are copied out of their argument registers right away,
+        // as the registers are clobbered by any call. The ones after the
+        // eighth are loaded from the caller's frame, right above our frame
+        // record.
+        self.curr_label = Some(self.target.prologue());
+        for (i, param) in self.func_ir.params().iter().enumerate() {
             let param = *param;
-            let param = param as &dyn ir::Value;
-            self.value_map.insert(param, Operand::Reg(self.ctx.x(reg)));
+            let param = param as &dyn ir::Value<'m>;
+            let vreg = self.new_vreg();
+            if i < ARG_REGS {
+                self.emit(
+                    self.ctx
+                        .mov(vreg, RegOrImm::Reg(RefCell::new(self.ctx.x(i)))),
+                );
+            } else {
+                let arg = Memory::BaseOffset {
+                    register: RefCell::new(self.ctx.x(29)),
+                    offset: 16 + 8 * (i - ARG_REGS) as i64,
+                };
+                self.emit(self.ctx.ldr(vreg, arg));
+            }
+            self.value_map.insert(param, Operand::Reg(vreg));
         }
 
+        // The arguments passed on the stack go at the bottom of the frame,
+        // so the space for the call with the most of them comes before any
+        // stack slot.
+        self.next_stack_offset = self.outgoing_args_size();
+
         for constant in self.func_ir.constants().iter() {
             self.value_map
                 .insert(*constant, Operand::Imm(constant.value()));
         }
 
         for block in self.func_ir.blocks().iter() {
-            self.block_map.insert(
-                *block,
-                self.ctx
-                    .new_label(format!("{}_{}", self.target.name(), block.name())),
-            );
+            let label = self
+                .ctx
+                .new_label(format!("{}_{}", self.target.name(), block.name()));
+            self.block_map.insert(*block, label);
+            self.target.body_mut().push(label);
+
+            // A phi is written by a copy at the end of each predecessor, so
+            // its register is needed before the phi itself is visited.
+            for phi in block.phis() {
+                let vreg = self.new_vreg();
+                self.value_map.insert(phi, Operand::Reg(vreg));
+            }
         }
 
-        for block in self.func_ir.blocks().iter() {
+        self.folded = isel::folded_instructions(self.func_ir)
+            .into_iter()
+            .map(|inst| inst as &dyn ir::Value<'m>)
+            .collect();
+
+        // Visit the blocks in reverse post order so that every value is
+        // defined before its uses are lowered. Unreachable blocks are left
+        // empty.
+        for block in ir::analysis::reverse_post_order(self.func_ir) {
             self.visit_block(block);
         }
+        self.target
+            .body_mut()
+            .extend(std::mem::take(&mut self.edge_labels));
 
-        if no_regalloc {
-            return;
+        let (ctx, target) = (self.ctx, self.target);
+        peephole::run(ctx, target);
+        match regalloc {
+            Some(RegAlloc::Naive) => {
+                NaiveRegisterAllocator::new(self.ctx, self.target, self).run();
+            }
+            Some(RegAlloc::Linear) => {
+                LinearScanAllocator::new(self.ctx, self.target, self).run();
+            }
+            Some(RegAlloc::Coloring) => {
+                ColoringAllocator::new(self.ctx, self.target, self).run();
+            }
+            None => {}
         }
-        let mut allocator = NaiveRegisterAllocator::new(self.ctx, self.target, self);
-        allocator.run();
+        layout::run(ctx, target);
+        peephole::run(ctx, target);
     }
 
     fn visit_block(&mut self, block: &'m ir::BasicBlock<'m>) {
-        self.target
-            .body_mut()
-            .push(self.block_map.get(&block).unwrap());
+        self.curr_block = Some(block);
         self.curr_label = Some(self.block_map.get(&block).unwrap());
-        for inst in block.instructions().iter() {
+        let insts = block.instructions();
+        for (i, inst) in insts.iter().enumerate() {
+            if can_tail_call(inst) {
+                // The verifier keeps the return of the result right after the
+                // call, and that return is left to the callee.
+                debug_assert!(
+                    insts.get(i + 1).is_some_and(|next| match &*next.kind() {
+                        ir::InstKind::Return(Some(val)) => val.addr() == inst.addr(),
+                        ir::InstKind::Return(None) => true,
+                        _ => false,
+                    }),
+                    "'{}' is not in tail position",
+                    inst
+                );
+                self.emit_tail_call(inst);
+                break;
+            }
+            if self.folded.contains(&(*inst as &dyn ir::Value<'m>)) {
+                continue;
+            }
             self.visit_instruction(inst);
         }
     }
 
-    fn get_reg(&mut self, val: &dyn ir::Value) -> &'m Register {
+    /// Pass the arguments and branch to the callee, which returns straight
+    /// to our caller. The frame teardown is inserted before the branch once
+    /// the frame size is known.
+    fn emit_tail_call(&mut self, inst: &'m ir::Inst<'m>) {
+        let ir::InstKind::Call(callee, args) = &*inst.kind() else {
+            unreachable!();
+        };
+        for (i, arg) in args.iter().enumerate() {
+            let arg = self.get_reg_or_imm(*arg);
+            self.emit(self.ctx.mov(self.ctx.x(i), arg));
+        }
+        let callee = self.func_map.get(callee.name()).unwrap();
+        self.emit(self.ctx.b(callee));
+        self.target.add_tail_call(self.curr_label.unwrap());
+    }
+
+    fn get_reg(&mut self, val: &dyn ir::Value<'m>) -> &'m Register {
         match *self.value_map.get(&val).unwrap() {
             Operand::Imm(i) => {
                 let reg = self.new_vreg();
@@ -131,17 +240,87 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
         }
     }
 
-    fn get_mem(&self, val: &dyn ir::Value) -> Memory<'m> {
+    /// Copy the incoming values of the phis in `target` for the edge from
+    /// the current block.
+    fn emit_phi_copies(&mut self, target: &'m ir::BasicBlock<'m>) {
+        let pred = self.curr_block.unwrap();
+        let phis = target.phis();
+        let copies: Vec<_> = phis
+            .iter()
+            .map(|phi| {
+                let dst = self.get_reg(*phi);
+                let src = self.get_reg_or_imm(phi.incoming_value(pred).unwrap());
+                (dst, src)
+            })
+            .collect();
+
+        // The copies happen in parallel, so when a phi feeds another one, go
+        // through temporaries.
+        if copies.len() == 1 {
+            let (dst, src) = copies.into_iter().next().unwrap();
+            self.emit(self.ctx.mov(dst, src));
+            return;
+        }
+        let mut temps = vec![];
+        for (dst, src) in copies {
+            let tmp = self.new_vreg();
+            self.emit(self.ctx.mov(tmp, src));
+            temps.push((dst, tmp));
+        }
+        for (dst, tmp) in temps {
+            self.emit(self.ctx.mov(dst, RegOrImm::Reg(RefCell::new(tmp))));
+        }
+    }
+
+    /// Label to branch to for the edge from the current block to `target`.
+    /// Edges into blocks with phis get their own label holding the copies.
+    fn edge_label(&mut self, target: &'m ir::BasicBlock<'m>) -> &'m Label<'m> {
+        let label = *self.block_map.get(&target).unwrap();
+        if target.phis().is_empty() {
+            return label;
+        }
+
+        let curr_label = self.curr_label;
+        let edge = self.ctx.new_label(format!(
+            "{}_{}_{}",
+            self.target.name(),
+            self.curr_block.unwrap().name(),
+            target.name()
+        ));
+        self.curr_label = Some(edge);
+        self.emit_phi_copies(target);
+        self.emit(self.ctx.b(label));
+        self.curr_label = curr_label;
+        self.edge_labels.push(edge);
+        edge
+    }
+
+    fn get_mem(&self, val: &dyn ir::Value<'m>) -> Memory<'m> {
         match self.value_map.get(&val).unwrap() {
             Operand::Memory(m) => m.clone(),
             _ => panic!("Expected memory operand"),
         }
     }
 
-    fn get_reg_or_imm(&self, val: &dyn ir::Value) -> RegOrImm<'m> {
-        // FIXME If the constant is too large, aarch64 instruction cannot accept it as
-        // an immediate operand. We need to split the constant and use a few more
-        // instructions to construct the value.
+    /// Like `get_reg_or_imm`, but constants the instruction cannot encode
+    /// are moved into a register first.
+    fn get_reg_or_encodable_imm(
+        &mut self,
+        val: &dyn ir::Value<'m>,
+        encodable: fn(u64) -> bool,
+    ) -> RegOrImm<'m> {
+        match self.get_reg_or_imm(val) {
+            RegOrImm::Imm(i) if !encodable(i) => RegOrImm::Reg(RefCell::new(self.get_reg(val))),
+            operand => operand,
+        }
+    }
+
+    /// The register or constant `val` lives in. Instructions other than
+    /// `mov` only take constants of a particular form, so their operands
+    /// come from `get_reg_or_encodable_imm`. Any constant can be moved; the
+    /// ones a single `mov` cannot take are built up after register
+    /// allocation.
+    fn get_reg_or_imm(&self, val: &dyn ir::Value<'m>) -> RegOrImm<'m> {
         match self.value_map.get(&val).unwrap() {
             Operand::Imm(i) => RegOrImm::Imm(*i),
             Operand::Reg(r) => RegOrImm::Reg(RefCell::new(r)),
@@ -149,46 +328,62 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
         }
     }
 
+    /// The second operand of an `add` or `sub` of `val`, and whether the
+    /// operation is to be flipped, since only the negation of the constant
+    /// fits in the instruction. `ty` is the type of the result, whose bits
+    /// above its width are cleared anyway.
+    fn get_arith_operand(&mut self, val: &dyn ir::Value<'m>, ty: ir::Type) -> (RegOrImm<'m>, bool) {
+        if let RegOrImm::Imm(i) = self.get_reg_or_imm(val) {
+            let negated = ty.truncate(i.wrapping_neg());
+            if !is_arith_imm(i) && is_arith_imm(negated) {
+                return (RegOrImm::Imm(negated), true);
+            }
+        }
+        (self.get_reg_or_encodable_imm(val, is_arith_imm), false)
+    }
+
     fn visit_instruction(&mut self, inst: &'m ir::Inst<'m>) {
-        match inst.kind() {
-            ir::InstKind::Alloca => {
+        if self.select_pattern(inst) {
+            return;
+        }
+        match &*inst.kind() {
+            // Every type fits in a stack slot.
+            ir::InstKind::Alloca(_) => {
                 let stack_slot = self.new_stack_slot();
                 self.value_map.insert(inst, Operand::Memory(stack_slot));
             }
             ir::InstKind::Store(val, ptr) => {
+                let size = val.ty().size_in_bytes();
                 let val = self.get_reg(*val);
                 let ptr = self.get_mem(*ptr);
 
-                self.emit(self.ctx.str(val, ptr.clone()));
+                self.emit(self.ctx.str_sized(val, ptr.clone(), size));
             }
             ir::InstKind::Load(ptr) => {
                 let dst = self.new_vreg();
                 let ptr = self.get_mem(*ptr);
                 self.value_map.insert(inst, Operand::Reg(dst));
-                self.emit(self.ctx.ldr(dst, ptr.clone()));
+                self.emit(
+                    self.ctx
+                        .ldr_sized(dst, ptr.clone(), inst.ty().size_in_bytes()),
+                );
             }
-            ir::InstKind::Eq(lhs, rhs)
-            | ir::InstKind::Ne(lhs, rhs)
-            | ir::InstKind::Gt(lhs, rhs)
-            | ir::InstKind::Ge(lhs, rhs)
-            | ir::InstKind::Lt(lhs, rhs)
-            | ir::InstKind::Le(lhs, rhs) => {
+            // Narrow integers are kept zero-extended in registers already.
+            ir::InstKind::ZExt(val) => {
                 let dst = self.new_vreg();
                 self.value_map.insert(inst, Operand::Reg(dst));
-
-                let src1 = self.get_reg(*lhs);
-                let src2 = self.get_reg_or_imm(*rhs);
-
-                let cc = match inst.kind() {
-                    ir::InstKind::Eq(_, _) => ConditionCode::EQ,
-                    ir::InstKind::Ne(_, _) => ConditionCode::NE,
-                    ir::InstKind::Gt(_, _) => ConditionCode::GT,
-                    ir::InstKind::Ge(_, _) => ConditionCode::GE,
-                    ir::InstKind::Lt(_, _) => ConditionCode::LT,
-                    ir::InstKind::Le(_, _) => ConditionCode::LE,
-                    _ => unreachable!(),
-                };
-                self.emit(self.ctx.cmp(src1, src2));
+                let src = self.get_reg_or_imm(*val);
+                self.emit(self.ctx.mov(dst, src));
+            }
+            ir::InstKind::Eq(_, _)
+            | ir::InstKind::Ne(_, _)
+            | ir::InstKind::Gt(_, _)
+            | ir::InstKind::Ge(_, _)
+            | ir::InstKind::Lt(_, _)
+            | ir::InstKind::Le(_, _) => {
+                let dst = self.new_vreg();
+                self.value_map.insert(inst, Operand::Reg(dst));
+                let cc = self.emit_compare(inst);
                 self.emit(self.ctx.cset(dst, cc));
             }
             ir::InstKind::Add(lhs, rhs)
@@ -202,10 +397,35 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
                 let dst = self.new_vreg();
                 self.value_map.insert(inst, Operand::Reg(dst));
 
-                let src1 = self.get_reg(*lhs);
-                let src2 = self.get_reg_or_imm(*rhs);
+                let src1 = match &*inst.kind() {
+                    ir::InstKind::AShr(_, _) => self.sign_extended(*lhs),
+                    _ => self.get_reg(*lhs),
+                };
+                let mut flip = false;
+                let src2 = match &*inst.kind() {
+                    ir::InstKind::Add(_, _) | ir::InstKind::Sub(_, _) => {
+                        let (src2, negated) = self.get_arith_operand(*rhs, inst.ty());
+                        flip = negated;
+                        src2
+                    }
+                    // The shift amount is taken modulo 64 either way.
+                    ir::InstKind::LShl(_, _)
+                    | ir::InstKind::LShr(_, _)
+                    | ir::InstKind::AShr(_, _) => match self.get_reg_or_imm(*rhs) {
+                        RegOrImm::Imm(i) => RegOrImm::Imm(i & 63),
+                        reg => reg,
+                    },
+                    _ if is_not(inst) => RegOrImm::Imm(u64::MAX),
+                    _ => self.get_reg_or_encodable_imm(*rhs, is_logical_imm),
+                };
 
-                match inst.kind() {
+                match &*inst.kind() {
+                    ir::InstKind::Add(_, _) if flip => {
+                        self.emit(self.ctx.sub(dst, src1, src2));
+                    }
+                    ir::InstKind::Sub(_, _) if flip => {
+                        self.emit(self.ctx.add(dst, src1, src2));
+                    }
                     ir::InstKind::Add(_, _) => {
                         self.emit(self.ctx.add(dst, src1, src2));
                     }
@@ -216,7 +436,7 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
                         self.emit(self.ctx.orr(dst, src1, src2));
                     }
                     ir::InstKind::Xor(_, _) => {
-                        if let RegOrImm::Imm(u64::MAX) = src2 {
+                        if is_not(inst) {
                             self.emit(self.ctx.mvn(dst, src1));
                         } else {
                             self.emit(self.ctx.eor(dst, src1, src2));
@@ -236,15 +456,18 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
                     }
                     _ => unreachable!(),
                 }
+                self.truncate(dst, inst.ty());
             }
             ir::InstKind::Mul(lhs, rhs) | ir::InstKind::Div(lhs, rhs) => {
                 let dst = self.new_vreg();
                 self.value_map.insert(inst, Operand::Reg(dst));
 
-                let src1 = self.get_reg(*lhs);
-                let src2 = self.get_reg(*rhs);
+                let (src1, src2) = match &*inst.kind() {
+                    ir::InstKind::Div(_, _) => (self.sign_extended(*lhs), self.sign_extended(*rhs)),
+                    _ => (self.get_reg(*lhs), self.get_reg(*rhs)),
+                };
 
-                match inst.kind() {
+                match &*inst.kind() {
                     ir::InstKind::Mul(_, _) => {
                         self.emit(self.ctx.mul(dst, src1, src2));
                     }
@@ -253,32 +476,52 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
                     }
                     _ => unreachable!(),
                 }
+                self.truncate(dst, inst.ty());
             }
             ir::InstKind::Mod(lhs, rhs) => {
                 let tmp = self.new_vreg();
                 let dst = self.new_vreg();
                 self.value_map.insert(inst, Operand::Reg(dst));
 
-                let src1 = self.get_reg(*lhs);
-                let src2 = self.get_reg(*rhs);
+                let src1 = self.sign_extended(*lhs);
+                let src2 = self.sign_extended(*rhs);
 
                 self.emit(self.ctx.sdiv(tmp, src1, src2));
                 self.emit(self.ctx.msub(dst, tmp, src2, src1));
+                self.truncate(dst, inst.ty());
             }
             ir::InstKind::Jump(target) => {
+                self.emit_phi_copies(target);
                 let label = self.block_map.get(target).unwrap();
                 self.emit(self.ctx.b(label));
             }
+            // Both edges lead to the same block, so the copies for its phis
+            // can go right here.
+            ir::InstKind::CJump(_, ifbb, elsebb) if ifbb == elsebb => {
+                self.emit_phi_copies(ifbb);
+                let label = self.block_map.get(ifbb).unwrap();
+                self.emit(self.ctx.b(label));
+            }
             ir::InstKind::CJump(cond, ifbb, elsebb) => {
-                let ifbb = *self.block_map.get(ifbb).unwrap();
-                let elsebb = *self.block_map.get(elsebb).unwrap();
+                let ifbb = self.edge_label(ifbb);
+                let elsebb = self.edge_label(elsebb);
                 let cond = self.get_reg(*cond);
 
                 self.emit(self.ctx.cbnz(cond, ifbb));
                 self.emit(self.ctx.b(elsebb));
             }
             ir::InstKind::Call(callee, args) => {
-                for (i, arg) in args.iter().enumerate() {
+                // https://github.com/ARM-software/abi-aa/blob/main/aapcs64/aapcs64.rst
+                // Arguments after the eighth are stored in 8-byte slots at the
+                // top of the stack, in order.
+                for (i, arg) in args.iter().enumerate().skip(ARG_REGS) {
+                    let arg = self.get_reg(*arg);
+                    let slot = Memory::Stack {
+                        offset: 8 * (i - ARG_REGS) as i64,
+                    };
+                    self.emit(self.ctx.str(arg, slot));
+                }
+                for (i, arg) in args.iter().enumerate().take(ARG_REGS) {
                     let arg = self.get_reg_or_imm(*arg);
                     self.emit(self.ctx.mov(self.ctx.x(i), arg));
                 }
@@ -286,6 +529,9 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
                 let callee = self.func_map.get(callee.name()).unwrap();
                 self.emit(self.ctx.bl(callee));
 
+                if inst.ty() == ir::Type::Void {
+                    return;
+                }
                 let ret = self.new_vreg();
                 self.emit(
                     self.ctx
@@ -299,8 +545,67 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
                 self.emit(self.ctx.b(self.target.epilogue()));
             }
             ir::InstKind::Return(None) => {
-                self.emit(self.ctx.ret());
+                self.emit(self.ctx.b(self.target.epilogue()));
             }
+            // Phis are lowered to copies in their predecessors.
+            ir::InstKind::Phi(_) => {}
+        }
+    }
+
+    /// Set the flags for the comparison `inst`, and return the condition
+    /// holding when it is true. A constant on the left is swapped to the
+    /// right, where `cmp` can take it.
+    fn emit_compare(&mut self, inst: &'m ir::Inst<'m>) -> ConditionCode {
+        let mut kind = inst.kind().clone();
+        let (lhs, rhs) = kind.binary_operands().unwrap();
+        if lhs.as_constant().is_some() && rhs.as_constant().is_none() {
+            kind = kind.swapped().unwrap();
+        }
+        let (lhs, rhs) = kind.binary_operands().unwrap();
+
+        let signed = !matches!(kind, ir::InstKind::Eq(_, _) | ir::InstKind::Ne(_, _));
+        let (src1, src2) = if signed && lhs.ty().bits() < 64 {
+            let src1 = self.sign_extended(lhs);
+            let src2 = self.sign_extended(rhs);
+            (src1, RegOrImm::Reg(RefCell::new(src2)))
+        } else {
+            let src1 = self.get_reg(lhs);
+            (src1, self.get_reg_or_encodable_imm(rhs, is_arith_imm))
+        };
+        self.emit(self.ctx.cmp(src1, src2));
+
+        match kind {
+            ir::InstKind::Eq(_, _) => ConditionCode::EQ,
+            ir::InstKind::Ne(_, _) => ConditionCode::NE,
+            ir::InstKind::Gt(_, _) => ConditionCode::GT,
+            ir::InstKind::Ge(_, _) => ConditionCode::GE,
+            ir::InstKind::Lt(_, _) => ConditionCode::LT,
+            ir::InstKind::Le(_, _) => ConditionCode::LE,
+            _ => unreachable!(),
+        }
+    }
+
+    /// A register holding `val` sign-extended to 64 bits, for signed
+    /// operations on narrow integers.
+    fn sign_extended(&mut self, val: &dyn ir::Value<'m>) -> &'m Register {
+        let reg = self.get_reg(val);
+        let bits = val.ty().bits();
+        if bits == 64 {
+            return reg;
+        }
+        let shift = 64 - bits as u64;
+        let tmp = self.new_vreg();
+        self.emit(self.ctx.lsl(tmp, reg, RegOrImm::Imm(shift)));
+        self.emit(self.ctx.asr(tmp, tmp, RegOrImm::Imm(shift)));
+        tmp
+    }
+
+    /// Clear the bits of `reg` above the width of `ty`, restoring the
+    /// zero-extended form of a narrow result.
+    fn truncate(&self, reg: &'m Register, ty: ir::Type) {
+        if ty.bits() < 64 {
+            let mask = RegOrImm::Imm(ty.truncate(u64::MAX));
+            self.emit(self.ctx.and(reg, reg, mask));
         }
     }
 
@@ -317,6 +622,23 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
         Memory::Stack { offset }
     }
 
+    /// Bytes needed at the bottom of the frame for the arguments passed on
+    /// the stack, kept 16-byte aligned.
+    fn outgoing_args_size(&self) -> i64 {
+        let stack_args = self
+            .func_ir
+            .blocks()
+            .iter()
+            .flat_map(|block| block.instructions().clone())
+            .filter_map(|inst| match &*inst.kind() {
+                ir::InstKind::Call(_, args) => Some(args.len().saturating_sub(ARG_REGS)),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        (8 * stack_args as i64 + 15) & !15
+    }
+
     fn stack_frame_size(&self) -> u64 {
         self.next_stack_offset as u64
     }
@@ -326,8 +648,228 @@ impl<'m, 'cg> FunctionCG<'m, 'cg> {
     }
 }
 
+/// Replace each `mov` of a constant a single `mov` cannot take with a
+/// `movz` or `movn` of one 16-bit chunk and a `movk` for each chunk that
+/// differs from what that leaves. `movn` is used when more chunks are all
+/// ones than all zeros.
+fn expand_wide_immediates<'m>(ctx: &'m Context<'m>, func: &'m Func<'m>) {
+    let labels = std::iter::once(func.prologue())
+        .chain(func.body().iter().copied())
+        .chain(std::iter::once(func.epilogue()))
+        .collect::<Vec<_>>();
+    for label in labels {
+        let mut insts = label.insts_mut();
+        let mut i = 0;
+        while i < insts.len() {
+            let Inst::Mov {
+                dst,
+                src: RegOrImm::Imm(imm),
+            } = insts[i]
+            else {
+                i += 1;
+                continue;
+            };
+            if is_mov_imm(*imm) {
+                i += 1;
+                continue;
+            }
+
+            let (dst, imm) = (*dst.borrow(), *imm);
+            let chunks: Vec<u64> = (0..4).map(|k| (imm >> (16 * k)) & 0xffff).collect();
+            let ones = chunks.iter().filter(|c| **c == 0xffff).count();
+            let zeros = chunks.iter().filter(|c| **c == 0).count();
+            let fill = if ones > zeros { 0xffff } else { 0 };
+            let mut sequence = vec![];
+            for (k, chunk) in chunks.iter().enumerate() {
+                let shift = 16 * k as u64;
+                if *chunk == fill {
+                    continue;
+                }
+                sequence.push(match (sequence.is_empty(), fill) {
+                    (true, 0) => ctx.movz(dst, *chunk, shift),
+                    (true, _) => ctx.movn(dst, !chunk & 0xffff, shift),
+                    (false, _) => ctx.movk(dst, *chunk, shift),
+                });
+            }
+            // All ones, the only constant with no chunk differing from the
+            // fill, is a single `movn`.
+            if sequence.is_empty() {
+                sequence.push(ctx.movn(dst, 0, 0));
+            }
+            let len = sequence.len();
+            insts.splice(i..i + 1, sequence);
+            i += len;
+        }
+    }
+}
+
+/// Whether `inst` is a tail call whose arguments all fit in registers, so
+/// that no stack space is needed for them.
+fn can_tail_call(inst: &ir::Inst<'_>) -> bool {
+    inst.is_tail_call()
+        && matches!(&*inst.kind(), ir::InstKind::Call(_, args) if args.len() <= ARG_REGS)
+}
+
+/// Whether `inst` is `x ^ -1`, i.e. a bitwise not.
+fn is_not(inst: &ir::Inst<'_>) -> bool {
+    matches!(&*inst.kind(), ir::InstKind::Xor(_, rhs)
+        if rhs.as_constant().is_some_and(|c| c.value() == u64::MAX))
+}
+
 enum Operand<'m> {
     Imm(u64),
     Reg(&'m Register),
     Memory(Memory<'m>),
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::Machine;
+    use crate::aarch64::{compile_source, Codegen, Module, RegAlloc};
+    use crate::ir;
+
+    #[test]
+    fn return_without_value() {
+        let src = "
+            func f(n: Int64) {
+                var x : Int64 = n;
+                return;
+            }";
+        let ir_module = ir::lower_source(src);
+        let module: &'static Module<'static> = Box::leak(Box::new(Module::new()));
+        let mut codegen = Codegen::new(module);
+        codegen.visit_unit(ir_module, Some(RegAlloc::Naive));
+        let mut out = vec![];
+        module.dump(&mut out).unwrap();
+        let asm = String::from_utf8(out).unwrap();
+
+        // The frame is torn down in the epilogue, so the only return is there.
+        let epilogue = asm.find("f_epilogue:").unwrap();
+        assert_eq!(asm.matches("\tret").count(), 1, "{}", asm);
+        assert!(asm[epilogue..].contains("\tret"), "{}", asm);
+    }
+
+    #[test]
+    fn loop_carried_phis() {
+        let src = "
+            func f(n: Int64) : Int64 {
+                var s : Int64 = 0;
+                var i : Int64 = 0;
+                while i < n {
+                    var j : Int64 = 0;
+                    while j < i {
+                        s = s + j;
+                        j = j + 1;
+                    }
+                    i = i + 1;
+                }
+                return s;
+            }";
+        let ir_module = ir::lower_source(src);
+        ir::PassManager::with_opt_level(1).run(ir_module);
+        assert!(ir_module.to_string().contains(" = phi "), "{}", ir_module);
+
+        let module: &'static Module<'static> = Box::leak(Box::new(Module::new()));
+        let mut codegen = Codegen::new(module);
+        codegen.visit_unit(ir_module, Some(RegAlloc::Naive));
+        let mut out = vec![];
+        module.dump(&mut out).unwrap();
+        let asm = String::from_utf8(out).unwrap();
+        assert!(!asm.contains("_t"), "{}", asm);
+
+        // Copies into a phi may be laid out after the labels reading it, and
+        // must still go to the slot it is read from.
+        let slots = |op: &str| -> Vec<&str> {
+            asm.lines()
+                .filter_map(|line| line.strip_prefix(op))
+                .map(|operands| &operands[operands.find(", ").unwrap() + 2..])
+                .collect()
+        };
+        let stored = slots("\tstr\t");
+        for slot in slots("\tldr\t") {
+            assert!(
+                stored.contains(&slot),
+                "{} is never stored in {}",
+                slot,
+                asm
+            );
+        }
+    }
+
+    #[test]
+    fn stack_arguments() {
+        let src = "
+            func weigh(a: Int64, b: Int64, c: Int64, d: Int64, e: Int64, f: Int64,
+                       g: Int64, h: Int64, i: Int64, j: Int64, k: Int64) : Int64 {
+                return a + 2 * b + 3 * c + 4 * d + 5 * e + 6 * f + 7 * g + 8 * h
+                    + 9 * i + 10 * j + 11 * k;
+            }
+            func f(x: Int64) : Int64 {
+                var y: Int64 = weigh(x, 1, 2, 3, 4, 5, 6, 7, x + 1, 9, x * 2);
+                return y + weigh(1, 1, 1, 1, 1, 1, 1, 1, 1, 1, x);
+            }";
+        let weigh = |args: [u64; 11]| (1..=11).zip(args).map(|(w, a)| w * a).sum::<u64>();
+        let f = |x| {
+            weigh([x, 1, 2, 3, 4, 5, 6, 7, x + 1, 9, x * 2])
+                + weigh([1, 1, 1, 1, 1, 1, 1, 1, 1, 1, x])
+        };
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            for opt_level in 0..=1 {
+                let module = compile_source(src, opt_level, regalloc);
+                let mut out = vec![];
+                module.dump(&mut out).unwrap();
+                let asm = String::from_utf8(out).unwrap();
+                assert!(asm.contains(", [x29, #32]\n"), "{}", asm);
+
+                let mut machine = Machine::new(module);
+                let args = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5];
+                assert_eq!(machine.call("weigh", &args), weigh(args));
+                assert_eq!(machine.call("f", &[10]), f(10));
+            }
+        }
+    }
+
+    #[test]
+    fn wide_immediates() {
+        let src = "
+            func wide(x: Int64) : Int64 {
+                var a: Int64 = x + 81985529216486895;
+                var b: Int64 = x + -1;
+                var c: Int64 = x + 8192;
+                var d: Int64 = x * 18446744073709486080;
+                var e: Int64 = x == 4294967296;
+                return a ^ b ^ c ^ d ^ e;
+            }";
+        let wide = |x: u64| {
+            let a = x.wrapping_add(0x0123_4567_89ab_cdef);
+            let d = x.wrapping_mul(0xffff_ffff_ffff_0000);
+            a ^ x.wrapping_sub(1) ^ x.wrapping_add(8192) ^ d ^ (x == 1 << 32) as u64
+        };
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            for opt_level in 0..=2 {
+                let module = compile_source(src, opt_level, regalloc);
+                let mut machine = Machine::new(module);
+                for x in [0, 5, 1 << 32, u64::MAX] {
+                    assert_eq!(machine.call("wide", &[x]), wide(x));
+                }
+            }
+        }
+
+        let module = compile_source(src, 1, RegAlloc::Coloring);
+        let mut out = vec![];
+        module.dump(&mut out).unwrap();
+        let asm = String::from_utf8(out).unwrap();
+        for expected in [
+            ", #52719\n",
+            ", #35243, lsl #16\n",
+            ", #17767, lsl #32\n",
+            ", #291, lsl #48\n",
+            ", x0, #1\n",
+            ", #2, lsl #12\n",
+            "\tmovn\tx",
+            ", #1, lsl #32\n",
+        ] {
+            assert!(asm.contains(expected), "{} not in {}", expected, asm);
+        }
+    }
+}
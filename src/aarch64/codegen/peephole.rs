@@ -0,0 +1,224 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::aarch64::{Context, Func, Inst, Label, Memory, RegOrImm, Register};
+
+/// Clean up the instructions of `func` by looking at neighbouring ones:
+///
+/// - moves of a register to itself, or back to where it was just copied from,
+///   are removed;
+/// - a load of the stack slot just stored to becomes a move of the stored
+///   register;
+/// - `cset` followed by `cbnz` on its result, when nothing else reads it,
+///   becomes `b.<cond>`;
+/// - branches to the next label are removed, inverting the condition of a
+///   conditional branch over such a branch.
+///
+/// It runs before register allocation, while the virtual registers tell
+/// which `cset` results have no other use, and again after it, once spill
+/// code and frame setup are in.
+pub fn run<'m>(ctx: &'m Context<'m>, func: &'m Func<'m>) {
+    let labels = std::iter::once(func.prologue())
+        .chain(func.body().iter().copied())
+        .chain(std::iter::once(func.epilogue()))
+        .collect::<Vec<_>>();
+    let uses = count_uses(&labels);
+
+    for label in labels.iter() {
+        let old = std::mem::take(&mut *label.insts_mut());
+        let mut insts: Vec<&'m Inst<'m>> = Vec::with_capacity(old.len());
+        for inst in old {
+            if is_identity_move(inst) {
+                continue;
+            }
+            match (insts.last().copied(), inst) {
+                (Some(prev), _) if copies_back(prev, inst) => continue,
+                (
+                    Some(Inst::Str {
+                        src,
+                        dst: Memory::Stack { offset: stored },
+                        size: 8,
+                    }),
+                    Inst::Ldr {
+                        dst,
+                        src: Memory::Stack { offset: loaded },
+                        size: 8,
+                    },
+                ) if stored == loaded => {
+                    let (src, dst) = (*src.borrow(), *dst.borrow());
+                    if !same_register(src, dst) {
+                        insts.push(ctx.mov(dst, RegOrImm::Reg(RefCell::new(src))));
+                    }
+                    continue;
+                }
+                (Some(Inst::Cset { dst, cond }), Inst::Cbnz { src, label: target })
+                    if same_register(*dst.borrow(), *src.borrow())
+                        && matches!(*src.borrow(), Register::Virtual(id) if uses[id] == 1) =>
+                {
+                    let cond = *cond;
+                    insts.pop();
+                    insts.push(ctx.b_cond(cond, target));
+                    continue;
+                }
+                _ => {}
+            }
+            insts.push(inst);
+        }
+        *label.insts_mut() = insts;
+    }
+
+    for (label, next) in labels.iter().zip(labels.iter().skip(1)) {
+        let mut insts = label.insts_mut();
+        let n = insts.len();
+        if n >= 2 {
+            if let (Some(taken), Inst::B { label: target }) =
+                (insts[n - 2].conditional_target(), insts[n - 1])
+            {
+                if std::ptr::eq(taken, *next) {
+                    let inverted = invert_branch(ctx, insts[n - 2], target);
+                    insts.splice(n - 2.., [inverted]);
+                }
+            }
+        }
+        if let Some(Inst::B { label: target }) = insts.last() {
+            if std::ptr::eq(*target, *next) {
+                insts.pop();
+            }
+        }
+    }
+}
+
+/// The conditional branch to `target` taken exactly when `branch` is not.
+fn invert_branch<'m>(
+    ctx: &'m Context<'m>,
+    branch: &'m Inst<'m>,
+    target: &'m Label<'m>,
+) -> &'m Inst<'m> {
+    match branch {
+        Inst::BCond { cond, .. } => ctx.b_cond(cond.invert(), target),
+        Inst::Cbnz { src, .. } => ctx.cbz(*src.borrow(), target),
+        Inst::Cbz { src, .. } => ctx.cbnz(*src.borrow(), target),
+        _ => unreachable!(),
+    }
+}
+
+/// How many instructions read each virtual register.
+fn count_uses<'m>(labels: &[&'m Label<'m>]) -> HashMap<u64, usize> {
+    let mut uses = HashMap::new();
+    for label in labels {
+        for inst in label.insts().iter() {
+            let mut read = vec![];
+            let mut written = vec![];
+            inst.collect_vregs(&mut read, &mut written);
+            for r in read.iter() {
+                if let Register::Virtual(id) = **r {
+                    *uses.entry(*id).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    uses
+}
+
+/// Whether `inst` is a move of a register to itself.
+pub fn is_identity_move(inst: &Inst<'_>) -> bool {
+    match inst {
+        Inst::Mov {
+            dst,
+            src: RegOrImm::Reg(src),
+        } => same_register(*dst.borrow(), *src.borrow()),
+        _ => false,
+    }
+}
+
+/// Whether `inst` moves back the register `prev` just copied.
+fn copies_back(prev: &Inst<'_>, inst: &Inst<'_>) -> bool {
+    match (prev, inst) {
+        (
+            Inst::Mov {
+                dst: a,
+                src: RegOrImm::Reg(b),
+            },
+            Inst::Mov {
+                dst: c,
+                src: RegOrImm::Reg(d),
+            },
+        ) => same_register(*a.borrow(), *d.borrow()) && same_register(*b.borrow(), *c.borrow()),
+        _ => false,
+    }
+}
+
+fn same_register(a: &Register, b: &Register) -> bool {
+    match (a, b) {
+        (Register::Physical(a), Register::Physical(b)) => a == b,
+        (Register::Virtual(a), Register::Virtual(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::Machine;
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    fn dump(src: &str, opt_level: u8, regalloc: RegAlloc) -> String {
+        let module = compile_source(src, opt_level, regalloc);
+        let mut out = vec![];
+        module.dump(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn compare_and_branch() {
+        let src = include_str!("../../../tests/gcd1.toy");
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            let asm = dump(src, 1, regalloc);
+            assert!(asm.contains("\tcmp\t"), "{}", asm);
+            assert!(
+                asm.contains("\tb.ne\t") || asm.contains("\tb.eq\t"),
+                "{}",
+                asm
+            );
+            assert!(!asm.contains("cset"), "{}", asm);
+            assert!(!asm.contains("cbnz"), "{}", asm);
+        }
+    }
+
+    #[test]
+    fn redundant_code() {
+        let src = include_str!("../../../tests/fib1.toy");
+        for regalloc in [RegAlloc::Naive, RegAlloc::Linear, RegAlloc::Coloring] {
+            for opt_level in 0..=2 {
+                let asm = dump(src, opt_level, regalloc);
+                let lines: Vec<&str> = asm.lines().collect();
+                for pair in lines.windows(2) {
+                    // A branch to the next label.
+                    if let Some(target) = pair[0].strip_prefix("\tb\t") {
+                        assert_ne!(pair[1], format!("{target}:"), "{}", asm);
+                    }
+                    // A load of the slot just stored to.
+                    if let Some(slot) = pair[0].strip_prefix("\tstr\t") {
+                        let slot = &slot[slot.find(", ").unwrap()..];
+                        assert!(
+                            !pair[1].starts_with("\tldr\t") || !pair[1].ends_with(slot),
+                            "{}",
+                            asm
+                        );
+                    }
+                }
+                for line in lines {
+                    if let Some((dst, src)) = line
+                        .strip_prefix("\tmov\t")
+                        .and_then(|ops| ops.split_once(", "))
+                    {
+                        assert_ne!(dst, src, "{}", asm);
+                    }
+                }
+
+                let module = compile_source(src, opt_level, regalloc);
+                let mut machine = Machine::new(module);
+                assert_eq!(machine.call("fib", &[10]), 89);
+            }
+        }
+    }
+}
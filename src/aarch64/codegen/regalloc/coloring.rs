@@ -0,0 +1,629 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::liveness::Liveness;
+use super::{insert_frame, rewrite, used_callee_saved, Location};
+use crate::aarch64::codegen::{Context, FunctionCG};
+use crate::aarch64::{Func, Inst, Label, RegOrImm, Register};
+
+/// Registers handed out to virtual registers, in order of preference. The
+/// callee-saved ones come last as they have to be saved in the frame.
+const COLORS: [usize; 25] = [
+    9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28,
+];
+/// Registers a call may overwrite.
+const CALLER_SAVED: [usize; 19] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18,
+];
+/// Argument registers.
+const ARGS: usize = 8;
+
+/// Graph colouring allocation with iterated register coalescing (George and
+/// Appel). Virtual registers and the argument registers are nodes of an
+/// interference graph built from liveness over the machine code, and
+/// register-to-register `mov`s are coalesced whenever the Briggs or George
+/// test shows that doing so keeps the graph colourable. Nodes that cannot be
+/// coloured are spilled in order of the number of their uses and
+/// definitions, weighted by loop nesting, over their degree.
+pub struct ColoringAllocator<'m, 'cg> {
+    ctx: &'m Context<'m>,
+    func: &'m Func<'m>,
+    func_cg: &'cg mut FunctionCG<'m, 'cg>,
+}
+
+impl<'m, 'cg> ColoringAllocator<'m, 'cg> {
+    pub fn new(
+        ctx: &'m Context<'m>,
+        func: &'m Func<'m>,
+        func_cg: &'cg mut FunctionCG<'m, 'cg>,
+    ) -> ColoringAllocator<'m, 'cg> {
+        ColoringAllocator { ctx, func, func_cg }
+    }
+
+    pub fn run(&mut self) {
+        let liveness = Liveness::new(self.func);
+        let mut graph = Graph::build(&liveness);
+        graph.color();
+
+        // Coalesced registers share the location of the register they were
+        // merged into, stack slots included.
+        let mut slots = HashMap::new();
+        let mut locations = HashMap::new();
+        for vreg in graph.vregs() {
+            let n = graph.alias_of(node(vreg));
+            let location = match graph.state[n] {
+                NodeState::Spilled => Location::Slot(
+                    slots
+                        .entry(n)
+                        .or_insert_with(|| self.func_cg.new_stack_slot())
+                        .clone(),
+                ),
+                _ => Location::Reg(graph.color[n].unwrap()),
+            };
+            locations.insert(vreg, location);
+        }
+
+        rewrite(self.ctx, liveness.labels(), &locations);
+        let saved = used_callee_saved(&locations);
+        insert_frame(self.ctx, self.func, self.func_cg.stack_frame_size(), &saved);
+    }
+}
+
+/// Node of the interference graph of a register: physical registers keep
+/// their number and virtual registers come after them.
+fn node(vreg: u64) -> usize {
+    32 + vreg as usize
+}
+
+fn node_of(reg: &Register) -> usize {
+    match reg {
+        Register::Physical(id) => *id as usize,
+        Register::Virtual(id) => node(*id),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum NodeState {
+    Unused,
+    Precolored,
+    Initial,
+    Simplify,
+    Freeze,
+    Spill,
+    Spilled,
+    Coalesced,
+    Colored,
+    Select,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum MoveState {
+    Worklist,
+    Active,
+    Coalesced,
+    Constrained,
+    Frozen,
+}
+
+struct Graph {
+    adj_set: HashSet<(usize, usize)>,
+    adj_list: Vec<Vec<usize>>,
+    degree: Vec<usize>,
+    // Moves as (destination, source), and the moves each node takes part
+    // in.
+    moves: Vec<(usize, usize)>,
+    move_state: Vec<MoveState>,
+    move_list: Vec<Vec<usize>>,
+    state: Vec<NodeState>,
+    alias: Vec<usize>,
+    color: Vec<Option<usize>>,
+    cost: Vec<f64>,
+
+    simplify_worklist: BTreeSet<usize>,
+    freeze_worklist: BTreeSet<usize>,
+    spill_worklist: BTreeSet<usize>,
+    worklist_moves: BTreeSet<usize>,
+    select_stack: Vec<usize>,
+}
+
+/// Registers an instruction reads and writes, as graph nodes. Only
+/// physical registers among `COLORS` are tracked.
+struct Operands {
+    uses: Vec<usize>,
+    defs: Vec<usize>,
+    is_move: bool,
+}
+
+impl Graph {
+    fn new(nodes: usize) -> Graph {
+        let mut state = vec![NodeState::Unused; nodes];
+        let mut color = vec![None; nodes];
+        for r in COLORS {
+            state[r] = NodeState::Precolored;
+            color[r] = Some(r);
+        }
+        Graph {
+            adj_set: HashSet::new(),
+            adj_list: vec![vec![]; nodes],
+            degree: vec![0; nodes],
+            moves: vec![],
+            move_state: vec![],
+            move_list: vec![vec![]; nodes],
+            state,
+            alias: (0..nodes).collect(),
+            color,
+            cost: vec![0.0; nodes],
+            simplify_worklist: BTreeSet::new(),
+            freeze_worklist: BTreeSet::new(),
+            spill_worklist: BTreeSet::new(),
+            worklist_moves: BTreeSet::new(),
+            select_stack: vec![],
+        }
+    }
+
+    /// Build the interference graph by walking each label backwards from
+    /// the registers live out of it. Physical registers are never live
+    /// across labels: the arguments of a call are set up right before it
+    /// and its result is copied right after.
+    fn build(liveness: &Liveness<'_>) -> Graph {
+        let labels = liveness.labels();
+        let mut operands: Vec<Vec<Operands>> = labels
+            .iter()
+            .map(|label| label_operands(label, labels))
+            .collect();
+        let nodes = operands
+            .iter()
+            .flatten()
+            .flat_map(|ops| ops.uses.iter().chain(ops.defs.iter()))
+            .max()
+            .map_or(32, |n| (n + 1).max(32));
+        let mut graph = Graph::new(nodes);
+
+        let depths = loop_depths(labels);
+        for (i, ops) in operands.iter_mut().enumerate() {
+            let weight = 10f64.powi(depths[i].min(8) as i32);
+            let mut live: HashSet<usize> = liveness.live_out(i).iter().map(|v| node(*v)).collect();
+            for op in ops.iter_mut().rev() {
+                // Other physical registers are never handed out, so they
+                // cannot conflict with anything.
+                let tracked = |n: &usize| *n >= 32 || COLORS.contains(n);
+                op.uses.retain(tracked);
+                op.defs.retain(tracked);
+                op.is_move &= op.uses.len() == 1 && op.defs.len() == 1;
+                for n in op.uses.iter().chain(op.defs.iter()) {
+                    graph.cost[*n] += weight;
+                    if graph.state[*n] == NodeState::Unused {
+                        graph.state[*n] = NodeState::Initial;
+                    }
+                }
+
+                if op.is_move {
+                    live.remove(&op.uses[0]);
+                    let m = graph.moves.len();
+                    graph.moves.push((op.defs[0], op.uses[0]));
+                    graph.move_state.push(MoveState::Worklist);
+                    graph.move_list[op.defs[0]].push(m);
+                    graph.move_list[op.uses[0]].push(m);
+                    graph.worklist_moves.insert(m);
+                }
+
+                live.extend(op.defs.iter().copied());
+                for d in op.defs.iter() {
+                    for l in live.iter() {
+                        graph.add_edge(*l, *d);
+                    }
+                }
+                for d in op.defs.iter() {
+                    live.remove(d);
+                }
+                live.extend(op.uses.iter().copied());
+            }
+        }
+        graph
+    }
+
+    /// Virtual registers in the graph.
+    fn vregs(&self) -> impl Iterator<Item = u64> + '_ {
+        (32..self.state.len())
+            .filter(|n| self.state[*n] != NodeState::Unused)
+            .map(|n| (n - 32) as u64)
+    }
+
+    fn color(&mut self) {
+        self.make_worklist();
+        loop {
+            if let Some(n) = self.simplify_worklist.pop_first() {
+                self.simplify(n);
+            } else if let Some(m) = self.worklist_moves.pop_first() {
+                self.coalesce(m);
+            } else if let Some(n) = self.freeze_worklist.pop_first() {
+                self.freeze(n);
+            } else if !self.spill_worklist.is_empty() {
+                self.select_spill();
+            } else {
+                break;
+            }
+        }
+        self.assign_colors();
+    }
+
+    fn add_edge(&mut self, u: usize, v: usize) {
+        if u == v || self.adj_set.contains(&(u, v)) {
+            return;
+        }
+        self.adj_set.insert((u, v));
+        self.adj_set.insert((v, u));
+        for (a, b) in [(u, v), (v, u)] {
+            if self.state[a] != NodeState::Precolored {
+                self.adj_list[a].push(b);
+                self.degree[a] += 1;
+            }
+        }
+    }
+
+    fn make_worklist(&mut self) {
+        for n in 0..self.state.len() {
+            if self.state[n] != NodeState::Initial {
+                continue;
+            }
+            if self.degree[n] >= COLORS.len() {
+                self.set_state(n, NodeState::Spill);
+            } else if self.move_related(n) {
+                self.set_state(n, NodeState::Freeze);
+            } else {
+                self.set_state(n, NodeState::Simplify);
+            }
+        }
+    }
+
+    /// Move `n` to the worklist of `state`, taking it off the one it is on.
+    fn set_state(&mut self, n: usize, state: NodeState) {
+        match self.state[n] {
+            NodeState::Simplify => self.simplify_worklist.remove(&n),
+            NodeState::Freeze => self.freeze_worklist.remove(&n),
+            NodeState::Spill => self.spill_worklist.remove(&n),
+            _ => false,
+        };
+        match state {
+            NodeState::Simplify => self.simplify_worklist.insert(n),
+            NodeState::Freeze => self.freeze_worklist.insert(n),
+            NodeState::Spill => self.spill_worklist.insert(n),
+            _ => false,
+        };
+        self.state[n] = state;
+    }
+
+    fn is_precolored(&self, n: usize) -> bool {
+        self.state[n] == NodeState::Precolored
+    }
+
+    fn adjacent(&self, n: usize) -> Vec<usize> {
+        self.adj_list[n]
+            .iter()
+            .copied()
+            .filter(|m| !matches!(self.state[*m], NodeState::Select | NodeState::Coalesced))
+            .collect()
+    }
+
+    fn node_moves(&self, n: usize) -> Vec<usize> {
+        self.move_list[n]
+            .iter()
+            .copied()
+            .filter(|m| matches!(self.move_state[*m], MoveState::Active | MoveState::Worklist))
+            .collect()
+    }
+
+    fn move_related(&self, n: usize) -> bool {
+        !self.node_moves(n).is_empty()
+    }
+
+    fn simplify(&mut self, n: usize) {
+        self.state[n] = NodeState::Select;
+        self.select_stack.push(n);
+        for m in self.adjacent(n) {
+            self.decrement_degree(m);
+        }
+    }
+
+    fn decrement_degree(&mut self, m: usize) {
+        if self.is_precolored(m) {
+            return;
+        }
+        let d = self.degree[m];
+        self.degree[m] -= 1;
+        if d == COLORS.len() {
+            let mut nodes = self.adjacent(m);
+            nodes.push(m);
+            self.enable_moves(&nodes);
+            if self.state[m] == NodeState::Spill {
+                if self.move_related(m) {
+                    self.set_state(m, NodeState::Freeze);
+                } else {
+                    self.set_state(m, NodeState::Simplify);
+                }
+            }
+        }
+    }
+
+    fn enable_moves(&mut self, nodes: &[usize]) {
+        for n in nodes.iter() {
+            for m in self.node_moves(*n) {
+                if self.move_state[m] == MoveState::Active {
+                    self.move_state[m] = MoveState::Worklist;
+                    self.worklist_moves.insert(m);
+                }
+            }
+        }
+    }
+
+    fn coalesce(&mut self, m: usize) {
+        let (dst, src) = self.moves[m];
+        let x = self.alias_of(dst);
+        let y = self.alias_of(src);
+        let (u, v) = if self.is_precolored(y) {
+            (y, x)
+        } else {
+            (x, y)
+        };
+
+        if u == v {
+            self.move_state[m] = MoveState::Coalesced;
+            self.add_work_list(u);
+        } else if self.is_precolored(v) || self.adj_set.contains(&(u, v)) {
+            self.move_state[m] = MoveState::Constrained;
+            self.add_work_list(u);
+            self.add_work_list(v);
+        } else if self.can_coalesce(u, v) {
+            self.move_state[m] = MoveState::Coalesced;
+            self.combine(u, v);
+            self.add_work_list(u);
+        } else {
+            self.move_state[m] = MoveState::Active;
+        }
+    }
+
+    /// The George test if `u` is precolored, the Briggs test otherwise.
+    fn can_coalesce(&self, u: usize, v: usize) -> bool {
+        let k = COLORS.len();
+        if self.is_precolored(u) {
+            return self.adjacent(v).into_iter().all(|t| {
+                self.degree[t] < k || self.is_precolored(t) || self.adj_set.contains(&(t, u))
+            });
+        }
+        let mut nodes: BTreeSet<usize> = self.adjacent(u).into_iter().collect();
+        nodes.extend(self.adjacent(v));
+        nodes
+            .into_iter()
+            .filter(|n| self.is_precolored(*n) || self.degree[*n] >= k)
+            .count()
+            < k
+    }
+
+    fn add_work_list(&mut self, u: usize) {
+        if !self.is_precolored(u) && !self.move_related(u) && self.degree[u] < COLORS.len() {
+            self.set_state(u, NodeState::Simplify);
+        }
+    }
+
+    fn alias_of(&self, n: usize) -> usize {
+        if self.state[n] == NodeState::Coalesced {
+            self.alias_of(self.alias[n])
+        } else {
+            n
+        }
+    }
+
+    fn combine(&mut self, u: usize, v: usize) {
+        self.set_state(v, NodeState::Coalesced);
+        self.alias[v] = u;
+        let moves = std::mem::take(&mut self.move_list[v]);
+        self.move_list[u].extend(moves.iter().copied());
+        self.move_list[v] = moves;
+        self.enable_moves(&[v]);
+        for t in self.adjacent(v) {
+            self.add_edge(t, u);
+            self.decrement_degree(t);
+        }
+        if self.degree[u] >= COLORS.len() && self.state[u] == NodeState::Freeze {
+            self.set_state(u, NodeState::Spill);
+        }
+    }
+
+    fn freeze(&mut self, u: usize) {
+        self.state[u] = NodeState::Simplify;
+        self.simplify_worklist.insert(u);
+        self.freeze_moves(u);
+    }
+
+    fn freeze_moves(&mut self, u: usize) {
+        for m in self.node_moves(u) {
+            let (x, y) = self.moves[m];
+            let v = if self.alias_of(y) == self.alias_of(u) {
+                self.alias_of(x)
+            } else {
+                self.alias_of(y)
+            };
+            self.move_state[m] = MoveState::Frozen;
+            self.worklist_moves.remove(&m);
+            if self.state[v] == NodeState::Freeze
+                && !self.move_related(v)
+                && self.degree[v] < COLORS.len()
+            {
+                self.set_state(v, NodeState::Simplify);
+            }
+        }
+    }
+
+    /// Pick the node that is cheapest to keep in memory for its degree, and
+    /// push it optimistically in the hope that it still gets a colour.
+    fn select_spill(&mut self) {
+        let m = *self
+            .spill_worklist
+            .iter()
+            .min_by(|a, b| {
+                let cost = |n: usize| self.cost[n] / self.degree[n] as f64;
+                cost(**a).total_cmp(&cost(**b))
+            })
+            .unwrap();
+        self.set_state(m, NodeState::Simplify);
+        self.freeze_moves(m);
+    }
+
+    fn assign_colors(&mut self) {
+        while let Some(n) = self.select_stack.pop() {
+            let mut ok: Vec<usize> = COLORS.to_vec();
+            for w in self.adj_list[n].iter() {
+                let a = self.alias_of(*w);
+                if matches!(self.state[a], NodeState::Colored | NodeState::Precolored) {
+                    ok.retain(|c| Some(*c) != self.color[a]);
+                }
+            }
+            match ok.first() {
+                Some(c) => {
+                    self.state[n] = NodeState::Colored;
+                    self.color[n] = Some(*c);
+                }
+                None => self.state[n] = NodeState::Spilled,
+            }
+        }
+    }
+}
+
+/// The operands of each instruction in `label`. Calls read the argument
+/// registers set up since the previous call and write every caller-saved
+/// register; branches to other functions are tail calls and read their
+/// arguments too.
+fn label_operands<'m>(label: &'m Label<'m>, labels: &[&'m Label<'m>]) -> Vec<Operands> {
+    let is_local = |target: &Label<'m>| labels.iter().any(|l| std::ptr::eq(*l, target));
+    let mut args: Vec<usize> = vec![];
+    let mut operands = vec![];
+    for inst in label.insts().iter() {
+        let op = match inst {
+            Inst::Mov {
+                dst,
+                src: RegOrImm::Reg(src),
+            } => Operands {
+                uses: vec![node_of(&src.borrow())],
+                defs: vec![node_of(&dst.borrow())],
+                is_move: true,
+            },
+            Inst::Mov {
+                dst,
+                src: RegOrImm::Imm(_),
+            } => Operands {
+                uses: vec![],
+                defs: vec![node_of(&dst.borrow())],
+                is_move: false,
+            },
+            Inst::Bl { .. } => Operands {
+                uses: std::mem::take(&mut args),
+                defs: CALLER_SAVED.to_vec(),
+                is_move: false,
+            },
+            Inst::B { label } if !is_local(label) => Operands {
+                uses: std::mem::take(&mut args),
+                defs: vec![],
+                is_move: false,
+            },
+            _ => {
+                let (uses, defs) = super::liveness::operands(inst);
+                Operands {
+                    uses: uses.into_iter().map(node).collect(),
+                    defs: defs.into_iter().map(node).collect(),
+                    is_move: false,
+                }
+            }
+        };
+        if let Inst::Mov { dst, .. } = inst {
+            if let Register::Physical(r) = **dst.borrow() {
+                if (r as usize) < ARGS {
+                    args.push(r as usize);
+                }
+            }
+        }
+        operands.push(op);
+    }
+    operands
+}
+
+/// How many loops each label is in, judging by the branches back to an
+/// earlier label in the layout.
+fn loop_depths(labels: &[&Label<'_>]) -> Vec<usize> {
+    let mut depths = vec![0; labels.len()];
+    for (i, label) in labels.iter().enumerate() {
+        for inst in label.insts().iter() {
+            let (Inst::B { label: target }
+            | Inst::Cbnz { label: target, .. }
+            | Inst::Cbz { label: target, .. }
+            | Inst::BCond { label: target, .. }) = inst
+            else {
+                continue;
+            };
+            if let Some(j) = labels[..=i].iter().position(|l| std::ptr::eq(*l, *target)) {
+                depths[j..=i].iter_mut().for_each(|d| *d += 1);
+            }
+        }
+    }
+    depths
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::{check_programs, Machine};
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    #[test]
+    fn programs() {
+        check_programs(RegAlloc::Coloring);
+    }
+
+    #[test]
+    fn coalesce_moves_around_calls() {
+        let src = "
+            func add(a: Int64, b: Int64) : Int64 {
+                return a + b;
+            }
+            func f(x: Int64, y: Int64) : Int64 {
+                return add(y, x) * 2;
+            }";
+        let module = compile_source(src, 1, RegAlloc::Coloring);
+        let mut out = vec![];
+        module.dump(&mut out).unwrap();
+        let asm = String::from_utf8(out).unwrap();
+
+        // The parameters come in, and the result goes out, in the registers
+        // the sum is computed in, and the arguments of the call need no
+        // copy but the swap.
+        assert!(asm.contains("\tadd\tx0, x0, x1\n"), "{}", asm);
+        let f = &asm[asm.find("_f:").unwrap()..];
+        let movs = f.lines().filter(|l| l.starts_with("\tmov")).count();
+        assert!(movs <= 3, "{}", asm);
+
+        let mut machine = Machine::new(module);
+        assert_eq!(machine.call("f", &[3, 4]), 14);
+    }
+
+    #[test]
+    fn spill_under_pressure() {
+        // Twenty values live at once, more than there are registers. Each
+        // is used twice, so none is folded into its use.
+        let mut src = String::from("func f(a: Int64) : Int64 {\n");
+        for i in 0..20 {
+            src += &format!("var v{i}: Int64 = a * {};\n", i + 1);
+        }
+        src += "return v0";
+        for i in 1..20 {
+            src += &format!(" + v{i}");
+        }
+        for i in 0..20 {
+            src += &format!(" | v{i}");
+        }
+        src += ";\n}\n";
+
+        let expected = (1..=20).fold(3 * 210, |acc, i| acc | (3 * i));
+        for opt_level in 0..=2 {
+            let module = compile_source(&src, opt_level, RegAlloc::Coloring);
+            let mut machine = Machine::new(module);
+            assert_eq!(machine.call("f", &[3]), expected);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeSet, HashMap};
+
+use super::liveness::{operands, Liveness};
+use super::{insert_frame, rewrite, used_callee_saved, Location, CALLEE_SAVED};
+use crate::aarch64::codegen::{Context, FunctionCG};
+use crate::aarch64::{Func, Inst};
+
+/// Caller-saved registers handed out to virtual registers. x0-x7 carry the
+/// arguments and results around calls, so they are left alone.
+const CALLER_SAVED: [usize; 7] = [9, 10, 11, 12, 13, 14, 15];
+
+/// Linear scan allocation (Poletto and Sarkar). Each virtual register gets a
+/// single interval over the instructions in layout order, from its first
+/// definition or entry into a label where it is live to its last use or
+/// exit from a label where it is live. The intervals are visited in order
+/// of their start; when no register is free, the interval ending last is
+/// spilled.
+pub struct LinearScanAllocator<'m, 'cg> {
+    ctx: &'m Context<'m>,
+    func: &'m Func<'m>,
+    func_cg: &'cg mut FunctionCG<'m, 'cg>,
+}
+
+struct Interval {
+    vreg: u64,
+    start: usize,
+    end: usize,
+}
+
+impl<'m, 'cg> LinearScanAllocator<'m, 'cg> {
+    pub fn new(
+        ctx: &'m Context<'m>,
+        func: &'m Func<'m>,
+        func_cg: &'cg mut FunctionCG<'m, 'cg>,
+    ) -> LinearScanAllocator<'m, 'cg> {
+        LinearScanAllocator { ctx, func, func_cg }
+    }
+
+    pub fn run(&mut self) {
+        let liveness = Liveness::new(self.func);
+        let (mut intervals, calls) = build_intervals(&liveness);
+        intervals.sort_by_key(|iv| (iv.start, iv.end, iv.vreg));
+
+        let mut locations = HashMap::new();
+        let mut free: BTreeSet<usize> = CALLER_SAVED.into_iter().chain(CALLEE_SAVED).collect();
+        // Intervals holding a register, as (end, vreg, register).
+        let mut active: Vec<(usize, u64, usize)> = vec![];
+        for iv in intervals.iter() {
+            active.retain(|&(end, _, reg)| {
+                let expired = end < iv.start;
+                if expired {
+                    free.insert(reg);
+                }
+                !expired
+            });
+
+            // Values live across a call need a register the callee
+            // preserves. Others prefer the caller-saved ones, which need no
+            // saving in the frame.
+            let crosses_call = calls.iter().any(|&c| iv.start < c && c < iv.end);
+            let usable = |reg: &usize| !crosses_call || CALLEE_SAVED.contains(reg);
+            if let Some(&reg) = free.iter().find(|r| usable(r)) {
+                free.remove(&reg);
+                locations.insert(iv.vreg, Location::Reg(reg));
+                active.push((iv.end, iv.vreg, reg));
+                continue;
+            }
+
+            let furthest = active
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, _, reg))| usable(reg))
+                .max_by_key(|(_, (end, _, _))| *end);
+            let Some((k, &(end, vreg, reg))) = furthest else {
+                locations.insert(iv.vreg, Location::Slot(self.func_cg.new_stack_slot()));
+                continue;
+            };
+            if end > iv.end {
+                locations.insert(vreg, Location::Slot(self.func_cg.new_stack_slot()));
+                locations.insert(iv.vreg, Location::Reg(reg));
+                active[k] = (iv.end, iv.vreg, reg);
+            } else {
+                locations.insert(iv.vreg, Location::Slot(self.func_cg.new_stack_slot()));
+            }
+        }
+
+        rewrite(self.ctx, liveness.labels(), &locations);
+        let saved = used_callee_saved(&locations);
+        insert_frame(self.ctx, self.func, self.func_cg.stack_frame_size(), &saved);
+    }
+}
+
+/// The live interval of each virtual register, and the positions of the
+/// calls. Instruction `i` reads its operands at position `2 * i` and writes
+/// its results at `2 * i + 1`, so a register last read by an instruction
+/// can be reused for the result.
+fn build_intervals(liveness: &Liveness<'_>) -> (Vec<Interval>, Vec<usize>) {
+    let mut ranges: HashMap<u64, (usize, usize)> = HashMap::new();
+    let mut extend = |vreg: u64, pos: usize| {
+        let range = ranges.entry(vreg).or_insert((pos, pos));
+        range.0 = range.0.min(pos);
+        range.1 = range.1.max(pos);
+    };
+
+    let mut calls = vec![];
+    let mut pos = 0;
+    for (i, label) in liveness.labels().iter().enumerate() {
+        let start = pos;
+        for vreg in liveness.live_in(i).iter() {
+            extend(*vreg, start);
+        }
+        for inst in label.insts().iter() {
+            let (read, written) = operands(inst);
+            for vreg in read {
+                extend(vreg, pos);
+            }
+            for vreg in written {
+                extend(vreg, pos + 1);
+            }
+            if let Inst::Bl { .. } = inst {
+                calls.push(pos);
+            }
+            pos += 2;
+        }
+        let end = pos.saturating_sub(1).max(start);
+        for vreg in liveness.live_out(i).iter() {
+            extend(*vreg, end);
+        }
+    }
+
+    let intervals = ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval { vreg, start, end })
+        .collect();
+    (intervals, calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::{check_programs, Machine};
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    #[test]
+    fn programs() {
+        check_programs(RegAlloc::Linear);
+    }
+
+    #[test]
+    fn spill_under_pressure() {
+        // More values live at once than there are registers to hold them.
+        let src = "
+            func f(a: Int64, b: Int64) : Int64 {
+                var c: Int64 = a + 1;
+                var d: Int64 = b + 2;
+                var e: Int64 = a * b;
+                var f: Int64 = a - b;
+                var g: Int64 = c * d;
+                var h: Int64 = e + f;
+                var i: Int64 = g - 3;
+                var j: Int64 = h * 5;
+                var k: Int64 = a | b;
+                return a + b + c + d + e + f + g + h + i + j + k;
+            }
+            func g(x: Int64) : Int64 {
+                return f(x, x + 1) + f(x + 2, x) + x;
+            }";
+        let expected = |a: u64, b: u64| {
+            let (c, d, e, f) = (a + 1, b + 2, a * b, a.wrapping_sub(b));
+            let (g, h) = (c * d, e.wrapping_add(f));
+            let (i, j, k) = (g - 3, h.wrapping_mul(5), a | b);
+            [a, b, c, d, e, f, g, h, i, j, k]
+                .into_iter()
+                .fold(0u64, u64::wrapping_add)
+        };
+        for opt_level in 0..=2 {
+            let module = compile_source(src, opt_level, RegAlloc::Linear);
+            let mut machine = Machine::new(module);
+            assert_eq!(machine.call("f", &[7, 3]), expected(7, 3));
+            let x = 4;
+            let g = expected(x, x + 1)
+                .wrapping_add(expected(x + 2, x))
+                .wrapping_add(x);
+            assert_eq!(machine.call("g", &[x]), g);
+        }
+    }
+}
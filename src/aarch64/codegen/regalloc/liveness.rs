@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+
+use super::vreg_id;
+use crate::aarch64::{Func, Inst, Label};
+
+/// Virtual registers live into and out of each label of a function, found
+/// by the usual backward dataflow over the labels in layout order.
+pub struct Liveness<'m> {
+    labels: Vec<&'m Label<'m>>,
+    live_in: Vec<HashSet<u64>>,
+    live_out: Vec<HashSet<u64>>,
+}
+
+impl<'m> Liveness<'m> {
+    pub fn new(func: &'m Func<'m>) -> Liveness<'m> {
+        let labels: Vec<&'m Label<'m>> = std::iter::once(func.prologue())
+            .chain(func.body().iter().copied())
+            .chain(std::iter::once(func.epilogue()))
+            .collect();
+        let index: HashMap<*const Label<'m>, usize> = labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| (*label as *const Label<'m>, i))
+            .collect();
+
+        // Registers read before being written in each label, and registers
+        // written in each label.
+        let mut upward = vec![HashSet::new(); labels.len()];
+        let mut defined = vec![HashSet::new(); labels.len()];
+        let mut succs = vec![vec![]; labels.len()];
+        for (i, label) in labels.iter().enumerate() {
+            for inst in label.insts().iter() {
+                let (read, written) = operands(inst);
+                for r in read {
+                    if !defined[i].contains(&r) {
+                        upward[i].insert(r);
+                    }
+                }
+                defined[i].extend(written);
+            }
+            succs[i] = successors(&labels, &index, i);
+        }
+
+        let mut live_in = vec![HashSet::new(); labels.len()];
+        let mut live_out = vec![HashSet::new(); labels.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..labels.len()).rev() {
+                let out: HashSet<u64> = succs[i]
+                    .iter()
+                    .flat_map(|s| live_in[*s].iter().copied())
+                    .collect();
+                let mut inn: HashSet<u64> = out.difference(&defined[i]).copied().collect();
+                inn.extend(upward[i].iter().copied());
+                if inn != live_in[i] || out != live_out[i] {
+                    live_in[i] = inn;
+                    live_out[i] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        Liveness {
+            labels,
+            live_in,
+            live_out,
+        }
+    }
+
+    /// The labels of the function in layout order: the prologue, the body
+    /// and the epilogue.
+    pub fn labels(&self) -> &[&'m Label<'m>] {
+        &self.labels
+    }
+
+    pub fn live_in(&self, label: usize) -> &HashSet<u64> {
+        &self.live_in[label]
+    }
+
+    pub fn live_out(&self, label: usize) -> &HashSet<u64> {
+        &self.live_out[label]
+    }
+}
+
+/// Ids of the virtual registers read and written by `inst`.
+pub fn operands<'m>(inst: &'m Inst<'m>) -> (Vec<u64>, Vec<u64>) {
+    let mut read = vec![];
+    let mut written = vec![];
+    inst.collect_vregs(&mut read, &mut written);
+    (
+        read.iter().map(|r| vreg_id(r)).collect(),
+        written.iter().map(|r| vreg_id(r)).collect(),
+    )
+}
+
+/// Labels control may flow to from the end of `labels[i]`. A label falls
+/// through to the next one unless it ends in `b` or `ret`, and branches out
+/// of the function are tail calls, which have no successor here.
+fn successors<'m>(
+    labels: &[&'m Label<'m>],
+    index: &HashMap<*const Label<'m>, usize>,
+    i: usize,
+) -> Vec<usize> {
+    let mut succs = vec![];
+    for inst in labels[i].insts().iter() {
+        match inst {
+            Inst::Cbnz { label, .. } | Inst::Cbz { label, .. } | Inst::BCond { label, .. } => {
+                succs.extend(index.get(&(*label as *const Label<'m>)))
+            }
+            Inst::B { label } => {
+                succs.extend(index.get(&(*label as *const Label<'m>)));
+                return succs;
+            }
+            Inst::Ret => return succs,
+            _ => {}
+        }
+    }
+    if i + 1 < labels.len() {
+        succs.push(i + 1);
+    }
+    succs
+}
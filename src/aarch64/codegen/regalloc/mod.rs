@@ -1,3 +1,152 @@
+use std::collections::HashMap;
+
+use crate::aarch64::codegen::Context;
+use crate::aarch64::inst::Memory;
+use crate::aarch64::{Label, Register};
+
+mod coloring;
+mod linear;
+mod liveness;
 mod naive;
 
+use super::frame::insert_frame;
+use super::peephole::is_identity_move;
+
+pub use coloring::ColoringAllocator;
+pub use linear::LinearScanAllocator;
 pub use naive::NaiveRegisterAllocator;
+
+/// The register allocator run on each function.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegAlloc {
+    /// Keep every virtual register in its own stack slot.
+    Naive,
+    /// Linear scan over live intervals, spilling only under pressure.
+    Linear,
+    /// Graph colouring with iterated register coalescing.
+    Coloring,
+}
+
+/// Registers spilled values are reloaded into, and written through, around
+/// a single instruction. They never hold a value from one instruction to
+/// the next, so no allocator hands them out.
+const SCRATCH: [usize; 3] = [16, 17, 8];
+
+/// Where a virtual register lives once allocated.
+enum Location<'m> {
+    Reg(usize),
+    Slot(Memory<'m>),
+}
+
+/// Replace every virtual register in `labels` with its location. A spilled
+/// register is reloaded into a scratch register before each instruction
+/// reading it and stored back after each instruction writing it.
+fn rewrite<'m>(
+    ctx: &'m Context<'m>,
+    labels: &[&'m Label<'m>],
+    locations: &HashMap<u64, Location<'m>>,
+) {
+    for label in labels.iter() {
+        let old = std::mem::take(&mut *label.insts_mut());
+        let mut insts = Vec::with_capacity(old.len());
+        for inst in old {
+            let mut read = vec![];
+            let mut written = vec![];
+            inst.collect_vregs(&mut read, &mut written);
+
+            for (j, r) in read.iter_mut().enumerate() {
+                **r = match &locations[&vreg_id(r)] {
+                    Location::Reg(n) => ctx.x(*n),
+                    Location::Slot(slot) => {
+                        let scratch = ctx.x(SCRATCH[j]);
+                        insts.push(ctx.ldr(scratch, slot.clone()));
+                        scratch
+                    }
+                };
+            }
+            // Sources are read before the destination is written, so the
+            // scratch registers can be reused for it.
+            let mut stores = vec![];
+            for (j, w) in written.iter_mut().enumerate() {
+                **w = match &locations[&vreg_id(w)] {
+                    Location::Reg(n) => ctx.x(*n),
+                    Location::Slot(slot) => {
+                        let scratch = ctx.x(SCRATCH[j]);
+                        stores.push(ctx.str(scratch, slot.clone()));
+                        scratch
+                    }
+                };
+            }
+
+            drop(read);
+            drop(written);
+            // Copies between registers allocated to the same place are
+            // gone.
+            if !is_identity_move(inst) {
+                insts.push(inst);
+            }
+            insts.extend(stores);
+        }
+        *label.insts_mut() = insts;
+    }
+}
+
+/// Callee-saved registers a function has to preserve if it uses them.
+const CALLEE_SAVED: [usize; 10] = [19, 20, 21, 22, 23, 24, 25, 26, 27, 28];
+
+/// The callee-saved registers among `locations`, in ascending order.
+fn used_callee_saved(locations: &HashMap<u64, Location<'_>>) -> Vec<usize> {
+    let mut used: Vec<usize> = locations
+        .values()
+        .filter_map(|location| match location {
+            Location::Reg(r) if CALLEE_SAVED.contains(r) => Some(*r),
+            _ => None,
+        })
+        .collect();
+    used.sort();
+    used.dedup();
+    used
+}
+
+fn vreg_id(reg: &Register) -> u64 {
+    match reg {
+        Register::Virtual(id) => *id,
+        _ => panic!("not a virtual register"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::Machine;
+    use crate::aarch64::{compile_source, RegAlloc};
+
+    #[test]
+    fn values_live_across_calls() {
+        let src = "
+            func id(x: Int64) : Int64 {
+                return x;
+            }
+            func f(a: Int64, b: Int64, c: Int64) : Int64 {
+                var s: Int64 = id(a + 1);
+                var t: Int64 = id(b * 2);
+                return s * t + a * b * c;
+            }";
+        for regalloc in [RegAlloc::Linear, RegAlloc::Coloring] {
+            let module = compile_source(src, 1, regalloc);
+            let mut out = vec![];
+            module.dump(&mut out).unwrap();
+            let asm = String::from_utf8(out).unwrap();
+            let f = &asm[asm.find("_f:").unwrap()..];
+
+            // a, b, c and s are kept in callee-saved registers rather than
+            // stack slots, and those are saved and restored.
+            assert!(f.contains("\tstp\tx19, x20, [sp]\n"), "{}", asm);
+            assert!(f.contains("\tstp\tx21, x22, [sp, #16]\n"), "{}", asm);
+            assert!(f.contains("\tldp\tx19, x20, [sp]\n"), "{}", asm);
+            assert!(!f.contains("x23"), "{}", asm);
+
+            let mut machine = Machine::new(module);
+            assert_eq!(machine.call("f", &[2, 3, 4]), 3 * 6 + 24);
+        }
+    }
+}
@@ -1,8 +1,8 @@
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use super::{insert_frame, vreg_id};
 use crate::aarch64::codegen::{Context, FunctionCG};
-use crate::aarch64::inst::{Memory, RegOrImm};
+use crate::aarch64::inst::Memory;
 use crate::aarch64::{Func, Inst, Label, Register};
 
 pub struct NaiveRegisterAllocator<'m, 'cg> {
@@ -10,6 +10,7 @@ pub struct NaiveRegisterAllocator<'m, 'cg> {
     func: &'m Func<'m>,
     func_cg: &'cg mut FunctionCG<'m, 'cg>,
     map: HashMap<u64, Memory<'m>>,
+    deferred: HashSet<u64>,
 }
 
 impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
@@ -23,10 +24,32 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
             func,
             func_cg,
             map: HashMap::new(),
+            deferred: HashSet::new(),
         }
     }
 
     pub fn run(&mut self) {
+        self.deferred = self.deferrable_loads();
+
+        // Every other register gets its slot up front. Labels are not laid
+        // out in execution order, so a phi register may be read before the
+        // copy writing it.
+        for label in self.labels() {
+            for inst in label.insts().iter() {
+                let mut read = vec![];
+                let mut written = vec![];
+                inst.collect_vregs(&mut read, &mut written);
+                for r in written.iter() {
+                    let id = vreg_id(r);
+                    if !self.deferred.contains(&id) {
+                        self.map
+                            .entry(id)
+                            .or_insert_with(|| self.func_cg.new_stack_slot());
+                    }
+                }
+            }
+        }
+
         // Process all labels -- Replace all virtual registers with physical
         // registers.
         self.process_label(self.func.prologue());
@@ -36,56 +59,90 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
         self.process_label(self.func.epilogue());
 
         // Now all virtual registers gone, and we know the stack frame size.
-        // Insert instructions for extending stack, saving and adjusting
-        // frame pointer register.
-        let frame_size = self.func_cg.stack_frame_size();
-        let frame_size = (frame_size + 15) & !15;
-        {
-            let mut prologue = self.func.prologue().insts_mut();
-
-            prologue.insert(
-                0,
-                self.ctx
-                    .add(self.ctx.x(29), self.ctx.sp(), RegOrImm::Imm(frame_size)),
-            );
-
-            prologue.insert(
-                0,
-                self.ctx.stp(
-                    self.ctx.x(29),
-                    self.ctx.x(30),
-                    Memory::Stack {
-                        offset: frame_size as i64,
-                    },
-                ),
-            );
-
-            prologue.insert(
-                0,
-                self.ctx
-                    .sub(self.ctx.sp(), self.ctx.sp(), RegOrImm::Imm(frame_size + 16)),
-            );
-        }
-
-        {
-            let mut epilogue = self.func.epilogue().insts_mut();
-
-            epilogue.push(self.ctx.ldp(
-                self.ctx.x(29),
-                self.ctx.x(30),
-                Memory::Stack {
-                    offset: frame_size as i64,
-                },
-            ));
+        insert_frame(self.ctx, self.func, self.func_cg.stack_frame_size(), &[]);
+    }
 
-            epilogue.push(self.ctx.add(
-                self.ctx.sp(),
-                self.ctx.sp(),
-                RegOrImm::Imm(frame_size + 16),
-            ));
+    /// Virtual registers loaded from the stack whose load can be postponed
+    /// to each use. That is only the case if the load is the only write to
+    /// the register, and all reads follow it in the same label with no
+    /// store to the same slot in between.
+    fn deferrable_loads(&self) -> HashSet<u64> {
+        let labels = self.labels();
+
+        let mut reads: HashMap<u64, usize> = HashMap::new();
+        let mut writes: HashMap<u64, usize> = HashMap::new();
+        for label in labels.iter() {
+            for inst in label.insts().iter() {
+                let mut read = vec![];
+                let mut written = vec![];
+                inst.collect_vregs(&mut read, &mut written);
+                for r in read.iter() {
+                    *reads.entry(vreg_id(r)).or_default() += 1;
+                }
+                for r in written.iter() {
+                    *writes.entry(vreg_id(r)).or_default() += 1;
+                }
+            }
+        }
 
-            epilogue.push(self.ctx.ret());
+        let mut deferrable = HashSet::new();
+        for label in labels.iter() {
+            // Loads seen so far in this label, with their slot and the number
+            // of reads still to come.
+            let mut pending: HashMap<u64, (i64, usize)> = HashMap::new();
+            for inst in label.insts().iter() {
+                let mut read = vec![];
+                let mut written = vec![];
+                inst.collect_vregs(&mut read, &mut written);
+                for r in read.iter() {
+                    let id = vreg_id(r);
+                    if let Some((_, left)) = pending.get_mut(&id) {
+                        *left -= 1;
+                        if *left == 0 {
+                            pending.remove(&id);
+                            deferrable.insert(id);
+                        }
+                    }
+                }
+                drop(read);
+                drop(written);
+
+                match inst {
+                    // The deferred load is re-emitted at each use as a full
+                    // 64-bit load, so narrow loads are left in place.
+                    Inst::Ldr {
+                        dst,
+                        src: Memory::Stack { offset },
+                        size: 8,
+                    } => {
+                        if let Register::Virtual(id) = *dst.borrow() {
+                            let left = reads.get(id).copied().unwrap_or(0);
+                            if writes[id] == 1 {
+                                if left == 0 {
+                                    deferrable.insert(*id);
+                                } else {
+                                    pending.insert(*id, (*offset, left));
+                                }
+                            }
+                        }
+                    }
+                    Inst::Str {
+                        dst: Memory::Stack { offset },
+                        ..
+                    } => pending.retain(|_, (slot, _)| slot != offset),
+                    Inst::Str { .. } => pending.clear(),
+                    _ => {}
+                }
+            }
         }
+        deferrable
+    }
+
+    fn labels(&self) -> Vec<&'m Label<'m>> {
+        std::iter::once(self.func.prologue())
+            .chain(self.func.body().iter().copied())
+            .chain(std::iter::once(self.func.epilogue()))
+            .collect()
     }
 
     fn process_label(&mut self, label: &Label<'m>) {
@@ -95,14 +152,16 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
             // If we are loading to a virtual register, we remember the
             // memory operand and erase this instruction. In this way,
             // we postpone the load until we need that value.
-            if let Inst::Ldr { dst, src } = insts[i] {
-                if let Register::Virtual(r) = *dst.borrow() {
-                    self.map.insert(*r, src.clone());
+            if let Inst::Ldr { dst, src, .. } = insts[i] {
+                let deferred = match *dst.borrow() {
+                    Register::Virtual(r) if self.deferred.contains(r) => Some(*r),
+                    _ => None,
+                };
+                if let Some(r) = deferred {
+                    self.map.insert(r, src.clone());
                     insts.remove(i);
                     continue;
                 }
-                // The target operand doesn't matter, because the
-                // instruction is removed.
             }
 
             // Otherwise, we collect all the virtual registers that are
@@ -128,8 +187,8 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
                     panic!("not a virtual register");
                 };
                 let preg = self.ctx.x(8 + j);
-                let ptr = self.map.get(id).unwrap();
-                insts.insert(i + j, self.ctx.ldr(preg, ptr.clone()));
+                let ptr = self.map[id].clone();
+                insts.insert(i + j, self.ctx.ldr(preg, ptr));
 
                 **r = preg;
             }
@@ -137,8 +196,8 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
             // Let i points to the original instruction
             i += read.len();
 
-            // Each written virtal register in this instruction will be
-            // spilled to a new stack slot. We replace the virtual
+            // Each written virtal register in this instruction is
+            // spilled to its stack slot. We replace the virtual
             // register with a physical one, then store the physical one
             // into the slot.
             // FIXME We should check if the designated physical register is used by the
@@ -148,14 +207,7 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
                     panic!("not a virtual register");
                 };
                 let preg = self.ctx.x(8 + j + read.len());
-                let ptr = match self.map.entry(*id) {
-                    Entry::Occupied(e) => e.get().clone(),
-                    Entry::Vacant(e) => {
-                        let ptr = self.func_cg.new_stack_slot();
-                        e.insert(ptr.clone());
-                        ptr
-                    }
-                };
+                let ptr = self.map[id].clone();
                 insts.insert(i + j + 1, self.ctx.str(preg, ptr));
 
                 **vreg = preg;
@@ -166,3 +218,14 @@ impl<'m, 'cg> NaiveRegisterAllocator<'m, 'cg> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::aarch64::sim::check_programs;
+    use crate::aarch64::RegAlloc;
+
+    #[test]
+    fn programs() {
+        check_programs(RegAlloc::Naive);
+    }
+}
@@ -43,10 +43,40 @@ impl<'m> Context<'m> {
         })
     }
 
+    pub fn movz(&self, dst: &'m Register, imm: u64, shift: u64) -> &Inst<'m> {
+        self.inst.alloc(Inst::Movz {
+            dst: RefCell::new(dst),
+            imm,
+            shift,
+        })
+    }
+
+    pub fn movn(&self, dst: &'m Register, imm: u64, shift: u64) -> &Inst<'m> {
+        self.inst.alloc(Inst::Movn {
+            dst: RefCell::new(dst),
+            imm,
+            shift,
+        })
+    }
+
+    pub fn movk(&self, dst: &'m Register, imm: u64, shift: u64) -> &Inst<'m> {
+        self.inst.alloc(Inst::Movk {
+            dst: RefCell::new(dst),
+            imm,
+            shift,
+        })
+    }
+
     pub fn ldr(&self, dst: &'m Register, src: Memory<'m>) -> &Inst<'m> {
+        self.ldr_sized(dst, src, 8)
+    }
+
+    /// Load `size` bytes, zero-extended to the whole register.
+    pub fn ldr_sized(&self, dst: &'m Register, src: Memory<'m>, size: u64) -> &Inst<'m> {
         self.inst.alloc(Inst::Ldr {
             dst: RefCell::new(dst),
             src,
+            size,
         })
     }
 
@@ -59,9 +89,15 @@ impl<'m> Context<'m> {
     }
 
     pub fn str(&self, src: &'m Register, dst: Memory<'m>) -> &Inst<'m> {
+        self.str_sized(src, dst, 8)
+    }
+
+    /// Store the low `size` bytes of `src`.
+    pub fn str_sized(&self, src: &'m Register, dst: Memory<'m>, size: u64) -> &Inst<'m> {
         self.inst.alloc(Inst::Str {
             src: RefCell::new(src),
             dst,
+            size,
         })
     }
 
@@ -77,6 +113,10 @@ impl<'m> Context<'m> {
         self.inst.alloc(Inst::B { label })
     }
 
+    pub fn b_cond(&self, cond: ConditionCode, label: &'m Label<'m>) -> &Inst<'m> {
+        self.inst.alloc(Inst::BCond { cond, label })
+    }
+
     pub fn cbnz(&self, src: &'m Register, label: &'m Label<'m>) -> &Inst<'m> {
         self.inst.alloc(Inst::Cbnz {
             src: RefCell::new(src),
@@ -84,6 +124,13 @@ impl<'m> Context<'m> {
         })
     }
 
+    pub fn cbz(&self, src: &'m Register, label: &'m Label<'m>) -> &Inst<'m> {
+        self.inst.alloc(Inst::Cbz {
+            src: RefCell::new(src),
+            label,
+        })
+    }
+
     pub fn bl(&self, callee: &'m Label<'m>) -> &Inst<'m> {
         self.inst.alloc(Inst::Bl { callee })
     }
@@ -106,6 +153,29 @@ impl<'m> Context<'m> {
         })
     }
 
+    pub fn csel(
+        &self,
+        dst: &'m Register,
+        src1: &'m Register,
+        src2: &'m Register,
+        cond: ConditionCode,
+    ) -> &Inst<'m> {
+        self.inst.alloc(Inst::Csel {
+            dst: RefCell::new(dst),
+            src1: RefCell::new(src1),
+            src2: RefCell::new(src2),
+            cond,
+        })
+    }
+
+    pub fn cinc(&self, dst: &'m Register, src: &'m Register, cond: ConditionCode) -> &Inst<'m> {
+        self.inst.alloc(Inst::Cinc {
+            dst: RefCell::new(dst),
+            src: RefCell::new(src),
+            cond,
+        })
+    }
+
     pub fn orr(&self, dst: &'m Register, src1: &'m Register, src2: RegOrImm<'m>) -> &Inst<'m> {
         self.inst.alloc(Inst::Orr {
             dst: RefCell::new(dst),
@@ -186,6 +256,21 @@ impl<'m> Context<'m> {
         })
     }
 
+    pub fn madd(
+        &self,
+        dst: &'m Register,
+        src1: &'m Register,
+        src2: &'m Register,
+        src3: &'m Register,
+    ) -> &Inst<'m> {
+        self.inst.alloc(Inst::Madd {
+            dst: RefCell::new(dst),
+            src1: RefCell::new(src1),
+            src2: RefCell::new(src2),
+            src3: RefCell::new(src3),
+        })
+    }
+
     pub fn msub(
         &self,
         dst: &'m Register,
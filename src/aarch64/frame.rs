@@ -0,0 +1,78 @@
+use std::fmt;
+
+/// The layout of a function's stack frame, from the bottom up: the stack
+/// slots, the callee-saved registers and the frame record of x29 and x30.
+/// sp points at the bottom, and the stack slots are always addressed from
+/// it.
+///
+/// Functions making calls or reading arguments passed on the stack have the
+/// frame record, which x29 points at and whose saved x29 links to the
+/// caller's; the stack arguments start right above it and are addressed from
+/// x29. Leaf functions leave x29 and x30 alone and keep only what they need,
+/// which may be nothing at all.
+pub struct FrameLayout {
+    slots: u64,
+    saved: Vec<usize>,
+    record: bool,
+}
+
+impl FrameLayout {
+    /// A frame with `slots` bytes of stack slots, rounded up to keep sp
+    /// 16-byte aligned, saving the registers in `saved`, and with a frame
+    /// record if `record` is set.
+    pub fn new(slots: u64, saved: &[usize], record: bool) -> FrameLayout {
+        FrameLayout {
+            slots: slots.next_multiple_of(16),
+            saved: saved.to_vec(),
+            record,
+        }
+    }
+
+    /// Size of the stack slots at the bottom of the frame.
+    pub fn slots_size(&self) -> u64 {
+        self.slots
+    }
+
+    /// Size of the area the callee-saved registers are saved in, right
+    /// above the stack slots.
+    pub fn saved_size(&self) -> u64 {
+        (self.saved.len() as u64 * 8).next_multiple_of(16)
+    }
+
+    /// The callee-saved registers, saved in this order from the bottom of
+    /// their area.
+    pub fn saved(&self) -> &[usize] {
+        &self.saved
+    }
+
+    /// Offset of the frame record from sp, if there is one.
+    pub fn record_offset(&self) -> Option<u64> {
+        self.record.then(|| self.slots + self.saved_size())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.slots + self.saved_size() + if self.record { 16 } else { 0 }
+    }
+}
+
+impl fmt::Display for FrameLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.size() == 0 {
+            return write!(f, "no frame");
+        }
+        write!(f, "frame of {} bytes", self.size())?;
+        let mut parts = vec![];
+        if self.slots > 0 {
+            parts.push(format!("slots at [sp, #0, #{})", self.slots));
+        }
+        for (i, reg) in self.saved.iter().enumerate() {
+            parts.push(format!("x{} at [sp, #{}]", reg, self.slots + 8 * i as u64));
+        }
+        if let Some(record) = self.record_offset() {
+            parts.push(format!(
+                "x29 and x30 at [sp, #{record}], x29 = sp + {record}"
+            ));
+        }
+        write!(f, ": {}", parts.join(", "))
+    }
+}
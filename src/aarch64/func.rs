@@ -1,21 +1,28 @@
 use std::cell::{Ref, RefCell, RefMut};
 
-use super::Label;
+use super::{FrameLayout, Inst, Label};
+use crate::dot::Graph;
 
 pub struct Func<'m> {
     name: String,
+    global: bool,
     prologue: Option<&'m Label<'m>>,
     epilogue: Option<&'m Label<'m>>,
     body: RefCell<Vec<&'m Label<'m>>>,
+    tail_calls: RefCell<Vec<&'m Label<'m>>>,
+    frame: RefCell<Option<FrameLayout>>,
 }
 
 impl<'m> Func<'m> {
     pub fn new(name: String) -> Func<'m> {
         Func {
             name,
+            global: true,
             prologue: None,
             epilogue: None,
             body: RefCell::new(Vec::new()),
+            tail_calls: RefCell::new(Vec::new()),
+            frame: RefCell::new(None),
         }
     }
 
@@ -23,6 +30,15 @@ impl<'m> Func<'m> {
         &self.name
     }
 
+    /// Whether the function is visible to other object files.
+    pub fn is_global(&self) -> bool {
+        self.global
+    }
+
+    pub fn set_global(&mut self, global: bool) {
+        self.global = global;
+    }
+
     pub fn prologue(&self) -> &'m Label<'m> {
         self.prologue.unwrap()
     }
@@ -39,11 +55,73 @@ impl<'m> Func<'m> {
         self.epilogue = Some(epilogue);
     }
 
-    pub fn body(&self) -> Ref<Vec<&'m Label<'m>>> {
+    pub fn body(&self) -> Ref<'_, Vec<&'m Label<'m>>> {
         self.body.borrow()
     }
 
-    pub fn body_mut(&self) -> RefMut<Vec<&'m Label<'m>>> {
+    pub fn body_mut(&self) -> RefMut<'_, Vec<&'m Label<'m>>> {
         self.body.borrow_mut()
     }
+
+    /// Labels ending in a branch to another function, which need the frame
+    /// torn down right before the branch.
+    pub fn tail_calls(&self) -> Ref<'_, Vec<&'m Label<'m>>> {
+        self.tail_calls.borrow()
+    }
+
+    pub fn add_tail_call(&self, label: &'m Label<'m>) {
+        self.tail_calls.borrow_mut().push(label);
+    }
+
+    /// The layout of the stack frame, once the register allocator has laid
+    /// it out.
+    pub fn frame(&self) -> Ref<'_, Option<FrameLayout>> {
+        self.frame.borrow()
+    }
+
+    pub fn set_frame(&self, frame: FrameLayout) {
+        *self.frame.borrow_mut() = Some(frame);
+    }
+
+    /// The graph of labels, in layout order. Conditional branch edges are
+    /// labelled true and the branch or fall-through right after them false.
+    /// Labels not ending in a branch fall through to the next one; branches
+    /// out of the function, i.e. tail calls, are left out.
+    pub fn cfg_graph(&self) -> Graph {
+        let labels: Vec<&'m Label<'m>> = std::iter::once(self.prologue())
+            .chain(self.body().iter().copied())
+            .chain(std::iter::once(self.epilogue()))
+            .collect();
+        let is_local = |target: &Label<'m>| labels.iter().any(|l| std::ptr::eq(*l, target));
+
+        let mut graph = Graph::new(&self.name);
+        for (i, label) in labels.iter().enumerate() {
+            let insts = label.insts();
+            graph.add_node(label.name(), insts.iter().map(|i| i.to_string()).collect());
+            let mut after_cond = false;
+            for inst in insts.iter() {
+                match inst {
+                    Inst::Cbnz { label: target, .. }
+                    | Inst::Cbz { label: target, .. }
+                    | Inst::BCond { label: target, .. }
+                        if is_local(target) =>
+                    {
+                        graph.add_edge(label.name(), target.name(), Some("true"));
+                    }
+                    Inst::B { label: target } if is_local(target) => {
+                        let cond = after_cond.then_some("false");
+                        graph.add_edge(label.name(), target.name(), cond);
+                    }
+                    _ => {}
+                }
+                after_cond = inst.is_conditional_branch();
+            }
+            let falls_through = !matches!(insts.last(), Some(Inst::B { .. } | Inst::Ret));
+            if let Some(next) = labels.get(i + 1).filter(|_| falls_through) {
+                let cond = after_cond.then_some("false");
+                graph.add_edge(label.name(), next.name(), cond);
+            }
+        }
+        graph
+    }
 }
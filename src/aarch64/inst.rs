@@ -1,4 +1,4 @@
-use std::cell::{Ref, RefCell, RefMut};
+use std::cell::{RefCell, RefMut};
 use std::fmt;
 
 use super::{Label, Register};
@@ -8,18 +8,41 @@ pub enum Inst<'m> {
         dst: RefCell<&'m Register>,
         src: RegOrImm<'m>,
     },
+    // Move wide: `imm` is a 16-bit chunk placed at bit `shift`. `movz`
+    // clears the other bits, `movn` sets them and inverts the chunk, and
+    // `movk` keeps them.
+    Movz {
+        dst: RefCell<&'m Register>,
+        imm: u64,
+        shift: u64,
+    },
+    Movn {
+        dst: RefCell<&'m Register>,
+        imm: u64,
+        shift: u64,
+    },
+    Movk {
+        dst: RefCell<&'m Register>,
+        imm: u64,
+        shift: u64,
+    },
+    // Loads `size` bytes, zero-extended. Sizes below 8 use the 32-bit view
+    // of the register.
     Ldr {
         dst: RefCell<&'m Register>,
         src: Memory<'m>,
+        size: u64,
     },
     Ldp {
         dst1: RefCell<&'m Register>,
         dst2: RefCell<&'m Register>,
         src: Memory<'m>,
     },
+    // Stores the low `size` bytes of the register.
     Str {
         src: RefCell<&'m Register>,
         dst: Memory<'m>,
+        size: u64,
     },
     Stp {
         src1: RefCell<&'m Register>,
@@ -33,6 +56,15 @@ pub enum Inst<'m> {
         src: RefCell<&'m Register>,
         label: &'m Label<'m>,
     },
+    Cbz {
+        src: RefCell<&'m Register>,
+        label: &'m Label<'m>,
+    },
+    // Branches if `cond` holds for the flags.
+    BCond {
+        cond: ConditionCode,
+        label: &'m Label<'m>,
+    },
     Bl {
         callee: &'m Label<'m>,
     },
@@ -42,6 +74,19 @@ pub enum Inst<'m> {
         dst: RefCell<&'m Register>,
         cond: ConditionCode,
     },
+    // `src1` if `cond` holds for the flags, `src2` otherwise.
+    Csel {
+        dst: RefCell<&'m Register>,
+        src1: RefCell<&'m Register>,
+        src2: RefCell<&'m Register>,
+        cond: ConditionCode,
+    },
+    // `src + 1` if `cond` holds for the flags, `src` otherwise.
+    Cinc {
+        dst: RefCell<&'m Register>,
+        src: RefCell<&'m Register>,
+        cond: ConditionCode,
+    },
     Cmp {
         src1: RefCell<&'m Register>,
         src2: RegOrImm<'m>,
@@ -97,6 +142,14 @@ pub enum Inst<'m> {
         src1: RefCell<&'m Register>,
         src2: RefCell<&'m Register>,
     },
+    // `src3 + src1 * src2`.
+    Madd {
+        dst: RefCell<&'m Register>,
+        src1: RefCell<&'m Register>,
+        src2: RefCell<&'m Register>,
+        src3: RefCell<&'m Register>,
+    },
+    // `src3 - src1 * src2`.
     Msub {
         dst: RefCell<&'m Register>,
         src1: RefCell<&'m Register>,
@@ -115,7 +168,9 @@ impl<'m> Inst<'m> {
         read: &mut Vec<RefMut<&'m Register>>,
     ) {
         match reg_or_imm {
-            RegOrImm::Reg(r) => Self::collect_vregs_from_reg(r, read),
+            RegOrImm::Reg(r) | RegOrImm::Shifted { reg: r, .. } => {
+                Self::collect_vregs_from_reg(r, read)
+            }
             RegOrImm::Imm(_) => {}
         }
     }
@@ -143,6 +198,20 @@ impl<'m> Inst<'m> {
         }
     }
 
+    /// The label a conditional branch may jump to.
+    pub fn conditional_target(&self) -> Option<&'m Label<'m>> {
+        match self {
+            Self::Cbnz { label, .. } | Self::Cbz { label, .. } | Self::BCond { label, .. } => {
+                Some(label)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_conditional_branch(&self) -> bool {
+        self.conditional_target().is_some()
+    }
+
     pub fn collect_vregs(
         &'m self,
         read: &mut Vec<RefMut<&'m Register>>,
@@ -153,7 +222,13 @@ impl<'m> Inst<'m> {
                 Self::collect_vregs_from_reg(dst, written);
                 Self::collect_vregs_from_reg_or_imm(src, read);
             }
-            Self::Ldr { dst, src } => {
+            // `movk` keeps the other bits of its destination, but the
+            // sequences it appears in are only built after register
+            // allocation, so its destination is never virtual.
+            Self::Movz { dst, .. } | Self::Movn { dst, .. } | Self::Movk { dst, .. } => {
+                Self::collect_vregs_from_reg(dst, written);
+            }
+            Self::Ldr { dst, src, .. } => {
                 Self::collect_vregs_from_reg(dst, written);
                 Self::collect_vregs_from_mem(src, read);
             }
@@ -165,7 +240,7 @@ impl<'m> Inst<'m> {
 
             // For STR and STP, the registers inside `dst` will be read, not be written, so we
             // collect them into read.
-            Self::Str { src, dst } => {
+            Self::Str { src, dst, .. } => {
                 Self::collect_vregs_from_reg(src, read);
                 Self::collect_vregs_from_mem(dst, read);
             }
@@ -175,7 +250,7 @@ impl<'m> Inst<'m> {
                 Self::collect_vregs_from_mem(dst, read);
             }
 
-            Self::Cbnz { src, label: _ } => {
+            Self::Cbnz { src, label: _ } | Self::Cbz { src, label: _ } => {
                 Self::collect_vregs_from_reg(src, read);
             }
             Self::Cmp { src1, src2 } => {
@@ -186,6 +261,17 @@ impl<'m> Inst<'m> {
             Self::Cset { dst, cond: _ } => {
                 Self::collect_vregs_from_reg(dst, written);
             }
+            Self::Csel {
+                dst, src1, src2, ..
+            } => {
+                Self::collect_vregs_from_reg(dst, written);
+                Self::collect_vregs_from_reg(src1, read);
+                Self::collect_vregs_from_reg(src2, read);
+            }
+            Self::Cinc { dst, src, .. } => {
+                Self::collect_vregs_from_reg(dst, written);
+                Self::collect_vregs_from_reg(src, read);
+            }
             Self::Add { dst, src1, src2 }
             | Self::Sub { dst, src1, src2 }
             | Self::Orr { dst, src1, src2 }
@@ -203,7 +289,13 @@ impl<'m> Inst<'m> {
                 Self::collect_vregs_from_reg(src1, read);
                 Self::collect_vregs_from_reg(src2, read);
             }
-            Self::Msub {
+            Self::Madd {
+                dst,
+                src1,
+                src2,
+                src3,
+            }
+            | Self::Msub {
                 dst,
                 src1,
                 src2,
@@ -219,8 +311,7 @@ impl<'m> Inst<'m> {
                 Self::collect_vregs_from_reg(src, read);
             }
 
-            Self::B { label: _ } | Self::Bl { callee: _ } | Self::Ret => {}
-            _ => unimplemented!(),
+            Self::B { label: _ } | Self::BCond { .. } | Self::Bl { callee: _ } | Self::Ret => {}
         }
     }
 }
@@ -229,20 +320,55 @@ impl fmt::Display for Inst<'_> {
     fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Inst::Mov { dst, src } => write!(out, "mov\t{}, {}", dst.borrow(), src)?,
-            Inst::Ldr { dst, src } => write!(out, "ldr\t{}, {}", dst.borrow(), src)?,
+            Inst::Movz { dst, imm, shift } => {
+                write!(out, "movz\t{}, #{}{}", dst.borrow(), imm, Lsl(*shift))?
+            }
+            Inst::Movn { dst, imm, shift } => {
+                write!(out, "movn\t{}, #{}{}", dst.borrow(), imm, Lsl(*shift))?
+            }
+            Inst::Movk { dst, imm, shift } => {
+                write!(out, "movk\t{}, #{}{}", dst.borrow(), imm, Lsl(*shift))?
+            }
+            Inst::Ldr { dst, src, size } => match size {
+                8 => write!(out, "ldr\t{}, {}", dst.borrow(), src)?,
+                _ => write!(out, "ldr{}\t{}, {}", suffix(*size), dst.borrow().w(), src)?,
+            },
             Inst::Ldp { dst1, dst2, src } => {
                 write!(out, "ldp\t{}, {}, {}", dst1.borrow(), dst2.borrow(), src)?
             }
-            Inst::Str { src, dst } => write!(out, "str\t{}, {}", src.borrow(), dst)?,
+            Inst::Str { src, dst, size } => match size {
+                8 => write!(out, "str\t{}, {}", src.borrow(), dst)?,
+                _ => write!(out, "str{}\t{}, {}", suffix(*size), src.borrow().w(), dst)?,
+            },
             Inst::Stp { src1, src2, dst } => {
                 write!(out, "stp\t{}, {}, {}", src1.borrow(), src2.borrow(), dst)?
             }
             Inst::B { label } => write!(out, "b\t{}", label.name())?,
             Inst::Cbnz { src, label } => write!(out, "cbnz\t{}, {}", src.borrow(), label.name())?,
+            Inst::Cbz { src, label } => write!(out, "cbz\t{}, {}", src.borrow(), label.name())?,
+            Inst::BCond { cond, label } => write!(out, "b.{}\t{}", cond, label.name())?,
             Inst::Bl { callee } => write!(out, "bl\t{}", callee.name())?,
             Inst::Ret => write!(out, "ret")?,
-            Inst::Cmp { src1, src2 } => write!(out, "cmp\t{}, {}", src1.borrow(), src2)?,
+            Inst::Cmp { src1, src2 } => {
+                write!(out, "cmp\t{}, {}", src1.borrow(), ArithOperand(src2))?
+            }
             Inst::Cset { dst, cond } => write!(out, "cset\t{}, {}", dst.borrow(), cond)?,
+            Inst::Csel {
+                dst,
+                src1,
+                src2,
+                cond,
+            } => write!(
+                out,
+                "csel\t{}, {}, {}, {}",
+                dst.borrow(),
+                src1.borrow(),
+                src2.borrow(),
+                cond
+            )?,
+            Inst::Cinc { dst, src, cond } => {
+                write!(out, "cinc\t{}, {}, {}", dst.borrow(), src.borrow(), cond)?
+            }
             Inst::Orr { dst, src1, src2 } => {
                 write!(out, "orr\t{}, {}, {}", dst.borrow(), src1.borrow(), src2)?
             }
@@ -261,12 +387,20 @@ impl fmt::Display for Inst<'_> {
             Inst::Asr { dst, src1, src2 } => {
                 write!(out, "asr\t{}, {}, {}", dst.borrow(), src1.borrow(), src2)?
             }
-            Inst::Add { dst, src1, src2 } => {
-                write!(out, "add\t{}, {}, {}", dst.borrow(), src1.borrow(), src2)?
-            }
-            Inst::Sub { dst, src1, src2 } => {
-                write!(out, "sub\t{}, {}, {}", dst.borrow(), src1.borrow(), src2)?
-            }
+            Inst::Add { dst, src1, src2 } => write!(
+                out,
+                "add\t{}, {}, {}",
+                dst.borrow(),
+                src1.borrow(),
+                ArithOperand(src2)
+            )?,
+            Inst::Sub { dst, src1, src2 } => write!(
+                out,
+                "sub\t{}, {}, {}",
+                dst.borrow(),
+                src1.borrow(),
+                ArithOperand(src2)
+            )?,
             Inst::Mul { dst, src1, src2 } => write!(
                 out,
                 "mul\t{}, {}, {}",
@@ -281,6 +415,19 @@ impl fmt::Display for Inst<'_> {
                 src1.borrow(),
                 src2.borrow()
             )?,
+            Inst::Madd {
+                dst,
+                src1,
+                src2,
+                src3,
+            } => write!(
+                out,
+                "madd\t{}, {}, {}, {}",
+                dst.borrow(),
+                src1.borrow(),
+                src2.borrow(),
+                src3.borrow()
+            )?,
             Inst::Msub {
                 dst,
                 src1,
@@ -300,8 +447,46 @@ impl fmt::Display for Inst<'_> {
     }
 }
 
+/// The shift of a move wide immediate, if any.
+struct Lsl(u64);
+
+impl fmt::Display for Lsl {
+    fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => Ok(()),
+            shift => write!(out, ", lsl #{}", shift),
+        }
+    }
+}
+
+/// The second operand of `add`, `sub` and `cmp`, whose immediate is 12 bits
+/// optionally shifted left by 12.
+struct ArithOperand<'a, 'm>(&'a RegOrImm<'m>);
+
+impl fmt::Display for ArithOperand<'_, '_> {
+    fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            RegOrImm::Imm(imm) if *imm >= 4096 => write!(out, "#{}, lsl #12", imm >> 12),
+            operand => write!(out, "{}", operand),
+        }
+    }
+}
+
+/// Mnemonic suffix of a load or store of `size` bytes through a `w`
+/// register.
+fn suffix(size: u64) -> &'static str {
+    match size {
+        1 => "b",
+        2 => "h",
+        4 => "",
+        _ => panic!("Invalid access size: {}", size),
+    }
+}
+
 // ARM Condition codes
 // https://developer.arm.com/documentation/dui0379/e/arm-and-thumb-instructions/condition-codes
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum ConditionCode {
     EQ, // Equal
     NE, // Not equal
@@ -320,6 +505,29 @@ pub enum ConditionCode {
     AL, // Always (unconditional)
 }
 
+impl ConditionCode {
+    /// The condition holding exactly when this one does not.
+    pub fn invert(self) -> ConditionCode {
+        match self {
+            ConditionCode::EQ => ConditionCode::NE,
+            ConditionCode::NE => ConditionCode::EQ,
+            ConditionCode::CS => ConditionCode::CC,
+            ConditionCode::CC => ConditionCode::CS,
+            ConditionCode::MI => ConditionCode::PL,
+            ConditionCode::PL => ConditionCode::MI,
+            ConditionCode::VS => ConditionCode::VC,
+            ConditionCode::VC => ConditionCode::VS,
+            ConditionCode::HI => ConditionCode::LS,
+            ConditionCode::LS => ConditionCode::HI,
+            ConditionCode::GE => ConditionCode::LT,
+            ConditionCode::LT => ConditionCode::GE,
+            ConditionCode::GT => ConditionCode::LE,
+            ConditionCode::LE => ConditionCode::GT,
+            ConditionCode::AL => panic!("`al` has no inverse"),
+        }
+    }
+}
+
 impl fmt::Display for ConditionCode {
     fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -346,6 +554,13 @@ impl fmt::Display for ConditionCode {
 pub enum RegOrImm<'m> {
     Reg(RefCell<&'m Register>),
     Imm(u64),
+    // A register shifted by a constant, only taken as the second operand of
+    // `add`, `sub`, `cmp`, `and`, `orr` and `eor`.
+    Shifted {
+        reg: RefCell<&'m Register>,
+        shift: Shift,
+        amount: u64,
+    },
 }
 
 impl fmt::Display for RegOrImm<'_> {
@@ -353,12 +568,78 @@ impl fmt::Display for RegOrImm<'_> {
         match self {
             RegOrImm::Reg(reg) => write!(out, "{}", reg.borrow())?,
             RegOrImm::Imm(imm) => write!(out, "#{}", imm)?,
+            RegOrImm::Shifted { reg, shift, amount } => {
+                write!(out, "{}, {} #{}", reg.borrow(), shift, amount)?
+            }
         }
         Ok(())
     }
 }
 
+#[derive(Clone, Copy)]
+pub enum Shift {
+    Lsl,
+    Lsr,
+    Asr,
+}
+
+impl fmt::Display for Shift {
+    fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shift::Lsl => write!(out, "lsl"),
+            Shift::Lsr => write!(out, "lsr"),
+            Shift::Asr => write!(out, "asr"),
+        }
+    }
+}
+
+/// Whether `imm` fits the unsigned 12-bit immediate of `add`, `sub` and
+/// `cmp`, which may be shifted left by 12.
+pub fn is_arith_imm(imm: u64) -> bool {
+    imm < 4096 || (imm & 0xfff == 0 && imm >> 12 < 4096)
+}
+
+/// Whether `offset` can be encoded by a load or store of `size` bytes: an
+/// unsigned 12-bit offset scaled by the size, or a small unscaled one.
+pub fn is_mem_offset(offset: i64, size: u64) -> bool {
+    let size = size as i64;
+    (offset % size == 0 && (0..4096 * size).contains(&offset)) || (-256..256).contains(&offset)
+}
+
+/// Whether a plain `mov` can take `imm`, i.e. it is a single 16-bit chunk.
+pub fn is_mov_imm(imm: u64) -> bool {
+    imm < 1 << 16
+}
+
+/// Whether `imm` can be encoded as the bitmask immediate of `and`, `orr` and
+/// `eor`: a repeated element of 2 to 64 bits holding one rotated run of ones.
+pub fn is_logical_imm(imm: u64) -> bool {
+    if imm == 0 || imm == u64::MAX {
+        return false;
+    }
+    let mut size = 64;
+    while size > 2 {
+        let half = size / 2;
+        let mask = (1u64 << half) - 1;
+        if imm & mask != (imm >> half) & mask {
+            break;
+        }
+        size = half;
+    }
+    let mask = if size == 64 {
+        u64::MAX
+    } else {
+        (1u64 << size) - 1
+    };
+    let elem = imm & mask;
+    // A single run of ones, allowing for wrap-around, has exactly two
+    // boundaries between zeros and ones.
+    let rotated = ((elem >> 1) | (elem << (size - 1))) & mask;
+    (elem ^ rotated).count_ones() == 2
+}
+
 #[derive(Clone)]
+#[allow(dead_code)]
 pub enum Memory<'m> {
     Base {
         register: RefCell<&'m Register>,
@@ -393,3 +674,44 @@ impl fmt::Display for Memory<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logical_immediates() {
+        assert!(is_logical_imm(1));
+        assert!(is_logical_imm(0xff00));
+        assert!(is_logical_imm(0x5555_5555_5555_5555));
+        assert!(is_logical_imm(0x8000_0000_0000_0001));
+        assert!(is_logical_imm(!0xf));
+        assert!(!is_logical_imm(0));
+        assert!(!is_logical_imm(u64::MAX));
+        assert!(!is_logical_imm(5));
+        assert!(!is_logical_imm(0x1234));
+    }
+
+    #[test]
+    fn sized_loads_and_stores() {
+        let reg = Register::Physical(3);
+        let slot = || Memory::Stack { offset: 8 };
+        let ldr = |size| Inst::Ldr {
+            dst: RefCell::new(&reg),
+            src: slot(),
+            size,
+        };
+        let str = |size| Inst::Str {
+            src: RefCell::new(&reg),
+            dst: slot(),
+            size,
+        };
+        let mem = slot().to_string();
+        assert_eq!(ldr(1).to_string(), format!("ldrb\tw3, {mem}"));
+        assert_eq!(ldr(2).to_string(), format!("ldrh\tw3, {mem}"));
+        assert_eq!(ldr(4).to_string(), format!("ldr\tw3, {mem}"));
+        assert_eq!(ldr(8).to_string(), format!("ldr\tx3, {mem}"));
+        assert_eq!(str(1).to_string(), format!("strb\tw3, {mem}"));
+        assert_eq!(str(8).to_string(), format!("str\tx3, {mem}"));
+    }
+}
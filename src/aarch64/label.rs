@@ -18,11 +18,11 @@ impl<'m> Label<'m> {
         &self.name
     }
 
-    pub fn insts(&self) -> Ref<Vec<&'m Inst<'m>>> {
+    pub fn insts(&self) -> Ref<'_, Vec<&'m Inst<'m>>> {
         self.insts.borrow()
     }
 
-    pub fn insts_mut(&self) -> RefMut<Vec<&'m Inst<'m>>> {
+    pub fn insts_mut(&self) -> RefMut<'_, Vec<&'m Inst<'m>>> {
         self.insts.borrow_mut()
     }
 
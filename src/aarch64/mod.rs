@@ -1,15 +1,19 @@
 use std::cell::{Ref, RefCell, RefMut};
 
 mod codegen;
-pub use codegen::Codegen;
+pub use codegen::{Codegen, RegAlloc};
 
 mod context;
+mod frame;
 mod func;
 mod inst;
 mod label;
 mod reg;
+#[cfg(test)]
+mod sim;
 
 use context::Context;
+use frame::FrameLayout;
 use func::Func;
 use inst::{ConditionCode, Inst, Memory, RegOrImm};
 use label::Label;
@@ -17,6 +21,7 @@ use reg::Register;
 
 pub struct Module<'m> {
     ctx: Context<'m>,
+    #[allow(dead_code)]
     externs: Vec<&'m Label<'m>>,
     functions: RefCell<Vec<&'m Func<'m>>>,
 }
@@ -34,11 +39,11 @@ impl<'m> Module<'m> {
         &self.ctx
     }
 
-    pub fn functions(&self) -> Ref<Vec<&'m Func<'m>>> {
+    pub fn functions(&self) -> Ref<'_, Vec<&'m Func<'m>>> {
         self.functions.borrow()
     }
 
-    pub fn functions_mut(&self) -> RefMut<Vec<&'m Func<'m>>> {
+    pub fn functions_mut(&self) -> RefMut<'_, Vec<&'m Func<'m>>> {
         self.functions.borrow_mut()
     }
 
@@ -46,8 +51,13 @@ impl<'m> Module<'m> {
         // TODO externs is not supported yet
 
         for func in self.functions().iter() {
-            writeln!(out, "\t.global\t{}", func.prologue().name(),)?;
+            if func.is_global() {
+                writeln!(out, "\t.global\t{}", func.prologue().name(),)?;
+            }
             writeln!(out, "\t.p2align\t2")?;
+            if let Some(frame) = func.frame().as_ref() {
+                writeln!(out, "\t// {}", frame)?;
+            }
 
             self.dump_label(out, func.prologue())?;
             for label in func.body().iter() {
@@ -68,3 +78,20 @@ impl<'m> Module<'m> {
         Ok(())
     }
 }
+
+/// Lower `src` to IR, optimize it at `opt_level` and generate code for it
+/// with `regalloc`, for tests.
+#[cfg(test)]
+pub(crate) fn compile_source(
+    src: &str,
+    opt_level: u8,
+    regalloc: RegAlloc,
+) -> &'static Module<'static> {
+    let ir_module = crate::ir::lower_source(src);
+    crate::ir::PassManager::with_opt_level(opt_level).run(ir_module);
+
+    let module: &'static Module<'static> = Box::leak(Box::new(Module::new()));
+    let mut codegen = Codegen::new(module);
+    codegen.visit_unit(ir_module, Some(regalloc));
+    module
+}
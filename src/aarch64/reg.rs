@@ -31,3 +31,22 @@ impl fmt::Display for Register {
         }
     }
 }
+
+impl Register {
+    /// The 32-bit view of this register, `w<n>` instead of `x<n>`. Writing
+    /// it clears the upper half of the register.
+    pub fn w(&self) -> WRegister<'_> {
+        WRegister(self)
+    }
+}
+
+pub struct WRegister<'a>(&'a Register);
+
+impl fmt::Display for WRegister<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Register::Physical(id) if *id < 31 => write!(f, "w{}", id),
+            reg => write!(f, "{}", reg),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+//! A small interpreter for the generated machine code, so that tests can run
+//! the backend's output without an aarch64 machine.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use super::inst::{is_arith_imm, is_logical_imm, is_mov_imm, Shift};
+use super::{
+    compile_source, ConditionCode, Inst, Label, Memory, Module, RegAlloc, RegOrImm, Register,
+};
+
+const STACK_SIZE: usize = 1 << 20;
+const MAX_STEPS: usize = 10_000_000;
+/// Return address of the outermost call; returning to it stops the run.
+const EXIT: u64 = u64::MAX;
+
+pub struct Machine<'m> {
+    program: Vec<&'m Inst<'m>>,
+    labels: HashMap<*const Label<'m>, usize>,
+    functions: HashMap<String, usize>,
+    regs: [u64; 32],
+    memory: Vec<u8>,
+    // Operands of the last `cmp`.
+    flags: (u64, u64),
+}
+
+impl<'m> Machine<'m> {
+    pub fn new(module: &Module<'m>) -> Machine<'m> {
+        let mut program = vec![];
+        let mut labels = HashMap::new();
+        let mut functions = HashMap::new();
+        for func in module.functions().iter() {
+            functions.insert(func.name().to_string(), program.len());
+            let body = func.body();
+            let all = std::iter::once(func.prologue())
+                .chain(body.iter().copied())
+                .chain(std::iter::once(func.epilogue()));
+            for label in all {
+                labels.insert(label as *const Label<'m>, program.len());
+                program.extend(label.insts().iter().copied());
+            }
+        }
+        Machine {
+            program,
+            labels,
+            functions,
+            regs: [0; 32],
+            memory: vec![0; STACK_SIZE],
+            flags: (0, 0),
+        }
+    }
+
+    /// Call `name` with `args` and return the value left in x0. Panics if
+    /// the callee does not preserve the callee-saved registers, the frame
+    /// pointer or the stack pointer.
+    pub fn call(&mut self, name: &str, args: &[u64]) -> u64 {
+        // Garbage in every register, so that reads of registers that were
+        // never written show up in the results.
+        for (i, reg) in self.regs.iter_mut().enumerate() {
+            *reg = 0xdead_0000 + i as u64;
+        }
+        let (in_regs, on_stack) = args.split_at(args.len().min(8));
+        self.regs[..in_regs.len()].copy_from_slice(in_regs);
+        self.regs[30] = EXIT;
+        self.regs[31] = (STACK_SIZE - (on_stack.len() * 8).next_multiple_of(16)) as u64;
+        for (i, arg) in on_stack.iter().enumerate() {
+            self.store(self.regs[31] as usize + 8 * i, *arg, 8);
+        }
+        // Registers at each call in progress.
+        let mut frames = vec![self.regs];
+
+        let mut pc = self.functions[name];
+        for _ in 0..MAX_STEPS {
+            let inst = self.program[pc];
+            assert!(is_encodable(inst), "`{}` cannot be encoded", inst);
+            pc += 1;
+            match inst {
+                Inst::Mov { dst, src } => {
+                    let val = self.operand(src);
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Movz { dst, imm, shift } => self.set(&dst.borrow(), imm << shift),
+                Inst::Movn { dst, imm, shift } => self.set(&dst.borrow(), !(imm << shift)),
+                Inst::Movk { dst, imm, shift } => {
+                    let val = self.get(&dst.borrow()) & !(0xffff << shift) | imm << shift;
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Ldr { dst, src, size } => {
+                    let addr = self.address(src);
+                    let val = self.load(addr, *size);
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Ldp { dst1, dst2, src } => {
+                    let addr = self.address(src);
+                    let (first, second) = (self.load(addr, 8), self.load(addr + 8, 8));
+                    self.set(&dst1.borrow(), first);
+                    self.set(&dst2.borrow(), second);
+                }
+                Inst::Str { src, dst, size } => {
+                    let addr = self.address(dst);
+                    self.store(addr, self.get(&src.borrow()), *size);
+                }
+                Inst::Stp { src1, src2, dst } => {
+                    let addr = self.address(dst);
+                    self.store(addr, self.get(&src1.borrow()), 8);
+                    self.store(addr + 8, self.get(&src2.borrow()), 8);
+                }
+                Inst::B { label } => pc = self.target(label),
+                Inst::Cbnz { src, label } => {
+                    if self.get(&src.borrow()) != 0 {
+                        pc = self.target(label);
+                    }
+                }
+                Inst::Cbz { src, label } => {
+                    if self.get(&src.borrow()) == 0 {
+                        pc = self.target(label);
+                    }
+                }
+                Inst::Bl { callee } => {
+                    assert_eq!(self.regs[31] % 16, 0, "sp is misaligned at a call");
+                    self.regs[30] = pc as u64;
+                    frames.push(self.regs);
+                    pc = self.target(callee);
+                }
+                Inst::Ret => {
+                    let saved = frames.pop().unwrap();
+                    for i in (19..=29).chain([31]) {
+                        assert_eq!(self.regs[i], saved[i], "x{} is not preserved", i);
+                    }
+                    if self.regs[30] == EXIT {
+                        return self.regs[0];
+                    }
+                    // The caller cannot rely on anything else a call may
+                    // overwrite.
+                    for (i, reg) in self.regs[1..=18].iter_mut().enumerate() {
+                        *reg = 0xbad_0000 + i as u64;
+                    }
+                    pc = self.regs[30] as usize;
+                }
+                Inst::Cmp { src1, src2 } => {
+                    self.flags = (self.get(&src1.borrow()), self.operand(src2));
+                }
+                Inst::BCond { cond, label } => {
+                    if self.holds(*cond) {
+                        pc = self.target(label);
+                    }
+                }
+                Inst::Cset { dst, cond } => {
+                    let holds = self.holds(*cond);
+                    self.set(&dst.borrow(), holds as u64);
+                }
+                Inst::Csel {
+                    dst,
+                    src1,
+                    src2,
+                    cond,
+                } => {
+                    let src = if self.holds(*cond) { src1 } else { src2 };
+                    let val = self.get(&src.borrow());
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Cinc { dst, src, cond } => {
+                    let inc = self.holds(*cond) as u64;
+                    let val = self.get(&src.borrow()).wrapping_add(inc);
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Orr { dst, src1, src2 } => self.binary(dst, src1, src2, |a, b| a | b),
+                Inst::Eor { dst, src1, src2 } => self.binary(dst, src1, src2, |a, b| a ^ b),
+                Inst::And { dst, src1, src2 } => self.binary(dst, src1, src2, |a, b| a & b),
+                Inst::Lsl { dst, src1, src2 } => self.binary(dst, src1, src2, |a, b| a << (b & 63)),
+                Inst::Lsr { dst, src1, src2 } => self.binary(dst, src1, src2, |a, b| a >> (b & 63)),
+                Inst::Asr { dst, src1, src2 } => {
+                    self.binary(dst, src1, src2, |a, b| ((a as i64) >> (b & 63)) as u64)
+                }
+                Inst::Add { dst, src1, src2 } => self.binary(dst, src1, src2, u64::wrapping_add),
+                Inst::Sub { dst, src1, src2 } => self.binary(dst, src1, src2, u64::wrapping_sub),
+                Inst::Mul { dst, src1, src2 } => {
+                    let val = self
+                        .get(&src1.borrow())
+                        .wrapping_mul(self.get(&src2.borrow()));
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Sdiv { dst, src1, src2 } => {
+                    let a = self.get(&src1.borrow()) as i64;
+                    let b = self.get(&src2.borrow()) as i64;
+                    let val = if b == 0 { 0 } else { a.wrapping_div(b) };
+                    self.set(&dst.borrow(), val as u64);
+                }
+                Inst::Madd {
+                    dst,
+                    src1,
+                    src2,
+                    src3,
+                } => {
+                    let product = self
+                        .get(&src1.borrow())
+                        .wrapping_mul(self.get(&src2.borrow()));
+                    let val = self.get(&src3.borrow()).wrapping_add(product);
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Msub {
+                    dst,
+                    src1,
+                    src2,
+                    src3,
+                } => {
+                    let product = self
+                        .get(&src1.borrow())
+                        .wrapping_mul(self.get(&src2.borrow()));
+                    let val = self.get(&src3.borrow()).wrapping_sub(product);
+                    self.set(&dst.borrow(), val);
+                }
+                Inst::Mvn { dst, src } => {
+                    let val = !self.get(&src.borrow());
+                    self.set(&dst.borrow(), val);
+                }
+            }
+        }
+        panic!("{} did not return within {} steps", name, MAX_STEPS);
+    }
+
+    fn target(&self, label: &Label<'m>) -> usize {
+        self.labels[&(label as *const Label<'m>)]
+    }
+
+    /// Whether `cond` holds for the operands of the last `cmp`.
+    fn holds(&self, cond: ConditionCode) -> bool {
+        let (a, b) = self.flags;
+        // The sign and signed overflow of `a - b`, as `cmp` sets them.
+        let negative = (a.wrapping_sub(b) as i64) < 0;
+        let overflow = (a as i64).overflowing_sub(b as i64).1;
+        match cond {
+            ConditionCode::EQ => a == b,
+            ConditionCode::NE => a != b,
+            ConditionCode::CS => a >= b,
+            ConditionCode::CC => a < b,
+            ConditionCode::HI => a > b,
+            ConditionCode::LS => a <= b,
+            ConditionCode::GE => a as i64 >= b as i64,
+            ConditionCode::LT => (a as i64) < b as i64,
+            ConditionCode::GT => a as i64 > b as i64,
+            ConditionCode::LE => a as i64 <= b as i64,
+            ConditionCode::MI => negative,
+            ConditionCode::PL => !negative,
+            ConditionCode::VS => overflow,
+            ConditionCode::VC => !overflow,
+            ConditionCode::AL => true,
+        }
+    }
+
+    fn get(&self, reg: &Register) -> u64 {
+        match reg {
+            Register::Physical(id) => self.regs[*id as usize],
+            Register::Virtual(_) => panic!("unallocated register {}", reg),
+        }
+    }
+
+    fn set(&mut self, reg: &Register, val: u64) {
+        match reg {
+            Register::Physical(id) => self.regs[*id as usize] = val,
+            Register::Virtual(_) => panic!("unallocated register {}", reg),
+        }
+    }
+
+    fn operand(&self, op: &RegOrImm<'m>) -> u64 {
+        match op {
+            RegOrImm::Reg(reg) => self.get(&reg.borrow()),
+            RegOrImm::Imm(imm) => *imm,
+            RegOrImm::Shifted { reg, shift, amount } => {
+                let val = self.get(&reg.borrow());
+                match shift {
+                    Shift::Lsl => val << amount,
+                    Shift::Lsr => val >> amount,
+                    Shift::Asr => ((val as i64) >> amount) as u64,
+                }
+            }
+        }
+    }
+
+    fn binary(
+        &mut self,
+        dst: &RefCell<&'m Register>,
+        src1: &RefCell<&'m Register>,
+        src2: &RegOrImm<'m>,
+        op: fn(u64, u64) -> u64,
+    ) {
+        let val = op(self.get(&src1.borrow()), self.operand(src2));
+        self.set(&dst.borrow(), val);
+    }
+
+    fn address(&self, mem: &Memory<'m>) -> usize {
+        let addr = match mem {
+            Memory::Base { register } => self.get(&register.borrow()),
+            Memory::BaseOffset { register, offset } => {
+                self.get(&register.borrow()).wrapping_add(*offset as u64)
+            }
+            Memory::Stack { offset } => self.regs[31].wrapping_add(*offset as u64),
+        };
+        assert!(addr < STACK_SIZE as u64, "address {:#x} out of range", addr);
+        addr as usize
+    }
+
+    fn load(&self, addr: usize, size: u64) -> u64 {
+        let mut bytes = [0; 8];
+        bytes[..size as usize].copy_from_slice(&self.memory[addr..addr + size as usize]);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn store(&mut self, addr: usize, val: u64, size: u64) {
+        let bytes = val.to_le_bytes();
+        self.memory[addr..addr + size as usize].copy_from_slice(&bytes[..size as usize]);
+    }
+}
+
+/// Whether the immediates and offsets of `inst` fit its encoding.
+fn is_encodable(inst: &Inst<'_>) -> bool {
+    let imm = |op: &RegOrImm<'_>, fits: fn(u64) -> bool| match op {
+        RegOrImm::Reg(_) => true,
+        RegOrImm::Imm(imm) => fits(*imm),
+        RegOrImm::Shifted { amount, .. } => *amount < 64,
+    };
+    let shifted = |op: &RegOrImm<'_>| matches!(op, RegOrImm::Shifted { .. });
+    // Unsigned offsets scaled by the access size, or small unscaled ones.
+    let offset = |mem: &Memory<'_>, size: i64| match mem {
+        Memory::Base { .. } => true,
+        Memory::BaseOffset { offset, .. } | Memory::Stack { offset } => {
+            (offset % size == 0 && (0..4096 * size).contains(offset))
+                || (-256..256).contains(offset)
+        }
+    };
+    // Signed 7-bit offsets scaled by 8.
+    let pair_offset = |mem: &Memory<'_>| match mem {
+        Memory::Base { .. } => true,
+        Memory::BaseOffset { offset, .. } | Memory::Stack { offset } => {
+            offset % 8 == 0 && (-512..512).contains(offset)
+        }
+    };
+    let wide = |imm: u64, shift: u64| imm < 1 << 16 && shift.is_multiple_of(16) && shift < 64;
+    match inst {
+        Inst::Mov { src, .. } => !shifted(src) && imm(src, is_mov_imm),
+        Inst::Movz { imm, shift, .. }
+        | Inst::Movn { imm, shift, .. }
+        | Inst::Movk { imm, shift, .. } => wide(*imm, *shift),
+        Inst::Ldr { src: mem, size, .. } | Inst::Str { dst: mem, size, .. } => {
+            offset(mem, *size as i64)
+        }
+        Inst::Ldp { src: mem, .. } | Inst::Stp { dst: mem, .. } => pair_offset(mem),
+        Inst::Cmp { src2, .. } | Inst::Add { src2, .. } | Inst::Sub { src2, .. } => {
+            imm(src2, is_arith_imm)
+        }
+        Inst::Orr { src2, .. } | Inst::Eor { src2, .. } | Inst::And { src2, .. } => {
+            imm(src2, is_logical_imm)
+        }
+        Inst::Lsl { src2, .. } | Inst::Lsr { src2, .. } | Inst::Asr { src2, .. } => {
+            !shifted(src2) && imm(src2, |i| i < 64)
+        }
+        _ => true,
+    }
+}
+
+/// Compile the programs under `tests/` at every optimization level with
+/// `regalloc`, and check what they compute.
+pub fn check_programs(regalloc: RegAlloc) {
+    let fib = [1, 1, 2, 3, 5, 8, 13, 21, 34, 55];
+    let factorial = [1, 1, 2, 6, 24, 120, 720, 5040, 40320, 362880];
+    let nqueens = [1, 0, 0, 2, 10, 4, 40, 92];
+    let gcd = [
+        (0, 0, 0),
+        (1, 1, 1),
+        (2, 3, 1),
+        (10, 15, 5),
+        (12, 18, 6),
+        (48, 36, 12),
+    ];
+    let programs: [(&str, &str); 9] = [
+        ("fib", include_str!("../../tests/fib1.toy")),
+        ("fib", include_str!("../../tests/fib2.toy")),
+        ("factorial", include_str!("../../tests/factorial1.toy")),
+        ("factorial", include_str!("../../tests/factorial2.toy")),
+        ("gcd", include_str!("../../tests/gcd1.toy")),
+        ("gcd", include_str!("../../tests/gcd2.toy")),
+        ("sum", include_str!("../../tests/sum.toy")),
+        ("prime", include_str!("../../tests/prime.toy")),
+        ("nqueens", include_str!("../../tests/nqueens.toy")),
+    ];
+
+    for opt_level in 0..=2 {
+        for (name, src) in programs.iter() {
+            let module = compile_source(src, opt_level, regalloc);
+            let mut machine = Machine::new(module);
+            let mut call = |args: &[u64]| machine.call(name, args);
+            let context = format!("{} at -O{}", name, opt_level);
+            match *name {
+                "fib" => {
+                    (0..10).for_each(|n| assert_eq!(call(&[n]), fib[n as usize], "{}", context))
+                }
+                "factorial" => (0..10)
+                    .for_each(|n| assert_eq!(call(&[n]), factorial[n as usize], "{}", context)),
+                "gcd" => gcd
+                    .iter()
+                    .for_each(|&(a, b, r)| assert_eq!(call(&[a, b]), r, "{}", context)),
+                "sum" => {
+                    (0..10).for_each(|n| assert_eq!(call(&[n]), n * (n + 1) / 2, "{}", context))
+                }
+                "prime" => (2..100).for_each(|n| {
+                    let prime = (2..n).all(|d| n % d != 0);
+                    assert_eq!(call(&[n]), prime as u64, "{} {}", context, n)
+                }),
+                "nqueens" => (1..=8)
+                    .for_each(|n| assert_eq!(call(&[n]), nqueens[n as usize - 1], "{}", context)),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
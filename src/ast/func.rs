@@ -2,15 +2,32 @@ use std::rc::Rc;
 
 use super::{Stmt, TypeSpecifier};
 
+/// Hints attached to a function definition with `@name`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Attribute {
+    Inline,
+    NoInline,
+    Internal,
+}
+
 #[derive(PartialEq, Eq, Debug)]
 pub struct Func {
     decl: FuncDecl,
     body: Stmt,
+    attrs: Vec<Attribute>,
 }
 
 impl Func {
-    pub fn new(proto: FuncDecl, body: Stmt) -> Func {
-        Func { decl: proto, body }
+    pub fn new(proto: FuncDecl, body: Stmt, attrs: Vec<Attribute>) -> Func {
+        Func {
+            decl: proto,
+            body,
+            attrs,
+        }
+    }
+
+    pub fn attributes(&self) -> &Vec<Attribute> {
+        &self.attrs
     }
 
     pub fn prototype(&self) -> &FuncDecl {
@@ -42,6 +59,7 @@ impl FuncDecl {
         &self.name
     }
 
+    #[allow(dead_code)]
     pub fn ret_ty(&self) -> Rc<TypeSpecifier> {
         self.ret_ty.clone()
     }
@@ -69,6 +87,7 @@ impl Param {
         &self.name
     }
 
+    #[allow(dead_code)]
     pub fn ty(&self) -> Rc<TypeSpecifier> {
         self.ty.clone()
     }
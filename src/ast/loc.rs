@@ -0,0 +1,20 @@
+use std::fmt;
+
+/// Position of a token in the source, both 1-based.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Loc {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Loc {
+    pub fn new(line: usize, col: usize) -> Loc {
+        Loc { line, col }
+    }
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
@@ -1,6 +1,7 @@
 mod decl;
 mod expr;
 mod func;
+mod loc;
 mod stmt;
 mod ty;
 
@@ -8,6 +9,7 @@ pub type Module = Vec<GlobalDecl>;
 
 pub use self::decl::GlobalDecl;
 pub use self::expr::{BinaryOp, Expr, UnaryOp};
-pub use self::func::{Func, FuncDecl, Param};
+pub use self::func::{Attribute, Func, FuncDecl, Param};
+pub use self::loc::Loc;
 pub use self::stmt::Stmt;
 pub use self::ty::TypeSpecifier;
@@ -1,6 +1,6 @@
 use std::rc::Rc;
 
-use super::{Expr, TypeSpecifier};
+use super::{Expr, Loc, TypeSpecifier};
 
 #[derive(PartialEq, Eq, Debug)]
 pub enum Stmt {
@@ -11,20 +11,25 @@ pub enum Stmt {
         cond: Box<Expr>,
         then_stmt: Box<Stmt>,
         else_stmt: Option<Box<Stmt>>,
+        loc: Loc,
     },
     While {
         cond: Box<Expr>,
         body: Box<Stmt>,
+        loc: Loc,
     },
     VarDecl {
         name: String,
         ty: Rc<TypeSpecifier>,
         expr: Option<Box<Expr>>,
+        loc: Loc,
     },
     Return {
         expr: Option<Box<Expr>>,
+        loc: Loc,
     },
     Expr {
         expr: Box<Expr>,
+        loc: Loc,
     },
 }
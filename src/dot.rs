@@ -0,0 +1,59 @@
+//! Minimal writer for GraphViz `dot` files, used to draw control-flow graphs.
+
+use std::io::{Result, Write};
+
+/// A directed graph whose nodes are boxes holding lines of text.
+pub struct Graph {
+    name: String,
+    nodes: Vec<(String, Vec<String>)>,
+    edges: Vec<(String, String, Option<&'static str>)>,
+}
+
+impl Graph {
+    pub fn new(name: &str) -> Graph {
+        Graph {
+            name: name.to_string(),
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    /// Add a node named `id`, showing `id` followed by `lines`.
+    pub fn add_node(&mut self, id: &str, lines: Vec<String>) {
+        self.nodes.push((id.to_string(), lines));
+    }
+
+    pub fn add_edge(&mut self, from: &str, to: &str, label: Option<&'static str>) {
+        self.edges.push((from.to_string(), to.to_string(), label));
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+        writeln!(out, "digraph \"{}\" {{", escape(&self.name))?;
+        writeln!(out, "\tnode [shape=box, fontname=\"monospace\"];")?;
+        for (id, lines) in self.nodes.iter() {
+            // `\l` ends a left-aligned line.
+            let mut label = format!("{}:\\l", escape(id));
+            for line in lines {
+                label.push_str("  ");
+                label.push_str(&escape(line));
+                label.push_str("\\l");
+            }
+            writeln!(out, "\t\"{}\" [label=\"{}\"];", escape(id), label)?;
+        }
+        for (from, to, label) in self.edges.iter() {
+            write!(out, "\t\"{}\" -> \"{}\"", escape(from), escape(to))?;
+            if let Some(label) = label {
+                write!(out, " [label=\"{}\"]", label)?;
+            }
+            writeln!(out, ";")?;
+        }
+        writeln!(out, "}}")
+    }
+}
+
+/// Quote `s` for use inside a double-quoted `dot` string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\t', " ")
+}
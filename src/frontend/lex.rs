@@ -1,12 +1,15 @@
 use super::char::Decode;
 use super::token::Token;
+use crate::ast::Loc;
 
 #[derive(Debug)]
 pub struct Lexer<D: Decode<R>, R: std::io::Read> {
     input: D,
     last: char,
-    row: isize,
-    col: isize,
+    row: usize,
+    col: usize,
+    // Where the last token returned by `gettok` starts
+    loc: Loc,
     _r: std::marker::PhantomData<R>,
 }
 
@@ -17,10 +20,15 @@ impl<D: Decode<R>, R: std::io::Read> Lexer<D, R> {
             last: ' ',
             row: 1,
             col: 0,
+            loc: Loc::new(1, 1),
             _r: std::marker::PhantomData,
         }
     }
 
+    pub fn loc(&self) -> Loc {
+        self.loc
+    }
+
     pub fn gettok(&mut self) -> Token {
         while self.last.is_whitespace() {
             self.last = match self.getchar() {
@@ -28,6 +36,7 @@ impl<D: Decode<R>, R: std::io::Read> Lexer<D, R> {
                 None => return Token::EOF,
             }
         }
+        self.loc = Loc::new(self.row, self.col);
 
         if self.last.is_alphabetic() {
             let mut word = String::new();
@@ -148,6 +157,7 @@ impl<D: Decode<R>, R: std::io::Read> Lexer<D, R> {
             ':' => Token::Colon,
             ';' => Token::SemiColon,
             ',' => Token::Comma,
+            '@' => Token::At,
             _ => panic!(""),
         };
 
@@ -247,9 +257,33 @@ mod tests {
             Token::RBrace,
         ];
 
-        for (i, answer) in tokens.iter().enumerate() {
+        for answer in tokens.iter() {
+            let token = lexer.gettok();
+            assert_eq!(&{ token }, answer);
+        }
+    }
+
+    #[test]
+    fn locations() {
+        let src = String::from("func f() {\n  # comment\n\treturn 42;\n}\n");
+
+        let mut lexer = Lexer::new(Utf8Decoder::new(src.as_bytes()));
+        let tokens = [
+            (Token::Func, Loc::new(1, 1)),
+            (Token::Identifier(String::from("f")), Loc::new(1, 6)),
+            (Token::LParen, Loc::new(1, 7)),
+            (Token::RParen, Loc::new(1, 8)),
+            (Token::LBrace, Loc::new(1, 10)),
+            (Token::Return, Loc::new(3, 2)),
+            (Token::Integer(42), Loc::new(3, 9)),
+            (Token::SemiColon, Loc::new(3, 11)),
+            (Token::RBrace, Loc::new(4, 1)),
+        ];
+
+        for (answer, loc) in tokens.iter() {
             let token = lexer.gettok();
             assert_eq!(&{ token }, answer);
+            assert_eq!(lexer.loc(), *loc);
         }
     }
 }
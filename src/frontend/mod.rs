@@ -4,6 +4,5 @@ mod parse;
 mod token;
 mod utf8;
 
-pub use char::Decode;
 pub use parse::Parser;
 pub use utf8::Utf8Decoder;
@@ -9,6 +9,7 @@ use crate::ast;
 pub struct Parser<D: Decode<R>, R: std::io::Read> {
     lexer: Lexer<D, R>,
     curr: Token,
+    loc: ast::Loc,
 }
 
 impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
@@ -16,10 +17,11 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
         Parser {
             lexer: Lexer::<D, R>::new(D::new(input)),
             curr: Token::EOF,
+            loc: ast::Loc::new(1, 1),
         }
     }
 
-    // root : function
+    // root : attributes function
     //      | extern
     pub fn parse(&mut self, unit: &mut ast::Module) {
         self.get_next_token();
@@ -27,15 +29,22 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
             match self.curr {
                 Token::EOF => return,
                 Token::SemiColon => self.get_next_token(),
-                Token::Func => unit.push(ast::GlobalDecl::Function(self.parse_function())),
+                Token::Func | Token::At => {
+                    unit.push(ast::GlobalDecl::Function(self.parse_function()))
+                }
                 Token::Extern => unit.push(ast::GlobalDecl::FuncDecl(self.parse_extern())),
                 _ => panic!("unexpected token"),
             }
         }
     }
 
-    // function : 'func' func_decl body
+    // function : attributes 'func' func_decl body
     fn parse_function(&mut self) -> ast::Func {
+        let attrs = self.parse_attributes();
+
+        if self.curr != Token::Func {
+            panic!("expected 'func'");
+        }
         // eat 'func'
         self.get_next_token();
 
@@ -46,7 +55,27 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
         }
         let body = self.parse_block_stmt();
 
-        ast::Func::new(proto, body)
+        ast::Func::new(proto, body, attrs)
+    }
+
+    // attributes : ( '@' identifier )*
+    fn parse_attributes(&mut self) -> Vec<ast::Attribute> {
+        let mut attrs = vec![];
+        while self.curr == Token::At {
+            self.get_next_token();
+            let attr = match self.curr {
+                Token::Identifier(ref name) => match name.as_str() {
+                    "inline" => ast::Attribute::Inline,
+                    "noinline" => ast::Attribute::NoInline,
+                    "internal" => ast::Attribute::Internal,
+                    _ => panic!("unknown attribute '@{}'", name),
+                },
+                _ => panic!("expected attribute name"),
+            };
+            attrs.push(attr);
+            self.get_next_token();
+        }
+        attrs
     }
 
     // extern : 'extern' func_decl ';'
@@ -155,6 +184,7 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
     // if : 'if' expr block
     //    | 'if' expr block 'else' block
     fn parse_if_stmt(&mut self) -> ast::Stmt {
+        let loc = self.loc;
         self.get_next_token();
 
         let cond = Box::new(self.parse_expr());
@@ -170,6 +200,7 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
                 cond,
                 then_stmt,
                 else_stmt,
+                loc,
             };
         }
 
@@ -183,11 +214,13 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
             cond,
             then_stmt,
             else_stmt,
+            loc,
         }
     }
 
     // while : 'while' expr block
     fn parse_while_stmt(&mut self) -> ast::Stmt {
+        let loc = self.loc;
         self.get_next_token();
 
         let cond = Box::new(self.parse_expr());
@@ -197,11 +230,12 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
         }
         let body = Box::new(self.parse_block_stmt());
 
-        ast::Stmt::While { cond, body }
+        ast::Stmt::While { cond, body, loc }
     }
 
     // var_decl : 'var' identifier ':' type ( '=' expr )? ';'
     fn parse_var_decl_stmt(&mut self) -> ast::Stmt {
+        let loc = self.loc;
         self.get_next_token(); // Eat 'var'
 
         let var_name = if let Token::Identifier(ref s) = self.curr {
@@ -234,11 +268,13 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
             name: var_name,
             ty: Rc::new(ty),
             expr,
+            loc,
         }
     }
 
     // return : 'return' expr? ';'
     fn parse_return_stmt(&mut self) -> ast::Stmt {
+        let loc = self.loc;
         self.get_next_token();
 
         let expr = if self.curr != Token::SemiColon {
@@ -253,7 +289,7 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
 
         self.get_next_token();
 
-        ast::Stmt::Return { expr }
+        ast::Stmt::Return { expr, loc }
     }
 
     // type : '*' type
@@ -280,6 +316,7 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
 
     // expr_stmt : expr ';'
     fn parse_expr_stmt(&mut self) -> ast::Stmt {
+        let loc = self.loc;
         let expr = Box::new(self.parse_expr());
 
         if self.curr != Token::SemiColon {
@@ -288,7 +325,7 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
 
         self.get_next_token();
 
-        ast::Stmt::Expr { expr }
+        ast::Stmt::Expr { expr, loc }
     }
 
     // expr : assignment
@@ -590,6 +627,7 @@ impl<D: Decode<R>, R: std::io::Read> Parser<D, R> {
 
     fn get_next_token(&mut self) {
         self.curr = self.lexer.gettok();
+        self.loc = self.lexer.loc();
     }
 }
 
@@ -692,22 +730,28 @@ mod tests {
                                             }],
                                         }),
                                     })),
+                                    loc: Loc::new(4, 13),
                                 }],
                             }),
                             else_stmt: Some(Box::new(ast::Stmt::Block {
                                 stmts: vec![ast::Stmt::Return {
                                     expr: Some(Box::new(ast::Expr::Integer { value: 1 })),
+                                    loc: Loc::new(6, 13),
                                 }],
                             })),
+                            loc: Loc::new(3, 9),
                         }],
                     }),
                     else_stmt: Some(Box::new(ast::Stmt::Block {
                         stmts: vec![ast::Stmt::Return {
                             expr: Some(Box::new(ast::Expr::Integer { value: 1 })),
+                            loc: Loc::new(9, 9),
                         }],
                     })),
+                    loc: Loc::new(2, 5),
                 }],
             },
+            vec![],
         );
         let decl = ast::GlobalDecl::Function(func);
         expected.push(decl);
@@ -751,6 +795,7 @@ mod tests {
                     then_stmt: Box::new(ast::Stmt::Block {
                         stmts: vec![ast::Stmt::Return {
                             expr: Some(Box::new(ast::Expr::Integer { value: 1 })),
+                            loc: Loc::new(3, 17),
                         }],
                     }),
                     else_stmt: Some(Box::new(ast::Stmt::Block {
@@ -778,10 +823,13 @@ mod tests {
                                     }],
                                 }),
                             })),
+                            loc: Loc::new(5, 17),
                         }],
                     })),
+                    loc: Loc::new(2, 13),
                 }],
             },
+            vec![],
         );
         let decl = ast::GlobalDecl::Function(func);
         expected.push(decl);
@@ -1049,7 +1097,24 @@ extern foo(a: Int64, b: Int64): Int64;"
                 name: String::from("a"),
                 ty: Rc::new(TypeSpecifier::Pointer(Rc::new(TypeSpecifier::Int64))),
                 expr: None,
+                loc: Loc::new(1, 1),
             })
         );
     }
+
+    #[test]
+    fn attributes() {
+        let src = String::from("@inline @noinline func f() { }");
+        let mut parser = Parser::<Utf8Decoder<_>, _>::new(src.as_bytes());
+        let mut unit = ast::Module::new();
+        parser.parse(&mut unit);
+
+        let ast::GlobalDecl::Function(func) = &unit[0] else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            func.attributes(),
+            &vec![ast::Attribute::Inline, ast::Attribute::NoInline]
+        );
+    }
 }
@@ -1,4 +1,5 @@
 #[derive(PartialEq, Eq, Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Token {
     Func,
     Extern,
@@ -50,6 +51,7 @@ pub enum Token {
     Colon,
     SemiColon,
     Comma,
+    At,
 
     EOF,
 }
@@ -0,0 +1,135 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{Func, InstKind, Module};
+
+/// Which functions call which, and the strongly connected components of
+/// that graph, i.e. the sets of mutually recursive functions.
+pub struct CallGraph<'m> {
+    callees: HashMap<&'m Func<'m>, Vec<&'m Func<'m>>>,
+    sccs: Vec<Vec<&'m Func<'m>>>,
+    scc_of: HashMap<&'m Func<'m>, usize>,
+}
+
+impl<'m> CallGraph<'m> {
+    #[allow(clippy::mutable_key_type)]
+    pub fn new(module: &Module<'m>) -> CallGraph<'m> {
+        let funcs = module.functions().clone();
+        let mut callees: HashMap<&'m Func<'m>, Vec<&'m Func<'m>>> = HashMap::new();
+        for func in funcs.iter() {
+            let mut list = vec![];
+            for inst in func.instructions() {
+                if let InstKind::Call(callee, _) = &*inst.kind() {
+                    if !list.contains(callee) {
+                        list.push(*callee);
+                    }
+                }
+            }
+            callees.insert(*func, list);
+        }
+
+        let mut graph = CallGraph {
+            callees,
+            sccs: vec![],
+            scc_of: HashMap::new(),
+        };
+        graph.compute_sccs(&funcs);
+        graph
+    }
+
+    /// Functions called by `func`, each listed once.
+    pub fn callees(&self, func: &'m Func<'m>) -> &[&'m Func<'m>] {
+        self.callees.get(&func).map_or(&[], |c| c.as_slice())
+    }
+
+    /// Strongly connected components, callees before their callers.
+    pub fn sccs(&self) -> &[Vec<&'m Func<'m>>] {
+        &self.sccs
+    }
+
+    pub fn in_same_scc(&self, a: &'m Func<'m>, b: &'m Func<'m>) -> bool {
+        self.scc_of.get(&a) == self.scc_of.get(&b)
+    }
+
+    /// Whether `func` can end up calling itself, directly or through other
+    /// functions.
+    pub fn is_recursive(&self, func: &'m Func<'m>) -> bool {
+        self.callees(func).contains(&func)
+            || self
+                .scc_of
+                .get(&func)
+                .is_some_and(|scc| self.sccs[*scc].len() > 1)
+    }
+
+    /// Functions reachable from `roots` through calls, `roots` included.
+    #[allow(clippy::mutable_key_type)]
+    pub fn reachable_from(
+        &self,
+        roots: impl IntoIterator<Item = &'m Func<'m>>,
+    ) -> HashSet<&'m Func<'m>> {
+        let mut reached = HashSet::new();
+        let mut worklist: Vec<_> = roots.into_iter().collect();
+        while let Some(func) = worklist.pop() {
+            if reached.insert(func) {
+                worklist.extend(self.callees(func));
+            }
+        }
+        reached
+    }
+
+    /// Tarjan's algorithm, which finds the components in reverse topological
+    /// order of the condensed graph.
+    fn compute_sccs(&mut self, funcs: &[&'m Func<'m>]) {
+        struct State<'m> {
+            index: HashMap<&'m Func<'m>, usize>,
+            lowlink: HashMap<&'m Func<'m>, usize>,
+            stack: Vec<&'m Func<'m>>,
+            on_stack: HashMap<&'m Func<'m>, bool>,
+        }
+
+        fn visit<'m>(graph: &mut CallGraph<'m>, state: &mut State<'m>, func: &'m Func<'m>) {
+            let index = state.index.len();
+            state.index.insert(func, index);
+            state.lowlink.insert(func, index);
+            state.stack.push(func);
+            state.on_stack.insert(func, true);
+
+            for callee in graph.callees(func).to_vec() {
+                if !state.index.contains_key(&callee) {
+                    visit(graph, state, callee);
+                    let low = state.lowlink[&func].min(state.lowlink[&callee]);
+                    state.lowlink.insert(func, low);
+                } else if state.on_stack.get(&callee) == Some(&true) {
+                    let low = state.lowlink[&func].min(state.index[&callee]);
+                    state.lowlink.insert(func, low);
+                }
+            }
+
+            if state.lowlink[&func] == state.index[&func] {
+                let mut scc = vec![];
+                loop {
+                    let member = state.stack.pop().unwrap();
+                    state.on_stack.insert(member, false);
+                    graph.scc_of.insert(member, graph.sccs.len());
+                    scc.push(member);
+                    if member == func {
+                        break;
+                    }
+                }
+                scc.reverse();
+                graph.sccs.push(scc);
+            }
+        }
+
+        let mut state = State {
+            index: HashMap::new(),
+            lowlink: HashMap::new(),
+            stack: vec![],
+            on_stack: HashMap::new(),
+        };
+        for func in funcs {
+            if !state.index.contains_key(func) {
+                visit(self, &mut state, func);
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::collections::HashSet;
+
+use crate::ir::{BasicBlock, Func};
+
+/// Blocks reachable from the entry block, in reverse post-order: every block
+/// comes before its successors, except along back edges.
+#[allow(clippy::mutable_key_type)]
+pub fn reverse_post_order<'m>(func: &Func<'m>) -> Vec<&'m BasicBlock<'m>> {
+    let entry = func.entry();
+    let mut visited = HashSet::from([entry]);
+    let mut order = Vec::new();
+    // Each stack entry is a block and the index of the next successor to
+    // visit, so that blocks are emitted only after all their successors.
+    let mut stack = vec![(entry, 0)];
+    while let Some((block, next)) = stack.pop() {
+        let succs = block.successors();
+        if let Some(succ) = succs.get(next) {
+            stack.push((block, next + 1));
+            if visited.insert(*succ) {
+                stack.push((*succ, 0));
+            }
+        } else {
+            order.push(block);
+        }
+    }
+    order.reverse();
+    order
+}
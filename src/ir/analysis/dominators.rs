@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use super::cfg::reverse_post_order;
+use crate::ir::{BasicBlock, Func};
+
+/// Dominator tree of the blocks reachable from the entry block, computed with
+/// the iterative algorithm of Cooper, Harvey and Kennedy.
+pub struct DominatorTree<'m> {
+    entry: &'m BasicBlock<'m>,
+    rpo: Vec<&'m BasicBlock<'m>>,
+    idom: HashMap<&'m BasicBlock<'m>, &'m BasicBlock<'m>>,
+    children: HashMap<&'m BasicBlock<'m>, Vec<&'m BasicBlock<'m>>>,
+}
+
+impl<'m> DominatorTree<'m> {
+    #[allow(clippy::mutable_key_type)]
+    pub fn new(func: &Func<'m>) -> DominatorTree<'m> {
+        let rpo = reverse_post_order(func);
+        let index: HashMap<&'m BasicBlock<'m>, usize> =
+            rpo.iter().enumerate().map(|(i, b)| (*b, i)).collect();
+
+        let entry = rpo[0];
+        let mut idom: Vec<Option<usize>> = vec![None; rpo.len()];
+        idom[0] = Some(0);
+
+        let intersect = |idom: &Vec<Option<usize>>, mut a: usize, mut b: usize| {
+            while a != b {
+                while a > b {
+                    a = idom[a].unwrap();
+                }
+                while b > a {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, block) in rpo.iter().enumerate().skip(1) {
+                let mut new_idom = None;
+                for pred in block.predecessors() {
+                    let Some(&p) = index.get(&pred) else {
+                        // Unreachable predecessor
+                        continue;
+                    };
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(cur) => intersect(&idom, p, cur),
+                    });
+                }
+                if new_idom != idom[i] {
+                    idom[i] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut tree = DominatorTree {
+            entry,
+            rpo: rpo.clone(),
+            idom: HashMap::new(),
+            children: HashMap::new(),
+        };
+        for (i, block) in rpo.iter().enumerate().skip(1) {
+            let parent = rpo[idom[i].unwrap()];
+            tree.idom.insert(*block, parent);
+            tree.children.entry(parent).or_default().push(*block);
+        }
+        tree
+    }
+
+    pub fn entry(&self) -> &'m BasicBlock<'m> {
+        self.entry
+    }
+
+    pub fn is_reachable(&self, block: &'m BasicBlock<'m>) -> bool {
+        block == self.entry || self.idom.contains_key(&block)
+    }
+
+    /// Immediate dominator; `None` for the entry block and unreachable
+    /// blocks.
+    pub fn idom(&self, block: &'m BasicBlock<'m>) -> Option<&'m BasicBlock<'m>> {
+        self.idom.get(&block).copied()
+    }
+
+    /// Whether every path from the entry to `b` goes through `a`. A block
+    /// dominates itself.
+    pub fn dominates(&self, a: &'m BasicBlock<'m>, b: &'m BasicBlock<'m>) -> bool {
+        let mut runner = Some(b);
+        while let Some(r) = runner {
+            if r == a {
+                return true;
+            }
+            runner = self.idom(r);
+        }
+        false
+    }
+
+    /// Reachable blocks in reverse post order.
+    pub fn reverse_post_order(&self) -> &[&'m BasicBlock<'m>] {
+        &self.rpo
+    }
+
+    pub fn children(&self, block: &'m BasicBlock<'m>) -> &[&'m BasicBlock<'m>] {
+        self.children.get(&block).map_or(&[], |c| c.as_slice())
+    }
+
+    /// Dominance frontier of every reachable block: the blocks where its
+    /// dominance ends.
+    #[allow(clippy::mutable_key_type)]
+    pub fn frontiers(&self) -> HashMap<&'m BasicBlock<'m>, Vec<&'m BasicBlock<'m>>> {
+        let mut frontiers: HashMap<&'m BasicBlock<'m>, Vec<&'m BasicBlock<'m>>> = HashMap::new();
+        for block in self.rpo.iter() {
+            let preds: Vec<_> = block
+                .predecessors()
+                .into_iter()
+                .filter(|p| self.is_reachable(p))
+                .collect();
+            if preds.len() < 2 {
+                continue;
+            }
+            let idom = self.idom(block);
+            for pred in preds {
+                let mut runner = Some(pred);
+                while let Some(r) = runner {
+                    if Some(r) == idom {
+                        break;
+                    }
+                    let frontier = frontiers.entry(r).or_default();
+                    if !frontier.contains(block) {
+                        frontier.push(*block);
+                    }
+                    runner = self.idom(r);
+                }
+            }
+        }
+        frontiers
+    }
+}
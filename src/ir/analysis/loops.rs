@@ -0,0 +1,139 @@
+use std::collections::{HashMap, HashSet};
+
+use super::DominatorTree;
+use crate::ir::BasicBlock;
+
+/// A natural loop: a header dominating every block of the loop, entered only
+/// through the header and returned to by one or more back edges.
+pub struct Loop<'m> {
+    header: &'m BasicBlock<'m>,
+    /// The blocks of the loop in reverse post order, so the header first.
+    blocks: Vec<&'m BasicBlock<'m>>,
+    members: HashSet<&'m BasicBlock<'m>>,
+}
+
+impl<'m> Loop<'m> {
+    pub fn header(&self) -> &'m BasicBlock<'m> {
+        self.header
+    }
+
+    pub fn blocks(&self) -> &[&'m BasicBlock<'m>] {
+        &self.blocks
+    }
+
+    pub fn contains(&self, block: &'m BasicBlock<'m>) -> bool {
+        self.members.contains(&block)
+    }
+
+    /// Predecessors of the header from outside the loop.
+    pub fn entering_blocks(&self) -> Vec<&'m BasicBlock<'m>> {
+        self.header
+            .predecessors()
+            .into_iter()
+            .filter(|pred| !self.contains(pred))
+            .collect()
+    }
+
+    /// The only block entering the loop, if it has no other successor, so
+    /// that code placed there runs exactly once before the loop.
+    pub fn preheader(&self) -> Option<&'m BasicBlock<'m>> {
+        match self.entering_blocks().as_slice() {
+            [pred] if pred.successors().len() == 1 => Some(*pred),
+            _ => None,
+        }
+    }
+}
+
+/// The natural loops of a function. Back edges to the same header are
+/// combined into one loop.
+pub struct LoopInfo<'m> {
+    /// Inner loops come before the loops containing them.
+    loops: Vec<Loop<'m>>,
+}
+
+impl<'m> LoopInfo<'m> {
+    #[allow(clippy::mutable_key_type)]
+    pub fn new(dom: &DominatorTree<'m>) -> LoopInfo<'m> {
+        let rpo = dom.reverse_post_order();
+        let index: HashMap<&'m BasicBlock<'m>, usize> =
+            rpo.iter().enumerate().map(|(i, b)| (*b, i)).collect();
+
+        let mut loops = vec![];
+        for header in rpo.iter() {
+            let latches: Vec<_> = header
+                .predecessors()
+                .into_iter()
+                .filter(|pred| dom.is_reachable(pred) && dom.dominates(header, pred))
+                .collect();
+            if latches.is_empty() {
+                continue;
+            }
+
+            // Everything that reaches a latch without going through the
+            // header belongs to the loop.
+            let mut members = HashSet::from([*header]);
+            let mut worklist = latches;
+            while let Some(block) = worklist.pop() {
+                if !members.insert(block) {
+                    continue;
+                }
+                worklist.extend(
+                    block
+                        .predecessors()
+                        .into_iter()
+                        .filter(|pred| dom.is_reachable(pred)),
+                );
+            }
+            let mut blocks: Vec<_> = members.iter().copied().collect();
+            blocks.sort_by_key(|b| index[b]);
+            loops.push(Loop {
+                header,
+                blocks,
+                members,
+            });
+        }
+        // A loop nested in another has fewer blocks.
+        loops.sort_by_key(|l| l.blocks.len());
+        LoopInfo { loops }
+    }
+
+    pub fn loops(&self) -> &[Loop<'m>] {
+        &self.loops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::analysis::AnalysisManager;
+    use crate::ir::lower_source;
+
+    #[test]
+    fn nested_loops() {
+        let module = lower_source(
+            r"func f(n: Int64) : Int64 {
+    var i: Int64 = 0;
+    var s: Int64 = 0;
+    while i < n {
+        var j: Int64 = 0;
+        while j < i {
+            s = s + j;
+            j = j + 1;
+        }
+        i = i + 1;
+    }
+    return s;
+}
+",
+        );
+        let func = module.functions()[0];
+        let loops = AnalysisManager::new().loops(func);
+        let [inner, outer] = loops.loops() else {
+            panic!("expected two loops");
+        };
+        assert!(inner.blocks().iter().all(|b| outer.contains(b)));
+        assert!(!inner.contains(outer.header()));
+        assert!(inner.blocks()[0] == inner.header());
+        assert!(inner.preheader().is_some_and(|p| outer.contains(p)));
+        assert!(outer.preheader().is_some());
+    }
+}
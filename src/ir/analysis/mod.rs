@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::Func;
+
+mod callgraph;
+mod cfg;
+mod dominators;
+mod loops;
+
+pub use callgraph::CallGraph;
+pub use cfg::reverse_post_order;
+pub use dominators::DominatorTree;
+pub use loops::{Loop, LoopInfo};
+
+/// Caches analysis results per function until a pass reports that it changed
+/// the function.
+pub struct AnalysisManager<'m> {
+    dominators: HashMap<&'m Func<'m>, Rc<DominatorTree<'m>>>,
+    loops: HashMap<&'m Func<'m>, Rc<LoopInfo<'m>>>,
+}
+
+impl<'m> AnalysisManager<'m> {
+    pub fn new() -> AnalysisManager<'m> {
+        AnalysisManager {
+            dominators: HashMap::new(),
+            loops: HashMap::new(),
+        }
+    }
+
+    pub fn dominators(&mut self, func: &'m Func<'m>) -> Rc<DominatorTree<'m>> {
+        self.dominators
+            .entry(func)
+            .or_insert_with(|| Rc::new(DominatorTree::new(func)))
+            .clone()
+    }
+
+    pub fn loops(&mut self, func: &'m Func<'m>) -> Rc<LoopInfo<'m>> {
+        if let Some(loops) = self.loops.get(&func) {
+            return loops.clone();
+        }
+        let loops = Rc::new(LoopInfo::new(&self.dominators(func)));
+        self.loops.insert(func, loops.clone());
+        loops
+    }
+
+    pub fn invalidate(&mut self, func: &'m Func<'m>) {
+        self.dominators.remove(&func);
+        self.loops.remove(&func);
+    }
+
+    pub fn invalidate_all(&mut self) {
+        self.dominators.clear();
+        self.loops.clear();
+    }
+}
+
+impl Default for AnalysisManager<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,4 +1,4 @@
-use super::Inst;
+use super::{Func, Inst};
 use std::cell::{Ref, RefCell};
 use std::fmt;
 use std::hash::{Hash, Hasher};
@@ -6,6 +6,7 @@ use std::hash::{Hash, Hasher};
 pub struct BasicBlock<'m> {
     name: String,
     instructions: RefCell<Vec<&'m Inst<'m>>>,
+    parent: RefCell<Option<&'m Func<'m>>>,
 }
 
 impl<'m> BasicBlock<'m> {
@@ -13,6 +14,7 @@ impl<'m> BasicBlock<'m> {
         BasicBlock {
             name: format!("bb_{}", id),
             instructions: RefCell::new(Vec::new()),
+            parent: RefCell::new(None),
         }
     }
 
@@ -20,13 +22,113 @@ impl<'m> BasicBlock<'m> {
         &self.name
     }
 
-    pub fn add_instruction(&self, inst: &'m Inst<'m>) {
-        self.instructions.borrow_mut().push(inst);
+    pub fn parent(&self) -> Option<&'m Func<'m>> {
+        *self.parent.borrow()
     }
 
-    pub fn instructions(&self) -> Ref<Vec<&'m Inst<'m>>> {
+    pub(super) fn set_parent(&self, parent: Option<&'m Func<'m>>) {
+        *self.parent.borrow_mut() = parent;
+    }
+
+    pub fn add_instruction(&'m self, inst: &'m Inst<'m>) {
+        let len = self.instructions.borrow().len();
+        self.insert_instruction(len, inst);
+    }
+
+    pub fn insert_instruction(&'m self, index: usize, inst: &'m Inst<'m>) {
+        assert!(
+            inst.parent().is_none(),
+            "Instruction is already in a basic block"
+        );
+        inst.set_parent(Some(self));
+        self.instructions.borrow_mut().insert(index, inst);
+    }
+
+    pub(super) fn remove_instruction(&self, inst: &'m Inst<'m>) {
+        let index = self
+            .index_of(inst)
+            .expect("instruction is not in this block");
+        self.instructions.borrow_mut().remove(index);
+        inst.set_parent(None);
+    }
+
+    pub fn instructions(&self) -> Ref<'_, Vec<&'m Inst<'m>>> {
         self.instructions.borrow()
     }
+
+    pub fn index_of(&self, inst: &Inst<'m>) -> Option<usize> {
+        self.instructions
+            .borrow()
+            .iter()
+            .position(|i| std::ptr::eq(*i, inst))
+    }
+
+    /// The phis at the top of this block.
+    pub fn phis(&self) -> Vec<&'m Inst<'m>> {
+        self.instructions
+            .borrow()
+            .iter()
+            .take_while(|inst| inst.is_phi())
+            .copied()
+            .collect()
+    }
+
+    /// Index of the first instruction that is not a phi.
+    pub fn first_non_phi(&self) -> usize {
+        self.instructions
+            .borrow()
+            .iter()
+            .take_while(|inst| inst.is_phi())
+            .count()
+    }
+
+    /// Forget the incoming values from `pred` after the edge from `pred` to
+    /// this block has been removed.
+    pub fn remove_predecessor(&self, pred: &'m BasicBlock<'m>) {
+        for phi in self.phis() {
+            phi.remove_incoming(pred);
+        }
+    }
+
+    pub fn terminator(&self) -> Option<&'m Inst<'m>> {
+        self.instructions
+            .borrow()
+            .last()
+            .filter(|inst| inst.is_terminator())
+            .copied()
+    }
+
+    pub fn successors(&self) -> Vec<&'m BasicBlock<'m>> {
+        let mut succs = self
+            .terminator()
+            .map(|term| term.successors())
+            .unwrap_or_default();
+        succs.dedup();
+        succs
+    }
+
+    /// Blocks of the parent function branching to this block, each listed
+    /// once, in layout order.
+    pub fn predecessors(&self) -> Vec<&'m BasicBlock<'m>> {
+        let func = self.parent().expect("basic block is not in a function");
+        func.blocks()
+            .iter()
+            .filter(|block| {
+                block
+                    .successors()
+                    .iter()
+                    .any(|succ| std::ptr::eq(*succ, self))
+            })
+            .copied()
+            .collect()
+    }
+
+    pub fn single_predecessor(&self) -> Option<&'m BasicBlock<'m>> {
+        match self.predecessors().as_slice() {
+            [pred] => Some(*pred),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for BasicBlock<'_> {
@@ -34,6 +136,9 @@ impl fmt::Display for BasicBlock<'_> {
         write!(f, "{}:", self.name)?;
         for inst in self.instructions.borrow().iter() {
             write!(f, "\n\t{}", inst)?;
+            if let Some(loc) = inst.loc() {
+                write!(f, "\t; {}", loc)?;
+            }
         }
         Ok(())
     }
@@ -4,8 +4,6 @@ use crate::ast;
 use crate::ir;
 use scope::NestedScope;
 
-use super::func;
-
 pub struct Codegen<'m> {
     unit: &'m ir::Module<'m>,
     ctx: &'m ir::Context<'m>,
@@ -21,38 +19,57 @@ impl<'m> Codegen<'m> {
         }
     }
 
-    fn make_function(&'m self, proto: &ast::FuncDecl) -> &'m mut ir::Func<'m> {
-        let mut params = Vec::<&'m ir::Param>::new();
+    fn make_function(&'m self, proto: &ast::FuncDecl) -> &'m ir::Func<'m> {
+        let mut params = Vec::<&'m ir::Param<'m>>::new();
 
-        for param in proto.params() {
-            params.push(self.ctx.new_parameter(String::from(param.name())));
+        for (i, param) in proto.params().iter().enumerate() {
+            if proto.params()[..i].iter().any(|p| p.name() == param.name()) {
+                panic!(
+                    "Parameter '{}' of '{}' is declared twice",
+                    param.name(),
+                    proto.name()
+                );
+            }
+            params.push(
+                self.ctx
+                    .new_parameter(String::from(param.name()), lower_type(&param.ty())),
+            );
         }
 
-        self.ctx.new_function(String::from(proto.name()), params)
+        self.ctx.new_function(
+            String::from(proto.name()),
+            params,
+            lower_type(&proto.ret_ty()),
+        )
     }
 
     pub fn visit_unit(&'m self, unit: &ast::Module) {
         for decl in unit {
             match decl {
-                ast::GlobalDecl::FuncDecl(proto) => {
+                ast::GlobalDecl::FuncDecl(_proto) => {
                     // TODO: Distinguish function and extern function; function
                     // requires an entry block, extern function does not.
                     unimplemented!();
                 }
                 ast::GlobalDecl::Function(func) => {
-                    self.ctx.reset_id();
-
                     let func_ir = match self.unit.get_function(func.prototype().name()) {
-                        Some(func) => {
+                        Some(_) => {
                             // TODO As we don't handle function declaration yet,
                             // and function cannot be defined twice, if we reach
                             // here, it means the function is already defined.
                             panic!("Function already exists");
                         }
                         None => {
-                            let func = &*self.make_function(func.prototype());
-                            self.unit.add_function(func);
-                            func
+                            let func_ir = self.make_function(func.prototype());
+                            for attr in func.attributes() {
+                                func_ir.add_attribute(match attr {
+                                    ast::Attribute::Inline => ir::Attribute::Inline,
+                                    ast::Attribute::NoInline => ir::Attribute::NoInline,
+                                    ast::Attribute::Internal => ir::Attribute::Internal,
+                                });
+                            }
+                            self.unit.add_function(func_ir);
+                            func_ir
                         }
                     };
 
@@ -67,8 +84,9 @@ impl<'m> Codegen<'m> {
         let param_values = func_ir.params();
 
         let _guard = self.scope.new_scope();
-        for (param_ast, param_ir) in params.iter().zip(param_values.iter()) {
-            let alloca = self.ctx.alloca();
+        for param_ast in params.iter() {
+            let alloca = self.ctx.alloca(lower_type(&param_ast.ty()));
+            func_ir.set_value_name(alloca, &format!("{}.addr", param_ast.name()));
             self.scope.update(param_ast.name(), alloca);
             func_ir.add_instruction(alloca);
         }
@@ -80,6 +98,19 @@ impl<'m> Codegen<'m> {
         }
 
         self.visit_stmt(func_ast.body(), func_ir);
+
+        // Falling off the end of the body returns zero, or nothing from a
+        // void function. This also terminates the exit block of an if-else
+        // whose arms both return.
+        func_ir.set_debug_loc(None);
+        let last = func_ir.insert_point();
+        if last.terminator().is_none() {
+            let value = match func_ir.return_type() {
+                ir::Type::Void => None,
+                ty => Some(func_ir.constant(ty, 0) as &dyn ir::Value<'m>),
+            };
+            func_ir.add_instruction(self.ctx.ret(value));
+        }
     }
 
     fn visit_stmt(&'m self, stmt: &ast::Stmt, func_ir: &'m ir::Func<'m>) {
@@ -94,15 +125,13 @@ impl<'m> Codegen<'m> {
                 cond,
                 then_stmt,
                 else_stmt: Some(else_stmt),
+                loc,
             } => {
+                let loc = Some(lower_loc(loc));
+                func_ir.set_debug_loc(loc);
                 let start_point = func_ir.insert_point();
 
-                let mut cond_val = self.visit_expr(cond, func_ir);
-                if cond_val.is_lvalue() {
-                    let load = self.ctx.load(cond_val);
-                    func_ir.add_instruction(load);
-                    cond_val = load;
-                }
+                let cond_val = self.visit_rvalue(cond, func_ir);
 
                 // Generate the then block
                 let then_block = self.ctx.new_basic_block();
@@ -118,17 +147,20 @@ impl<'m> Codegen<'m> {
                 self.visit_stmt(else_stmt, func_ir);
                 let else_end = func_ir.insert_point();
 
+                // The arms have moved the location on, the branches belong to
+                // the if statement.
+                func_ir.set_debug_loc(loc);
                 func_ir.set_insert_point(start_point);
                 func_ir.add_instruction(self.ctx.cjump(cond_val, then_block, else_block));
 
                 let exit_block = self.ctx.new_basic_block();
 
-                if !then_end.instructions().last().unwrap().is_terminator() {
+                if then_end.terminator().is_none() {
                     func_ir.set_insert_point(then_end);
                     func_ir.add_instruction(self.ctx.jump(exit_block));
                 }
 
-                if !else_end.instructions().last().unwrap().is_terminator() {
+                if else_end.terminator().is_none() {
                     func_ir.set_insert_point(else_end);
                     func_ir.add_instruction(self.ctx.jump(exit_block));
                 }
@@ -143,15 +175,13 @@ impl<'m> Codegen<'m> {
                 cond,
                 then_stmt,
                 else_stmt: None,
+                loc,
             } => {
+                let loc = Some(lower_loc(loc));
+                func_ir.set_debug_loc(loc);
                 let start_point = func_ir.insert_point();
 
-                let mut cond_val = self.visit_expr(cond, func_ir);
-                if cond_val.is_lvalue() {
-                    let load = self.ctx.load(cond_val);
-                    func_ir.add_instruction(load);
-                    cond_val = load;
-                }
+                let cond_val = self.visit_rvalue(cond, func_ir);
 
                 // Generate the then block
                 let then_block = self.ctx.new_basic_block();
@@ -162,10 +192,11 @@ impl<'m> Codegen<'m> {
 
                 let exit_block = self.ctx.new_basic_block();
 
+                func_ir.set_debug_loc(loc);
                 func_ir.set_insert_point(start_point);
                 func_ir.add_instruction(self.ctx.cjump(cond_val, then_block, exit_block));
 
-                if !then_end.instructions().last().unwrap().is_terminator() {
+                if then_end.terminator().is_none() {
                     func_ir.set_insert_point(then_end);
                     func_ir.add_instruction(self.ctx.jump(exit_block));
                 }
@@ -173,7 +204,9 @@ impl<'m> Codegen<'m> {
                 func_ir.add_block(exit_block);
                 func_ir.set_insert_point(exit_block);
             }
-            ast::Stmt::While { cond, body } => {
+            ast::Stmt::While { cond, body, loc } => {
+                let loc = Some(lower_loc(loc));
+                func_ir.set_debug_loc(loc);
                 let cond_block = self.ctx.new_basic_block();
                 let body_block = self.ctx.new_basic_block();
                 let end_block = self.ctx.new_basic_block();
@@ -187,18 +220,14 @@ impl<'m> Codegen<'m> {
 
                 // Generate the condition block
                 func_ir.set_insert_point(cond_block);
-                let mut cond_val = self.visit_expr(cond, func_ir);
-                if cond_val.is_lvalue() {
-                    let load = self.ctx.load(cond_val);
-                    func_ir.add_instruction(load);
-                    cond_val = load;
-                }
+                let cond_val = self.visit_rvalue(cond, func_ir);
                 let cjump = self.ctx.cjump(cond_val, body_block, end_block);
                 func_ir.add_instruction(cjump);
 
                 // Generate the body block
                 func_ir.set_insert_point(body_block);
                 self.visit_stmt(body, func_ir);
+                func_ir.set_debug_loc(loc);
                 let jump = self.ctx.jump(cond_block);
                 func_ir.add_instruction(jump);
 
@@ -206,73 +235,83 @@ impl<'m> Codegen<'m> {
             }
             ast::Stmt::VarDecl {
                 name: var_name,
-                ty: type_name,
+                ty,
                 expr,
+                loc,
             } => {
-                let alloca = self.ctx.alloca();
+                func_ir.set_debug_loc(Some(lower_loc(loc)));
+                let alloca = self.ctx.alloca(lower_type(ty));
+                func_ir.set_value_name(alloca, &format!("{}.addr", var_name));
                 func_ir.add_instruction(alloca);
 
                 self.scope.update(var_name, alloca);
 
                 if let Some(expr) = expr {
-                    let value = self.visit_expr(expr, func_ir);
-                    let value = if value.is_lvalue() {
-                        let load = self.ctx.load(value);
-                        func_ir.add_instruction(load);
-                        load
-                    } else {
-                        value
-                    };
+                    let value = self.visit_value(expr, func_ir);
                     let store = self.ctx.store(value, alloca);
                     func_ir.add_instruction(store);
                 }
             }
-            ast::Stmt::Return { expr } => {
-                let value = expr.as_ref().map(|expr| self.visit_expr(expr, func_ir));
-                // If value is Some, and it is an lvalue, load it.
-                let value = value.map(|value| {
-                    if value.is_lvalue() {
-                        let load = self.ctx.load(value);
-                        func_ir.add_instruction(load);
-                        load
-                    } else {
-                        value
-                    }
-                });
+            ast::Stmt::Return { expr, loc } => {
+                func_ir.set_debug_loc(Some(lower_loc(loc)));
+                let value = expr.as_ref().map(|expr| self.visit_value(expr, func_ir));
                 let ret = self.ctx.ret(value);
                 func_ir.add_instruction(ret);
             }
-            ast::Stmt::Expr { expr } => {
+            ast::Stmt::Expr { expr, loc } => {
+                func_ir.set_debug_loc(Some(lower_loc(loc)));
                 self.visit_expr(expr, func_ir);
             }
         }
     }
 
-    fn visit_expr(&'m self, expr: &ast::Expr, func_ir: &'m ir::Func<'m>) -> &'m dyn ir::Value {
+    /// Evaluate `expr`, loading the value of a variable.
+    fn visit_rvalue(
+        &'m self,
+        expr: &ast::Expr,
+        func_ir: &'m ir::Func<'m>,
+    ) -> &'m dyn ir::Value<'m> {
+        let value = self.visit_expr(expr, func_ir);
+        let Some(ty) = value.as_inst().and_then(|inst| inst.allocated_type()) else {
+            return value;
+        };
+        let load = self.ctx.load(ty, value);
+        func_ir.add_instruction(load);
+        load
+    }
+
+    /// Evaluate `expr` as a value of the language. Comparisons yield an
+    /// `i1`, which is widened to `Int64` here; conditions use it directly.
+    fn visit_value(&'m self, expr: &ast::Expr, func_ir: &'m ir::Func<'m>) -> &'m dyn ir::Value<'m> {
+        let value = self.visit_rvalue(expr, func_ir);
+        if value.ty() != ir::Type::I1 {
+            return value;
+        }
+        let zext = self.ctx.zext(value, ir::Type::I64);
+        func_ir.add_instruction(zext);
+        zext
+    }
+
+    fn visit_expr(&'m self, expr: &ast::Expr, func_ir: &'m ir::Func<'m>) -> &'m dyn ir::Value<'m> {
         match expr {
             ast::Expr::Integer { value } => {
-                let constant = self.ctx.new_constant(*value);
+                let constant = self.ctx.new_constant(ir::Type::I64, *value);
                 func_ir.add_constant(constant);
                 constant
             }
             ast::Expr::Variable { name } => self.scope.lookup(name).unwrap(),
             ast::Expr::Unary { op, operand } => {
-                let mut operand_val = self.visit_expr(operand, func_ir);
-                if operand_val.is_lvalue() {
-                    let load = self.ctx.load(operand_val);
-                    func_ir.add_instruction(load);
-                    operand_val = load;
-                }
+                let operand_val = self.visit_value(operand, func_ir);
                 match op {
                     ast::UnaryOp::Neg => {
-                        let zero = self.ctx.new_constant(0);
+                        let zero = self.ctx.new_constant(operand_val.ty(), 0);
                         let sub = self.ctx.sub(zero, operand_val);
                         func_ir.add_constant(zero);
                         func_ir.add_instruction(sub);
                         sub
                     }
                     ast::UnaryOp::BitwiseNot => {
-                        let neg1 = self.ctx.new_constant(u64::MAX);
+                        let neg1 = self.ctx.new_constant(operand_val.ty(), u64::MAX);
                         let not = self.ctx.xor(operand_val, neg1);
                         func_ir.add_constant(neg1);
                         func_ir.add_instruction(not);
@@ -282,18 +321,12 @@ impl<'m> Codegen<'m> {
                 }
             }
             ast::Expr::Binary { op, lhs, rhs } => {
-                let mut lhs_val = self.visit_expr(lhs, func_ir);
-                let mut rhs_val = self.visit_expr(rhs, func_ir);
-                if *op != ast::BinaryOp::Assignment && lhs_val.is_lvalue() {
-                    let load = self.ctx.load(lhs_val);
-                    func_ir.add_instruction(load);
-                    lhs_val = load;
-                }
-                if rhs_val.is_lvalue() {
-                    let load = self.ctx.load(rhs_val);
-                    func_ir.add_instruction(load);
-                    rhs_val = load;
-                }
+                let lhs_val = if *op == ast::BinaryOp::Assignment {
+                    self.visit_expr(lhs, func_ir)
+                } else {
+                    self.visit_value(lhs, func_ir)
+                };
+                let rhs_val = self.visit_value(rhs, func_ir);
                 match op {
                     ast::BinaryOp::Assignment => {
                         let store = self.ctx.store(rhs_val, lhs_val);
@@ -380,26 +413,30 @@ impl<'m> Codegen<'m> {
                         func_ir.add_instruction(modulo);
                         modulo
                     }
-                    _ => unimplemented!(),
                 }
             }
             ast::Expr::Call { callee, arguments } => {
                 let callee_ir = self.unit.get_function(callee).unwrap();
-                let mut args = Vec::<&'m dyn ir::Value>::new();
+                let mut args = Vec::<&'m dyn ir::Value<'m>>::new();
                 for arg in arguments {
-                    let arg = self.visit_expr(arg, func_ir);
-                    if arg.is_lvalue() {
-                        let load = self.ctx.load(arg);
-                        func_ir.add_instruction(load);
-                        args.push(load);
-                    } else {
-                        args.push(arg);
-                    }
+                    args.push(self.visit_value(arg, func_ir));
                 }
-                let call = self.ctx.call(String::from(callee), callee_ir, args);
+                let call = self.ctx.call(callee_ir, args);
                 func_ir.add_instruction(call);
                 call
             }
         }
     }
 }
+
+fn lower_loc(loc: &ast::Loc) -> ir::DebugLoc {
+    ir::DebugLoc::new(loc.line, loc.col)
+}
+
+fn lower_type(ty: &ast::TypeSpecifier) -> ir::Type {
+    match ty {
+        ast::TypeSpecifier::Void => ir::Type::Void,
+        ast::TypeSpecifier::Int64 => ir::Type::I64,
+        ast::TypeSpecifier::Pointer(_) => ir::Type::Ptr,
+    }
+}
@@ -4,7 +4,7 @@ use std::collections::HashMap;
 use super::super::Value;
 
 pub struct NestedScope<'m> {
-    stack: RefCell<Vec<HashMap<String, &'m dyn Value>>>,
+    stack: RefCell<Vec<HashMap<String, &'m dyn Value<'m>>>>,
 }
 
 impl<'m> NestedScope<'m> {
@@ -20,7 +20,7 @@ impl<'m> NestedScope<'m> {
         ScopeGuard::new(self)
     }
 
-    pub fn lookup(&self, name: &str) -> Option<&'m dyn Value> {
+    pub fn lookup(&self, name: &str) -> Option<&'m dyn Value<'m>> {
         let stack = self.stack.borrow();
         for scope in stack.iter().rev() {
             if let Some(val) = scope.get(name) {
@@ -30,7 +30,7 @@ impl<'m> NestedScope<'m> {
         None
     }
 
-    pub fn update(&self, name: &str, val: &'m dyn Value) {
+    pub fn update(&self, name: &str, val: &'m dyn Value<'m>) {
         let mut stack = self.stack.borrow_mut();
         stack.last_mut().unwrap().insert(String::from(name), val);
     }
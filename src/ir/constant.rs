@@ -1,15 +1,20 @@
-use super::Value;
+use super::{Type, UseList, Value};
 
-pub struct Constant {
+pub struct Constant<'m> {
     name: String,
+    ty: Type,
     value: u64,
+    uses: UseList<'m>,
 }
 
-impl Constant {
-    pub fn new(_name: String, value: u64) -> Constant {
+impl<'m> Constant<'m> {
+    pub fn new(_name: String, ty: Type, value: u64) -> Constant<'m> {
+        let value = ty.truncate(value);
         Constant {
             name: format!("${value}"),
+            ty,
             value,
+            uses: UseList::new(),
         }
     }
 
@@ -18,8 +23,20 @@ impl Constant {
     }
 }
 
-impl Value for Constant {
+impl<'m> Value<'m> for Constant<'m> {
     fn name(&self) -> &str {
         &self.name
     }
+
+    fn ty(&self) -> Type {
+        self.ty
+    }
+
+    fn use_list(&self) -> &UseList<'m> {
+        &self.uses
+    }
+
+    fn as_constant(&self) -> Option<&Constant<'m>> {
+        Some(self)
+    }
 }
@@ -2,15 +2,15 @@ use std::cell::RefCell;
 
 use typed_arena::Arena;
 
-use super::{BasicBlock, Constant, Func, Inst, Param, Value};
+use super::{BasicBlock, Constant, Func, Inst, InstKind, Param, Type, Value};
 
 pub struct Context<'m> {
     next_id: RefCell<usize>,
     func: Arena<Func<'m>>,
-    param: Arena<Param>,
+    param: Arena<Param<'m>>,
     basic_block: Arena<BasicBlock<'m>>,
     inst: Arena<Inst<'m>>,
-    constant: Arena<Constant>,
+    constant: Arena<Constant<'m>>,
 }
 
 impl<'m> Context<'m> {
@@ -25,10 +25,6 @@ impl<'m> Context<'m> {
         }
     }
 
-    pub fn reset_id(&self) {
-        *self.next_id.borrow_mut() = 0;
-    }
-
     fn next_id(&self) -> usize {
         let id = *self.next_id.borrow();
         *self.next_id.borrow_mut() += 1;
@@ -39,122 +35,164 @@ impl<'m> Context<'m> {
         format!("%{}", self.next_id())
     }
 
-    pub fn new_function(&'m self, name: String, params: Vec<&'m Param>) -> &'m mut Func<'m> {
-        self.func
-            .alloc(Func::new(name, params, self.new_basic_block()))
+    pub fn new_function(
+        &'m self,
+        name: String,
+        params: Vec<&'m Param<'m>>,
+        ret_ty: Type,
+    ) -> &'m Func<'m> {
+        let entry = self.new_basic_block();
+        let func = self
+            .func
+            .alloc(Func::new(self, name, params, ret_ty, entry));
+        entry.set_parent(Some(func));
+        func
     }
 
-    pub fn new_parameter(&self, name: String) -> &Param {
-        self.param.alloc(Param::new(self.next_name()))
+    /// A parameter named after `name`, or numbered like other values if
+    /// `name` is empty.
+    pub fn new_parameter(&'m self, name: String, ty: Type) -> &'m Param<'m> {
+        let name = if name.is_empty() {
+            self.next_name()
+        } else {
+            format!("%{name}")
+        };
+        self.param.alloc(Param::new(name, ty))
     }
 
     pub fn new_basic_block(&'m self) -> &'m BasicBlock<'m> {
         self.basic_block.alloc(BasicBlock::new(self.next_id()))
     }
 
-    pub fn alloca(&self) -> &Inst<'m> {
-        self.inst.alloc(Inst::alloca(self.next_name()))
+    /// Allocate an instruction and register it in the use lists of its
+    /// operands.
+    fn new_inst(&'m self, inst: Inst<'m>) -> &'m Inst<'m> {
+        let inst = self.inst.alloc(inst);
+        inst.attach();
+        inst
+    }
+
+    /// Build an instruction of type `ty` from an already assembled
+    /// `InstKind`.
+    pub fn inst(&'m self, ty: Type, kind: InstKind<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::new(self.next_name(), ty, kind))
+    }
+
+    pub fn alloca(&'m self, ty: Type) -> &'m Inst<'m> {
+        self.new_inst(Inst::alloca(self.next_name(), ty))
+    }
+
+    pub fn store(&'m self, value: &'m dyn Value<'m>, ptr: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::store(self.next_name(), value, ptr))
     }
 
-    pub fn store(&self, value: &'m dyn Value, ptr: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::store(self.next_name(), value, ptr))
+    pub fn load(&'m self, ty: Type, ptr: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::load(self.next_name(), ty, ptr))
     }
 
-    pub fn load(&self, ptr: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::load(self.next_name(), ptr))
+    pub fn zext(&'m self, val: &'m dyn Value<'m>, ty: Type) -> &'m Inst<'m> {
+        self.new_inst(Inst::zext(self.next_name(), val, ty))
     }
 
-    pub fn or(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::or(self.next_name(), op0, op1))
+    pub fn or(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::or(self.next_name(), op0, op1))
     }
 
-    pub fn xor(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::xor(self.next_name(), op0, op1))
+    pub fn xor(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::xor(self.next_name(), op0, op1))
     }
 
-    pub fn and(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::and(self.next_name(), op0, op1))
+    pub fn and(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::and(self.next_name(), op0, op1))
     }
 
-    pub fn lshl(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::lshl(self.next_name(), op0, op1))
+    pub fn lshl(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::lshl(self.next_name(), op0, op1))
     }
 
-    pub fn lshr(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::lshr(self.next_name(), op0, op1))
+    pub fn lshr(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::lshr(self.next_name(), op0, op1))
     }
 
-    pub fn ashr(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::ashr(self.next_name(), op0, op1))
+    pub fn ashr(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::ashr(self.next_name(), op0, op1))
     }
 
-    pub fn eq(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::eq(self.next_name(), op0, op1))
+    pub fn eq(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::eq(self.next_name(), op0, op1))
     }
 
-    pub fn ne(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::ne(self.next_name(), op0, op1))
+    pub fn ne(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::ne(self.next_name(), op0, op1))
     }
 
-    pub fn gt(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::gt(self.next_name(), op0, op1))
+    pub fn gt(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::gt(self.next_name(), op0, op1))
     }
 
-    pub fn ge(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::ge(self.next_name(), op0, op1))
+    pub fn ge(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::ge(self.next_name(), op0, op1))
     }
 
-    pub fn lt(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::lt(self.next_name(), op0, op1))
+    pub fn lt(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::lt(self.next_name(), op0, op1))
     }
 
-    pub fn le(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::le(self.next_name(), op0, op1))
+    pub fn le(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::le(self.next_name(), op0, op1))
     }
 
-    pub fn add(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::add(self.next_name(), op0, op1))
+    pub fn add(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::add(self.next_name(), op0, op1))
     }
 
-    pub fn sub(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::sub(self.next_name(), op0, op1))
+    pub fn sub(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::sub(self.next_name(), op0, op1))
     }
 
-    pub fn mul(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::mul(self.next_name(), op0, op1))
+    pub fn mul(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::mul(self.next_name(), op0, op1))
     }
 
-    pub fn div(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::div(self.next_name(), op0, op1))
+    pub fn div(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::div(self.next_name(), op0, op1))
     }
 
-    pub fn modulo(&self, op0: &'m dyn Value, op1: &'m dyn Value) -> &Inst<'m> {
-        self.inst.alloc(Inst::modulo(self.next_name(), op0, op1))
+    pub fn modulo(&'m self, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::modulo(self.next_name(), op0, op1))
     }
 
-    pub fn jump(&self, target: &'m BasicBlock<'m>) -> &Inst<'m> {
-        self.inst.alloc(Inst::jump(self.next_name(), target))
+    pub fn jump(&'m self, target: &'m BasicBlock<'m>) -> &'m Inst<'m> {
+        self.new_inst(Inst::jump(self.next_name(), target))
     }
 
     pub fn cjump(
-        &self,
-        cond: &'m dyn Value,
+        &'m self,
+        cond: &'m dyn Value<'m>,
         then_block: &'m BasicBlock<'m>,
         else_block: &'m BasicBlock<'m>,
-    ) -> &Inst<'m> {
-        self.inst
-            .alloc(Inst::cjump(self.next_name(), cond, then_block, else_block))
+    ) -> &'m Inst<'m> {
+        self.new_inst(Inst::cjump(self.next_name(), cond, then_block, else_block))
+    }
+
+    pub fn call(&'m self, callee: &'m Func<'m>, args: Vec<&'m dyn Value<'m>>) -> &'m Inst<'m> {
+        self.new_inst(Inst::call(self.next_name(), callee, args))
     }
 
-    pub fn call(&self, name: String, callee: &'m Func<'m>, args: Vec<&'m dyn Value>) -> &Inst<'m> {
-        self.inst.alloc(Inst::call(name, callee, args))
+    pub fn ret(&'m self, value: Option<&'m dyn Value<'m>>) -> &'m Inst<'m> {
+        self.new_inst(Inst::ret(self.next_name(), value))
     }
 
-    pub fn ret(&self, value: Option<&'m dyn Value>) -> &Inst<'m> {
-        self.inst.alloc(Inst::ret(self.next_name(), value))
+    pub fn phi(
+        &'m self,
+        ty: Type,
+        incoming: Vec<(&'m dyn Value<'m>, &'m BasicBlock<'m>)>,
+    ) -> &'m Inst<'m> {
+        self.new_inst(Inst::new(self.next_name(), ty, InstKind::Phi(incoming)))
     }
 
-    pub fn new_constant(&self, value: u64) -> &Constant {
-        self.constant.alloc(Constant::new(self.next_name(), value))
+    pub fn new_constant(&'m self, ty: Type, value: u64) -> &'m Constant<'m> {
+        self.constant
+            .alloc(Constant::new(self.next_name(), ty, value))
     }
 }
@@ -0,0 +1,20 @@
+use std::fmt;
+
+/// The source position an instruction was generated from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebugLoc {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl DebugLoc {
+    pub fn new(line: usize, col: usize) -> DebugLoc {
+        DebugLoc { line, col }
+    }
+}
+
+impl fmt::Display for DebugLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
@@ -1,31 +1,213 @@
-use super::{BasicBlock, Constant, Inst, Param, Value};
-use std::cell::{Ref, RefCell};
+use super::{BasicBlock, Constant, Context, DebugLoc, Inst, InstKind, Param, Type, Value};
+use crate::dot::Graph;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::HashSet;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 
+/// Properties of a function that passes take into account.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Attribute {
+    /// Inline calls to this function whenever possible.
+    Inline,
+    /// Never inline calls to this function.
+    NoInline,
+    /// Not visible outside the module, so it can be removed once nothing
+    /// calls it.
+    Internal,
+    /// Only touches its own stack slots and calls pure functions. Inferred.
+    Pure,
+    /// Contains no calls at all. Inferred.
+    NoCalls,
+    /// Makes no calls other than tail calls, which are emitted as branches,
+    /// so the link register is never clobbered. Inferred.
+    Leaf,
+    /// Never calls itself, directly or through other functions. Inferred.
+    NoRecurse,
+    /// Returns to its caller whatever its arguments: it has no loops and
+    /// calls only functions that also return, none of them recursively. A
+    /// call to a pure function that returns can be deleted if its result
+    /// is unused. Inferred.
+    WillReturn,
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attribute::Inline => write!(f, "inline"),
+            Attribute::NoInline => write!(f, "noinline"),
+            Attribute::Internal => write!(f, "internal"),
+            Attribute::Pure => write!(f, "pure"),
+            Attribute::NoCalls => write!(f, "nocalls"),
+            Attribute::Leaf => write!(f, "leaf"),
+            Attribute::NoRecurse => write!(f, "norecurse"),
+            Attribute::WillReturn => write!(f, "willreturn"),
+        }
+    }
+}
+
 pub struct Func<'m> {
+    ctx: &'m Context<'m>,
     name: String,
-    params: Vec<&'m Param>,
-    constants: RefCell<Vec<&'m Constant>>,
+    params: Vec<&'m Param<'m>>,
+    ret_ty: Type,
+    attrs: RefCell<Vec<Attribute>>,
+    constants: RefCell<Vec<&'m Constant<'m>>>,
     blocks: RefCell<Vec<&'m BasicBlock<'m>>>,
     insert_point: RefCell<&'m BasicBlock<'m>>,
+    // Source names given to the parameters and instructions so far
+    names: RefCell<HashSet<String>>,
+    // Location given to instructions added at the insert point
+    debug_loc: Cell<Option<DebugLoc>>,
 }
 
 impl<'m> Func<'m> {
-    pub fn new(name: String, params: Vec<&'m Param>, entry: &'m BasicBlock<'m>) -> Func<'m> {
+    pub fn new(
+        ctx: &'m Context<'m>,
+        name: String,
+        params: Vec<&'m Param<'m>>,
+        ret_ty: Type,
+        entry: &'m BasicBlock<'m>,
+    ) -> Func<'m> {
+        let mut names = HashSet::new();
+        for param in params.iter() {
+            let param_name = &param.name()[1..];
+            let unique = unique_name(&mut names, param_name);
+            if unique != param_name {
+                param.set_unique_name(unique);
+            }
+        }
         Func {
+            ctx,
             name,
             params,
+            ret_ty,
+            attrs: RefCell::new(vec![]),
             constants: RefCell::new(vec![]),
             blocks: RefCell::new(vec![entry]),
             insert_point: RefCell::new(entry),
+            names: RefCell::new(names),
+            debug_loc: Cell::new(None),
         }
     }
 
-    pub fn add_block(&self, block: &'m BasicBlock<'m>) {
+    pub fn context(&self) -> &'m Context<'m> {
+        self.ctx
+    }
+
+    pub fn add_block(&'m self, block: &'m BasicBlock<'m>) {
+        block.set_parent(Some(self));
         self.blocks.borrow_mut().push(block);
     }
 
+    pub fn insert_block_after(&'m self, after: &'m BasicBlock<'m>, block: &'m BasicBlock<'m>) {
+        let index = self
+            .block_index(after)
+            .expect("block is not in this function");
+        block.set_parent(Some(self));
+        self.blocks.borrow_mut().insert(index + 1, block);
+    }
+
+    pub fn insert_block_before(&'m self, before: &'m BasicBlock<'m>, block: &'m BasicBlock<'m>) {
+        let index = self
+            .block_index(before)
+            .expect("block is not in this function");
+        block.set_parent(Some(self));
+        self.blocks.borrow_mut().insert(index, block);
+    }
+
+    /// Unlink a block from this function without touching its instructions.
+    pub fn remove_block(&self, block: &'m BasicBlock<'m>) {
+        let index = self
+            .block_index(block)
+            .expect("block is not in this function");
+        self.blocks.borrow_mut().remove(index);
+        block.set_parent(None);
+    }
+
+    /// Delete a block and all of its instructions. Values defined in it must
+    /// only be used by instructions that are being deleted as well.
+    pub fn erase_block(&self, block: &'m BasicBlock<'m>) {
+        for inst in block.instructions().iter() {
+            inst.drop_operands();
+        }
+        self.remove_block(block);
+    }
+
+    fn block_index(&self, block: &'m BasicBlock<'m>) -> Option<usize> {
+        self.blocks.borrow().iter().position(|b| *b == block)
+    }
+
+    pub fn entry(&self) -> &'m BasicBlock<'m> {
+        self.blocks.borrow()[0]
+    }
+
+    /// Split `block` right before `inst`. `inst` and everything after it move
+    /// to a new block placed after `block`, and `block` falls through to it
+    /// with a jump.
+    pub fn split_block(
+        &'m self,
+        block: &'m BasicBlock<'m>,
+        inst: &'m Inst<'m>,
+    ) -> &'m BasicBlock<'m> {
+        let index = block
+            .index_of(inst)
+            .expect("instruction is not in this block");
+        let new_block = self.ctx.new_basic_block();
+        self.insert_block_after(block, new_block);
+
+        let moved = block.instructions()[index..].to_vec();
+        for inst in moved {
+            inst.remove_from_parent();
+            new_block.add_instruction(inst);
+        }
+        block.add_instruction(self.ctx.jump(new_block));
+        for succ in new_block.successors() {
+            for phi in succ.phis() {
+                phi.replace_incoming_block(block, new_block);
+            }
+        }
+        new_block
+    }
+
+    /// Fold `block` into its predecessor if that predecessor has no other
+    /// successor and `block` has no other predecessor. Returns whether the
+    /// blocks were merged.
+    pub fn merge_into_predecessor(&self, block: &'m BasicBlock<'m>) -> bool {
+        if block == self.entry() {
+            return false;
+        }
+        let Some(pred) = block.single_predecessor() else {
+            return false;
+        };
+        if pred == block {
+            return false;
+        }
+        let term = pred.terminator().unwrap();
+        if !matches!(&*term.kind(), InstKind::Jump(_)) {
+            return false;
+        }
+
+        term.erase();
+        for phi in block.phis() {
+            let val = phi.incoming_value(pred).unwrap();
+            phi.replace_all_uses_with(val);
+            phi.erase();
+        }
+        for succ in block.successors() {
+            for phi in succ.phis() {
+                phi.replace_incoming_block(block, pred);
+            }
+        }
+        let moved = block.instructions().clone();
+        for inst in moved {
+            inst.remove_from_parent();
+            pred.add_instruction(inst);
+        }
+        self.remove_block(block);
+        true
+    }
+
     pub fn insert_point(&self) -> &'m BasicBlock<'m> {
         *self.insert_point.borrow()
     }
@@ -34,43 +216,159 @@ impl<'m> Func<'m> {
         *self.insert_point.borrow_mut() = block;
     }
 
+    /// Location attached to the instructions added with `add_instruction`
+    /// from now on.
+    pub fn set_debug_loc(&self, loc: Option<DebugLoc>) {
+        self.debug_loc.set(loc);
+    }
+
     pub fn add_instruction(&self, inst: &'m Inst<'m>) {
+        if inst.loc().is_none() {
+            inst.set_loc(self.debug_loc.get());
+        }
         self.insert_point.borrow().add_instruction(inst);
     }
 
-    pub fn add_constant(&self, constant: &'m Constant) {
+    /// Give `inst` the source name `name`, or `name.N` if another value of
+    /// this function already has it. Identifiers cannot contain a `.`, so
+    /// the suffixed names never clash with names from the source.
+    pub fn set_value_name(&self, inst: &Inst<'m>, name: &str) {
+        let unique = unique_name(&mut self.names.borrow_mut(), name);
+        inst.set_debug_name(unique);
+    }
+
+    /// Carry the source name and location of `from` over to `to`, a copy of
+    /// it placed in this function.
+    pub fn copy_debug_info(&self, from: &Inst<'m>, to: &Inst<'m>) {
+        if let Some(name) = from.debug_name() {
+            self.set_value_name(to, name);
+        }
+        to.set_loc(from.loc());
+    }
+
+    pub fn add_constant(&self, constant: &'m Constant<'m>) {
         self.constants.borrow_mut().push(constant);
     }
 
+    /// A constant of this function with the given type and value, created
+    /// if there is none yet.
+    pub fn constant(&self, ty: Type, value: u64) -> &'m Constant<'m> {
+        let value = ty.truncate(value);
+        let existing = self
+            .constants
+            .borrow()
+            .iter()
+            .find(|c| c.ty() == ty && c.value() == value)
+            .copied();
+        existing.unwrap_or_else(|| {
+            let constant = self.ctx.new_constant(ty, value);
+            self.add_constant(constant);
+            constant
+        })
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    pub fn params(&self) -> &Vec<&'m Param> {
+    pub fn add_attribute(&self, attr: Attribute) {
+        if !self.has_attribute(attr) {
+            self.attrs.borrow_mut().push(attr);
+        }
+    }
+
+    pub fn remove_attribute(&self, attr: Attribute) {
+        self.attrs.borrow_mut().retain(|a| *a != attr);
+    }
+
+    pub fn attributes(&self) -> Ref<'_, Vec<Attribute>> {
+        self.attrs.borrow()
+    }
+
+    pub fn has_attribute(&self, attr: Attribute) -> bool {
+        self.attrs.borrow().contains(&attr)
+    }
+
+    pub fn params(&self) -> &Vec<&'m Param<'m>> {
         &self.params
     }
 
-    pub fn constants(&self) -> Ref<Vec<&'m Constant>> {
+    pub fn return_type(&self) -> Type {
+        self.ret_ty
+    }
+
+    pub fn constants(&self) -> Ref<'_, Vec<&'m Constant<'m>>> {
         self.constants.borrow()
     }
 
-    pub fn blocks(&self) -> Ref<Vec<&'m BasicBlock<'m>>> {
+    pub fn blocks(&self) -> Ref<'_, Vec<&'m BasicBlock<'m>>> {
         self.blocks.borrow()
     }
+
+    /// The control-flow graph, with the instructions of each block and the
+    /// edges of conditional jumps labelled true or false.
+    pub fn cfg_graph(&self) -> Graph {
+        let mut graph = Graph::new(&self.name);
+        for block in self.blocks.borrow().iter() {
+            let lines = block.instructions().iter().map(|i| i.to_string()).collect();
+            graph.add_node(block.name(), lines);
+            match block.terminator().map(|term| term.kind().clone()) {
+                Some(InstKind::Jump(target)) => graph.add_edge(block.name(), target.name(), None),
+                Some(InstKind::CJump(_, ifbb, elsebb)) => {
+                    graph.add_edge(block.name(), ifbb.name(), Some("true"));
+                    graph.add_edge(block.name(), elsebb.name(), Some("false"));
+                }
+                _ => {}
+            }
+        }
+        graph
+    }
+
+    /// All instructions of this function, in layout order.
+    pub fn instructions(&self) -> Vec<&'m Inst<'m>> {
+        self.blocks
+            .borrow()
+            .iter()
+            .flat_map(|block| block.instructions().clone())
+            .collect()
+    }
+}
+
+/// `name`, or `name.N` if it is already in `names`, added to `names`.
+fn unique_name(names: &mut HashSet<String>, name: &str) -> String {
+    let mut unique = String::from(name);
+    if names.contains(&unique) {
+        // Copies of `x.1` are numbered `x.2`, ... rather than `x.1.1`.
+        let base = match name.rsplit_once('.') {
+            Some((base, n)) if n.parse::<usize>().is_ok() => base,
+            _ => name,
+        };
+        let mut n = 0;
+        while names.contains(&unique) {
+            n += 1;
+            unique = format!("{base}.{n}");
+        }
+    }
+    names.insert(unique.clone());
+    unique
 }
 
 impl fmt::Display for Func<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO If this function is just a prototype, print 'extern' instead of
         // 'define'
-        write!(f, "define @{}(", self.name)?;
+        write!(f, "define {} @{}(", self.ret_ty, self.name)?;
         for (i, param) in self.params.iter().enumerate() {
             if i > 0 {
                 write!(f, ", ")?;
             }
-            write!(f, "{}", param.name())?;
+            write!(f, "{} {}", param.ty(), param.name())?;
         }
-        write!(f, ") {{")?;
+        write!(f, ")")?;
+        for attr in self.attrs.borrow().iter() {
+            write!(f, " {}", attr)?;
+        }
+        write!(f, " {{")?;
         for block in self.blocks.borrow().iter() {
             write!(f, "\n{}", block)?;
         }
@@ -91,3 +389,262 @@ impl<'m> Hash for &'m Func<'m> {
         std::ptr::hash(*self, state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::ir::verify::verify_function;
+    use crate::ir::{lower_source, DebugLoc, InstKind, Module, Type, Value};
+
+    #[test]
+    fn split_and_merge() {
+        let module = Module::new();
+        let ctx = module.context();
+        let n = ctx.new_parameter(String::from("n"), Type::I64);
+        let func = ctx.new_function(String::from("f"), vec![n], Type::I64);
+        module.add_function(func);
+
+        let one = ctx.new_constant(Type::I64, 1);
+        func.add_constant(one);
+        let add = ctx.add(n, one);
+        func.add_instruction(add);
+        let mul = ctx.mul(add, add);
+        func.add_instruction(mul);
+        func.add_instruction(ctx.ret(Some(mul)));
+
+        let entry = func.entry();
+        let tail = func.split_block(entry, mul);
+        verify_function(func).unwrap();
+        assert_eq!(func.blocks().len(), 2);
+        assert_eq!(entry.instructions().len(), 2);
+        assert!(entry.successors() == vec![tail]);
+        assert!(tail.predecessors() == vec![entry]);
+        assert!(mul.parent().is_some_and(|b| b == tail));
+
+        assert!(!func.merge_into_predecessor(entry));
+        assert!(func.merge_into_predecessor(tail));
+        verify_function(func).unwrap();
+        assert_eq!(func.blocks().len(), 1);
+        assert_eq!(entry.instructions().len(), 3);
+    }
+
+    #[test]
+    fn replace_and_erase() {
+        let module = Module::new();
+        let ctx = module.context();
+        let n = ctx.new_parameter(String::from("n"), Type::I64);
+        let func = ctx.new_function(String::from("f"), vec![n], Type::I64);
+        module.add_function(func);
+
+        let two = ctx.new_constant(Type::I64, 2);
+        func.add_constant(two);
+        let mul = ctx.mul(n, two);
+        func.add_instruction(mul);
+        let add = ctx.add(mul, mul);
+        func.add_instruction(add);
+        let ret = ctx.ret(Some(add));
+        func.add_instruction(ret);
+        assert_eq!(mul.users().len(), 2);
+        assert_eq!(n.users().len(), 1);
+
+        // Rewrite `n * 2` as `n << 1` placed before the multiplication.
+        let one = ctx.new_constant(Type::I64, 1);
+        func.add_constant(one);
+        let shl = ctx.lshl(n, one);
+        shl.insert_before(mul);
+        mul.replace_all_uses_with(shl);
+        assert!(!mul.has_users());
+        assert_eq!(shl.users().len(), 2);
+        mul.erase();
+        assert_eq!(n.users().len(), 1);
+        assert!(!two.has_users());
+        verify_function(func).unwrap();
+
+        // Returning the parameter directly leaves the addition unused.
+        ret.replace_uses_of(add, n);
+        assert!(matches!(&*ret.kind(), InstKind::Return(Some(v)) if *v == n as &dyn Value));
+        add.erase();
+        shl.erase();
+        verify_function(func).unwrap();
+        assert_eq!(func.entry().instructions().len(), 1);
+    }
+
+    #[test]
+    fn verifier_rejects_missing_terminator() {
+        let module = Module::new();
+        let ctx = module.context();
+        let func = ctx.new_function(String::from("f"), vec![], Type::Void);
+        module.add_function(func);
+
+        let alloca = ctx.alloca(Type::I64);
+        func.add_instruction(alloca);
+        assert!(verify_function(func).is_err());
+        func.add_instruction(ctx.ret(None));
+        verify_function(func).unwrap();
+    }
+
+    #[test]
+    fn implicit_return() {
+        let module = lower_source(
+            r"func f(n: Int64) {
+    var x: Int64 = n;
+}
+func g(n: Int64) : Int64 {
+    if n > 0 {
+        return 1;
+    } else {
+        return 0;
+    }
+}
+",
+        );
+        for func in module.functions().iter() {
+            for block in func.blocks().iter() {
+                let insts = block.instructions();
+                assert!(
+                    insts.last().is_some_and(|inst| inst.is_terminator()),
+                    "{}",
+                    func
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unique_value_names() {
+        let module = lower_source(
+            r"func f(n: Int64) : Int64 {
+    var x: Int64 = n + 1;
+    return x * 2;
+}
+func g() {
+}
+",
+        );
+        let ctx = module.context();
+        let f = module.get_function("f").unwrap();
+
+        // Instructions created once the whole module is lowered, as passes
+        // do, get names of their own.
+        let n = f.params()[0];
+        let add = ctx.add(n, n);
+        add.insert_before(f.entry().terminator().unwrap());
+        let names: Vec<_> = f
+            .instructions()
+            .iter()
+            .map(|i| i.name().to_string())
+            .collect();
+        let unique: HashSet<_> = names.iter().collect();
+        assert_eq!(names.len(), unique.len(), "{}", f);
+    }
+
+    #[test]
+    fn verifier_checks_types() {
+        let module = Module::new();
+        let ctx = module.context();
+        let n = ctx.new_parameter(String::from("n"), Type::I64);
+        let func = ctx.new_function(String::from("f"), vec![n], Type::I64);
+        module.add_function(func);
+
+        // Comparisons yield an i1, which has to be widened before it can be
+        // added to an i64.
+        let one = func.constant(Type::I64, 1);
+        let cmp = ctx.lt(n, one);
+        func.add_instruction(cmp);
+        let add = ctx.add(n, cmp);
+        func.add_instruction(add);
+        let ret = ctx.ret(Some(add));
+        func.add_instruction(ret);
+        assert!(verify_function(func).is_err());
+
+        let zext = ctx.zext(cmp, Type::I64);
+        zext.insert_before(add);
+        add.replace_uses_of(cmp, zext);
+        verify_function(func).unwrap();
+        assert_eq!(
+            add.to_string(),
+            format!("{} = add i64 %n, {}", add.name(), zext.name())
+        );
+
+        // The returned value has to match the return type.
+        ret.replace_uses_of(add, cmp);
+        assert!(verify_function(func).is_err());
+        ret.replace_uses_of(cmp, add);
+
+        let slot = ctx.alloca(Type::I32);
+        func.entry().insert_instruction(0, slot);
+        assert_eq!(slot.to_string(), format!("{} = alloca i32", slot.name()));
+        let store = ctx.store(n, one);
+        store.insert_before(ret);
+        assert!(verify_function(func).is_err());
+        store.set_kind(InstKind::Store(n, slot));
+        verify_function(func).unwrap();
+    }
+
+    #[test]
+    fn cfg_graph_labels_branches() {
+        let src = r"func f(a: Int64) : Int64 {
+    if a {
+        return 1;
+    }
+    return 2;
+}";
+        let func = crate::ir::lower_source(src).functions()[0];
+        let entry = func.entry().name().to_string();
+        let mut out = Vec::new();
+        func.cfg_graph().write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("digraph \"f\" {"));
+        assert!(out.contains(&format!("\"{}\" [label=\"{}:\\l", entry, entry)));
+        assert!(out.contains("cjump i64 %"));
+        assert_eq!(out.matches("[label=\"true\"]").count(), 1);
+        assert_eq!(out.matches("[label=\"false\"]").count(), 1);
+    }
+
+    #[test]
+    fn source_names_and_locations() {
+        let src = r"func f(n: Int64) : Int64 {
+    var ans: Int64 = n;
+    if n {
+        var ans: Int64 = 2;
+        return ans;
+    }
+    return ans;
+}";
+        let func = crate::ir::lower_source(src).functions()[0];
+        let ir = func.to_string();
+        assert!(ir.starts_with("define i64 @f(i64 %n)"), "{}", ir);
+        assert!(ir.contains("\n\tstore i64 %n, %n.addr\n"), "{}", ir);
+        assert!(ir.contains("%ans.addr = alloca i64\t; 2:5"), "{}", ir);
+        assert!(ir.contains("%ans.addr.1 = alloca i64\t; 4:9"), "{}", ir);
+
+        let ret = func.blocks()[1].terminator().unwrap();
+        assert_eq!(ret.loc(), Some(DebugLoc::new(5, 9)));
+        let cjump = func.entry().terminator().unwrap();
+        assert_eq!(cjump.loc(), Some(DebugLoc::new(3, 5)));
+    }
+
+    #[test]
+    fn duplicate_parameter_names() {
+        let module = Module::new();
+        let ctx = module.context();
+        let a = ctx.new_parameter(String::from("a"), Type::I64);
+        let b = ctx.new_parameter(String::from("a"), Type::I64);
+        let func = ctx.new_function(String::from("f"), vec![a, b], Type::I64);
+        module.add_function(func);
+        assert_eq!(a.name(), "%a");
+        assert_eq!(b.name(), "%a.1");
+
+        let add = ctx.add(a, b);
+        func.add_instruction(add);
+        func.set_value_name(add, "a");
+        assert_eq!(add.name(), "%a.2");
+    }
+
+    #[test]
+    #[should_panic(expected = "Parameter 'a' of 'f' is declared twice")]
+    fn duplicate_parameters_in_source() {
+        crate::ir::lower_source("func f(a: Int64, a: Int64) : Int64 { return a; }");
+    }
+}
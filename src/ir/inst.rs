@@ -1,335 +1,862 @@
-use super::{BasicBlock, Func, Value};
+use super::{Attribute, BasicBlock, DebugLoc, Func, Type, UseList, Value};
+use std::cell::{Cell, OnceCell, Ref, RefCell};
 use std::fmt;
 
+#[derive(Clone)]
 pub enum InstKind<'m> {
-    Alloca,
+    // result := pointer to a new stack slot holding a <0: type>
+    Alloca(Type),
     // <0: val> -> *<1: ptr>
-    Store(&'m dyn Value, &'m dyn Value),
-    // result := *<0: ptr>
-    Load(&'m dyn Value),
-
-    Or(&'m dyn Value, &'m dyn Value),
-    Xor(&'m dyn Value, &'m dyn Value),
-    And(&'m dyn Value, &'m dyn Value),
-    LShl(&'m dyn Value, &'m dyn Value),
-    LShr(&'m dyn Value, &'m dyn Value),
-    AShr(&'m dyn Value, &'m dyn Value),
-
-    Eq(&'m dyn Value, &'m dyn Value),
-    Ne(&'m dyn Value, &'m dyn Value),
-    Gt(&'m dyn Value, &'m dyn Value),
-    Ge(&'m dyn Value, &'m dyn Value),
-    Lt(&'m dyn Value, &'m dyn Value),
-    Le(&'m dyn Value, &'m dyn Value),
+    Store(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    // result := *<0: ptr>, read as the type of the result
+    Load(&'m dyn Value<'m>),
+
+    // result := <0: val> zero-extended to the type of the result
+    ZExt(&'m dyn Value<'m>),
+
+    Or(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    Xor(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    And(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    LShl(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    LShr(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    AShr(&'m dyn Value<'m>, &'m dyn Value<'m>),
+
+    Eq(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    Ne(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    Gt(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    Ge(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    Lt(&'m dyn Value<'m>, &'m dyn Value<'m>),
+    Le(&'m dyn Value<'m>, &'m dyn Value<'m>),
 
     // result := <0: op0> + <1: op1>
-    Add(&'m dyn Value, &'m dyn Value),
+    Add(&'m dyn Value<'m>, &'m dyn Value<'m>),
     // result := <0: op0> - <1: op1>
-    Sub(&'m dyn Value, &'m dyn Value),
+    Sub(&'m dyn Value<'m>, &'m dyn Value<'m>),
     // result := <0: op0> * <1: op1>
-    Mul(&'m dyn Value, &'m dyn Value),
+    Mul(&'m dyn Value<'m>, &'m dyn Value<'m>),
     // result := <0: op0> / <1: op1>
-    Div(&'m dyn Value, &'m dyn Value),
+    Div(&'m dyn Value<'m>, &'m dyn Value<'m>),
     // result := <0: op0> % <1: op1>
-    Mod(&'m dyn Value, &'m dyn Value),
+    Mod(&'m dyn Value<'m>, &'m dyn Value<'m>),
 
     // goto <0: target>
     Jump(&'m BasicBlock<'m>),
     // if <0: cond> != $0 goto <1: target1> else goto <2: target2>
-    CJump(&'m dyn Value, &'m BasicBlock<'m>, &'m BasicBlock<'m>),
+    CJump(&'m dyn Value<'m>, &'m BasicBlock<'m>, &'m BasicBlock<'m>),
 
     // result := call <0: callee>(<1: args...>)
-    Call(&'m Func<'m>, Vec<&'m dyn Value>),
+    Call(&'m Func<'m>, Vec<&'m dyn Value<'m>>),
     // return <0: val?>
-    Return(Option<&'m dyn Value>),
-}
+    Return(Option<&'m dyn Value<'m>>),
 
-pub struct Inst<'m> {
-    name: String,
-    inst: InstKind<'m>,
+    // result := <val> of the pair whose <block> control came from
+    Phi(Vec<(&'m dyn Value<'m>, &'m BasicBlock<'m>)>),
 }
 
-impl<'m> Inst<'m> {
-    pub fn kind(&self) -> &InstKind<'m> {
-        &self.inst
+impl<'m> InstKind<'m> {
+    pub fn operands(&self) -> Vec<&'m dyn Value<'m>> {
+        match self {
+            InstKind::Alloca(_) | InstKind::Jump(_) | InstKind::Return(None) => vec![],
+            InstKind::Load(op)
+            | InstKind::ZExt(op)
+            | InstKind::CJump(op, _, _)
+            | InstKind::Return(Some(op)) => {
+                vec![*op]
+            }
+            InstKind::Store(op0, op1)
+            | InstKind::Or(op0, op1)
+            | InstKind::Xor(op0, op1)
+            | InstKind::And(op0, op1)
+            | InstKind::LShl(op0, op1)
+            | InstKind::LShr(op0, op1)
+            | InstKind::AShr(op0, op1)
+            | InstKind::Eq(op0, op1)
+            | InstKind::Ne(op0, op1)
+            | InstKind::Gt(op0, op1)
+            | InstKind::Ge(op0, op1)
+            | InstKind::Lt(op0, op1)
+            | InstKind::Le(op0, op1)
+            | InstKind::Add(op0, op1)
+            | InstKind::Sub(op0, op1)
+            | InstKind::Mul(op0, op1)
+            | InstKind::Div(op0, op1)
+            | InstKind::Mod(op0, op1) => vec![*op0, *op1],
+            InstKind::Call(_, args) => args.clone(),
+            InstKind::Phi(incoming) => incoming.iter().map(|(val, _)| *val).collect(),
+        }
     }
 
-    pub fn is_terminator(&self) -> bool {
+    fn operands_mut(&mut self) -> Vec<&mut &'m dyn Value<'m>> {
+        match self {
+            InstKind::Alloca(_) | InstKind::Jump(_) | InstKind::Return(None) => vec![],
+            InstKind::Load(op)
+            | InstKind::ZExt(op)
+            | InstKind::CJump(op, _, _)
+            | InstKind::Return(Some(op)) => {
+                vec![op]
+            }
+            InstKind::Store(op0, op1)
+            | InstKind::Or(op0, op1)
+            | InstKind::Xor(op0, op1)
+            | InstKind::And(op0, op1)
+            | InstKind::LShl(op0, op1)
+            | InstKind::LShr(op0, op1)
+            | InstKind::AShr(op0, op1)
+            | InstKind::Eq(op0, op1)
+            | InstKind::Ne(op0, op1)
+            | InstKind::Gt(op0, op1)
+            | InstKind::Ge(op0, op1)
+            | InstKind::Lt(op0, op1)
+            | InstKind::Le(op0, op1)
+            | InstKind::Add(op0, op1)
+            | InstKind::Sub(op0, op1)
+            | InstKind::Mul(op0, op1)
+            | InstKind::Div(op0, op1)
+            | InstKind::Mod(op0, op1) => vec![op0, op1],
+            InstKind::Call(_, args) => args.iter_mut().collect(),
+            InstKind::Phi(incoming) => incoming.iter_mut().map(|(val, _)| val).collect(),
+        }
+    }
+
+    /// Rewrite the operands and the referenced blocks, including the incoming
+    /// blocks of a phi, e.g. when cloning an instruction into another
+    /// function. The use lists are not touched, so this is meant for kinds
+    /// that are not part of an instruction yet.
+    pub fn remap(
+        &mut self,
+        mut value: impl FnMut(&'m dyn Value<'m>) -> &'m dyn Value<'m>,
+        mut block: impl FnMut(&'m BasicBlock<'m>) -> &'m BasicBlock<'m>,
+    ) {
+        for op in self.operands_mut() {
+            *op = value(*op);
+        }
+        for succ in self.successors_mut() {
+            *succ = block(succ);
+        }
+        if let InstKind::Phi(incoming) = self {
+            for (_, pred) in incoming.iter_mut() {
+                *pred = block(pred);
+            }
+        }
+    }
+
+    /// Whether swapping the operands gives the same result.
+    pub fn is_commutative(&self) -> bool {
         matches!(
-            &self.inst,
-            InstKind::Jump(_) | InstKind::CJump(_, _, _) | InstKind::Return(_)
+            self,
+            InstKind::Or(_, _)
+                | InstKind::Xor(_, _)
+                | InstKind::And(_, _)
+                | InstKind::Eq(_, _)
+                | InstKind::Ne(_, _)
+                | InstKind::Add(_, _)
+                | InstKind::Mul(_, _)
         )
     }
 
-    pub fn alloca(name: String) -> Self {
-        Self {
-            name,
-            inst: InstKind::Alloca,
-        }
+    /// The same operation with its operands swapped, e.g. `lt y, x` for
+    /// `gt x, y`, if there is one.
+    pub fn swapped(&self) -> Option<InstKind<'m>> {
+        let (lhs, rhs) = self.binary_operands()?;
+        let kind = match self {
+            InstKind::Gt(_, _) => InstKind::Lt(rhs, lhs),
+            InstKind::Ge(_, _) => InstKind::Le(rhs, lhs),
+            InstKind::Lt(_, _) => InstKind::Gt(rhs, lhs),
+            InstKind::Le(_, _) => InstKind::Ge(rhs, lhs),
+            _ if self.is_commutative() => {
+                let mut kind = self.clone();
+                let mut ops = kind.operands_mut();
+                *ops[0] = rhs;
+                *ops[1] = lhs;
+                drop(ops);
+                kind
+            }
+            _ => return None,
+        };
+        Some(kind)
     }
 
-    pub fn store(name: String, val: &'m dyn Value, ptr: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Store(val, ptr),
+    /// Operands of an arithmetic, bitwise or comparison instruction.
+    pub fn binary_operands(&self) -> Option<(&'m dyn Value<'m>, &'m dyn Value<'m>)> {
+        match self {
+            InstKind::Or(op0, op1)
+            | InstKind::Xor(op0, op1)
+            | InstKind::And(op0, op1)
+            | InstKind::LShl(op0, op1)
+            | InstKind::LShr(op0, op1)
+            | InstKind::AShr(op0, op1)
+            | InstKind::Eq(op0, op1)
+            | InstKind::Ne(op0, op1)
+            | InstKind::Gt(op0, op1)
+            | InstKind::Ge(op0, op1)
+            | InstKind::Lt(op0, op1)
+            | InstKind::Le(op0, op1)
+            | InstKind::Add(op0, op1)
+            | InstKind::Sub(op0, op1)
+            | InstKind::Mul(op0, op1)
+            | InstKind::Div(op0, op1)
+            | InstKind::Mod(op0, op1) => Some((*op0, *op1)),
+            _ => None,
         }
     }
 
-    pub fn load(name: String, ptr: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Load(ptr),
-        }
+    /// Result of a binary instruction on operand values of type `ty`,
+    /// exactly as the aarch64 backend computes it: arithmetic wraps to the
+    /// width of `ty`, shift amounts are taken modulo 64, comparisons are
+    /// signed, and division by zero yields zero, so that `lhs % 0 == lhs`.
+    pub fn evaluate(&self, ty: Type, lhs: u64, rhs: u64) -> Option<u64> {
+        let (a, b) = (ty.sign_extend(lhs), ty.sign_extend(rhs));
+        let amount = rhs & 63;
+        let value = match self {
+            InstKind::Or(_, _) => lhs | rhs,
+            InstKind::Xor(_, _) => lhs ^ rhs,
+            InstKind::And(_, _) => lhs & rhs,
+            InstKind::LShl(_, _) => lhs << amount,
+            InstKind::LShr(_, _) => lhs >> amount,
+            InstKind::AShr(_, _) => (a >> amount) as u64,
+            InstKind::Eq(_, _) => return Some((a == b) as u64),
+            InstKind::Ne(_, _) => return Some((a != b) as u64),
+            InstKind::Gt(_, _) => return Some((a > b) as u64),
+            InstKind::Ge(_, _) => return Some((a >= b) as u64),
+            InstKind::Lt(_, _) => return Some((a < b) as u64),
+            InstKind::Le(_, _) => return Some((a <= b) as u64),
+            InstKind::Add(_, _) => lhs.wrapping_add(rhs),
+            InstKind::Sub(_, _) => lhs.wrapping_sub(rhs),
+            InstKind::Mul(_, _) => lhs.wrapping_mul(rhs),
+            InstKind::Div(_, _) => sdiv(a, b) as u64,
+            // sdiv followed by msub
+            InstKind::Mod(_, _) => a.wrapping_sub(sdiv(a, b).wrapping_mul(b)) as u64,
+            _ => return None,
+        };
+        Some(ty.truncate(value))
     }
 
-    pub fn or(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Or(op0, op1),
+    pub fn successors(&self) -> Vec<&'m BasicBlock<'m>> {
+        match self {
+            InstKind::Jump(target) => vec![*target],
+            InstKind::CJump(_, target1, target2) => vec![*target1, *target2],
+            _ => vec![],
         }
     }
 
-    pub fn xor(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Xor(op0, op1),
+    fn successors_mut(&mut self) -> Vec<&mut &'m BasicBlock<'m>> {
+        match self {
+            InstKind::Jump(target) => vec![target],
+            InstKind::CJump(_, target1, target2) => vec![target1, target2],
+            _ => vec![],
         }
     }
+}
 
-    pub fn and(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::And(op0, op1),
-        }
+/// Signed division as done by `sdiv`, which does not trap.
+fn sdiv(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        0
+    } else {
+        a.wrapping_div(b)
     }
+}
 
-    pub fn lshl(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
+pub struct Inst<'m> {
+    name: String,
+    // Name taken from the source, uniqued by the function it is placed in
+    debug_name: OnceCell<String>,
+    loc: Cell<Option<DebugLoc>>,
+    ty: Type,
+    inst: RefCell<InstKind<'m>>,
+    parent: RefCell<Option<&'m BasicBlock<'m>>>,
+    uses: UseList<'m>,
+    tail: Cell<bool>,
+}
+
+impl<'m> Inst<'m> {
+    pub fn new(name: String, ty: Type, inst: InstKind<'m>) -> Self {
         Self {
             name,
-            inst: InstKind::LShl(op0, op1),
+            debug_name: OnceCell::new(),
+            loc: Cell::new(None),
+            ty,
+            inst: RefCell::new(inst),
+            parent: RefCell::new(None),
+            uses: UseList::new(),
+            tail: Cell::new(false),
         }
     }
 
-    pub fn lshr(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::LShr(op0, op1),
-        }
+    pub fn kind(&self) -> Ref<'_, InstKind<'m>> {
+        self.inst.borrow()
     }
 
-    pub fn ashr(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::AShr(op0, op1),
-        }
+    pub fn operands(&self) -> Vec<&'m dyn Value<'m>> {
+        self.inst.borrow().operands()
     }
 
-    pub fn eq(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Eq(op0, op1),
+    pub fn successors(&self) -> Vec<&'m BasicBlock<'m>> {
+        self.inst.borrow().successors()
+    }
+
+    pub fn parent(&self) -> Option<&'m BasicBlock<'m>> {
+        *self.parent.borrow()
+    }
+
+    /// The source name of this instruction without the leading `%`, if it
+    /// has one.
+    pub fn debug_name(&self) -> Option<&str> {
+        self.debug_name.get().map(|name| &name[1..])
+    }
+
+    /// Set through `Func::set_value_name`, which makes the name unique.
+    pub(super) fn set_debug_name(&self, name: String) {
+        assert!(
+            self.debug_name.set(format!("%{name}")).is_ok(),
+            "{} is already named",
+            self.name()
+        );
+    }
+
+    pub fn loc(&self) -> Option<DebugLoc> {
+        self.loc.get()
+    }
+
+    pub fn set_loc(&self, loc: Option<DebugLoc>) {
+        self.loc.set(loc);
+    }
+
+    pub(super) fn set_parent(&self, parent: Option<&'m BasicBlock<'m>>) {
+        *self.parent.borrow_mut() = parent;
+    }
+
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            &*self.inst.borrow(),
+            InstKind::Jump(_) | InstKind::CJump(_, _, _) | InstKind::Return(_)
+        )
+    }
+
+    /// Whether this is a call whose result is returned right away, so that
+    /// the caller's frame can be torn down before jumping to the callee.
+    pub fn is_tail_call(&self) -> bool {
+        self.tail.get()
+    }
+
+    pub fn set_tail_call(&self, tail: bool) {
+        self.tail.set(tail);
+    }
+
+    /// Whether removing this instruction could change the behaviour of the
+    /// program even if its result is never used.
+    pub fn has_side_effects(&self) -> bool {
+        match &*self.inst.borrow() {
+            InstKind::Store(_, _) => true,
+            // A pure call may still never return.
+            InstKind::Call(callee, _) => {
+                !callee.has_attribute(Attribute::Pure)
+                    || !callee.has_attribute(Attribute::WillReturn)
+            }
+            _ => self.is_terminator(),
         }
     }
 
-    pub fn ne(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Ne(op0, op1),
+    /// Register this instruction as a user of each of its operands. Called
+    /// once, when the instruction is allocated.
+    pub(super) fn attach(&'m self) {
+        for op in self.operands() {
+            op.use_list().add(self);
         }
     }
 
-    pub fn gt(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Gt(op0, op1),
+    fn detach(&'m self) {
+        for op in self.operands() {
+            op.use_list().remove(self);
         }
     }
 
-    pub fn ge(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Ge(op0, op1),
+    /// Replace the whole instruction, keeping its name and position. Use
+    /// lists of the old and new operands are updated accordingly.
+    pub fn set_kind(&'m self, kind: InstKind<'m>) {
+        self.detach();
+        *self.inst.borrow_mut() = kind;
+        self.attach();
+    }
+
+    pub fn replace_uses_of(&'m self, old: &dyn Value<'m>, new: &'m dyn Value<'m>) {
+        self.replace_operand(old.addr(), new);
+    }
+
+    pub(super) fn replace_operand(&'m self, old: *const (), new: &'m dyn Value<'m>) {
+        let mut inst = self.inst.borrow_mut();
+        for op in inst.operands_mut() {
+            if op.addr() == old {
+                op.use_list().remove(self);
+                new.use_list().add(self);
+                *op = new;
+            }
         }
     }
 
-    pub fn lt(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Lt(op0, op1),
+    pub fn replace_successor(&self, old: &'m BasicBlock<'m>, new: &'m BasicBlock<'m>) {
+        let mut inst = self.inst.borrow_mut();
+        for succ in inst.successors_mut() {
+            if *succ == old {
+                *succ = new;
+            }
         }
     }
 
-    pub fn le(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Le(op0, op1),
+    /// The type stored in the slot of an alloca.
+    pub fn allocated_type(&self) -> Option<Type> {
+        match &*self.inst.borrow() {
+            InstKind::Alloca(ty) => Some(*ty),
+            _ => None,
         }
     }
 
-    pub fn add(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Add(op0, op1),
+    pub fn is_phi(&self) -> bool {
+        matches!(&*self.inst.borrow(), InstKind::Phi(_))
+    }
+
+    /// The value a phi receives when control comes from `block`.
+    pub fn incoming_value(&self, block: &'m BasicBlock<'m>) -> Option<&'m dyn Value<'m>> {
+        match &*self.inst.borrow() {
+            InstKind::Phi(incoming) => incoming.iter().find(|(_, b)| *b == block).map(|(v, _)| *v),
+            _ => panic!("{} is not a phi", self.name()),
         }
     }
 
-    pub fn sub(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Sub(op0, op1),
+    pub fn add_incoming(&'m self, val: &'m dyn Value<'m>, block: &'m BasicBlock<'m>) {
+        match &mut *self.inst.borrow_mut() {
+            InstKind::Phi(incoming) => incoming.push((val, block)),
+            _ => panic!("{} is not a phi", self.name()),
         }
+        val.use_list().add(self);
     }
 
-    pub fn mul(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Mul(op0, op1),
+    pub fn remove_incoming(&'m self, block: &'m BasicBlock<'m>) {
+        let removed = match &mut *self.inst.borrow_mut() {
+            InstKind::Phi(incoming) => {
+                let index = incoming.iter().position(|(_, b)| *b == block);
+                index.map(|i| incoming.remove(i).0)
+            }
+            _ => panic!("{} is not a phi", self.name()),
+        };
+        if let Some(val) = removed {
+            val.use_list().remove(self);
         }
     }
 
-    pub fn div(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Div(op0, op1),
+    pub fn replace_incoming_block(&self, old: &'m BasicBlock<'m>, new: &'m BasicBlock<'m>) {
+        if let InstKind::Phi(incoming) = &mut *self.inst.borrow_mut() {
+            for (_, block) in incoming.iter_mut() {
+                if *block == old {
+                    *block = new;
+                }
+            }
         }
     }
 
-    pub fn modulo(name: String, op0: &'m dyn Value, op1: &'m dyn Value) -> Self {
-        Self {
-            name,
-            inst: InstKind::Mod(op0, op1),
+    /// Insert this detached instruction right before `pos`.
+    pub fn insert_before(&'m self, pos: &'m Inst<'m>) {
+        let block = pos.parent().expect("insertion point is not in a block");
+        let index = block.index_of(pos).unwrap();
+        block.insert_instruction(index, self);
+    }
+
+    /// Insert this detached instruction right after `pos`.
+    pub fn insert_after(&'m self, pos: &'m Inst<'m>) {
+        let block = pos.parent().expect("insertion point is not in a block");
+        let index = block.index_of(pos).unwrap();
+        block.insert_instruction(index + 1, self);
+    }
+
+    pub fn move_before(&'m self, pos: &'m Inst<'m>) {
+        self.remove_from_parent();
+        self.insert_before(pos);
+    }
+
+    /// Unlink this instruction from its block. It keeps its operands and
+    /// can be inserted somewhere else.
+    pub fn remove_from_parent(&'m self) {
+        if let Some(block) = self.parent() {
+            block.remove_instruction(self);
         }
     }
 
+    /// Unlink this instruction from its block and drop its operands. The
+    /// result must not be used anymore.
+    pub fn erase(&'m self) {
+        assert!(
+            !self.has_users(),
+            "Cannot erase {} while it still has users",
+            self.name()
+        );
+        self.remove_from_parent();
+        self.detach();
+    }
+
+    /// Drop the operands of an instruction whose block is being deleted,
+    /// regardless of remaining users in other dead blocks.
+    pub(super) fn drop_operands(&'m self) {
+        self.detach();
+        self.set_parent(None);
+    }
+
+    pub fn alloca(name: String, ty: Type) -> Self {
+        Self::new(name, Type::Ptr, InstKind::Alloca(ty))
+    }
+
+    pub fn store(name: String, val: &'m dyn Value<'m>, ptr: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::Void, InstKind::Store(val, ptr))
+    }
+
+    pub fn load(name: String, ty: Type, ptr: &'m dyn Value<'m>) -> Self {
+        Self::new(name, ty, InstKind::Load(ptr))
+    }
+
+    pub fn zext(name: String, val: &'m dyn Value<'m>, ty: Type) -> Self {
+        Self::new(name, ty, InstKind::ZExt(val))
+    }
+
+    pub fn or(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Or(op0, op1))
+    }
+
+    pub fn xor(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Xor(op0, op1))
+    }
+
+    pub fn and(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::And(op0, op1))
+    }
+
+    pub fn lshl(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::LShl(op0, op1))
+    }
+
+    pub fn lshr(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::LShr(op0, op1))
+    }
+
+    pub fn ashr(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::AShr(op0, op1))
+    }
+
+    pub fn eq(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::I1, InstKind::Eq(op0, op1))
+    }
+
+    pub fn ne(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::I1, InstKind::Ne(op0, op1))
+    }
+
+    pub fn gt(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::I1, InstKind::Gt(op0, op1))
+    }
+
+    pub fn ge(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::I1, InstKind::Ge(op0, op1))
+    }
+
+    pub fn lt(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::I1, InstKind::Lt(op0, op1))
+    }
+
+    pub fn le(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, Type::I1, InstKind::Le(op0, op1))
+    }
+
+    pub fn add(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Add(op0, op1))
+    }
+
+    pub fn sub(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Sub(op0, op1))
+    }
+
+    pub fn mul(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Mul(op0, op1))
+    }
+
+    pub fn div(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Div(op0, op1))
+    }
+
+    pub fn modulo(name: String, op0: &'m dyn Value<'m>, op1: &'m dyn Value<'m>) -> Self {
+        Self::new(name, op0.ty(), InstKind::Mod(op0, op1))
+    }
+
     pub fn jump(name: String, target: &'m BasicBlock<'m>) -> Self {
-        Self {
-            name,
-            inst: InstKind::Jump(target),
-        }
+        Self::new(name, Type::Void, InstKind::Jump(target))
     }
 
     pub fn cjump(
         name: String,
-        cond: &'m dyn Value,
+        cond: &'m dyn Value<'m>,
         target1: &'m BasicBlock<'m>,
         target2: &'m BasicBlock<'m>,
     ) -> Self {
-        Self {
-            name,
-            inst: InstKind::CJump(cond, target1, target2),
-        }
+        Self::new(name, Type::Void, InstKind::CJump(cond, target1, target2))
     }
 
-    pub fn call(name: String, callee: &'m Func<'m>, args: Vec<&'m dyn Value>) -> Self {
-        Self {
-            name,
-            inst: InstKind::Call(callee, args),
-        }
+    pub fn call(name: String, callee: &'m Func<'m>, args: Vec<&'m dyn Value<'m>>) -> Self {
+        Self::new(name, callee.return_type(), InstKind::Call(callee, args))
     }
 
-    pub fn ret(name: String, val: Option<&'m dyn Value>) -> Self {
-        Self {
-            name,
-            inst: InstKind::Return(val),
-        }
+    pub fn ret(name: String, val: Option<&'m dyn Value<'m>>) -> Self {
+        Self::new(name, Type::Void, InstKind::Return(val))
     }
 }
 
-impl Value for Inst<'_> {
+impl<'m> Value<'m> for Inst<'m> {
     fn name(&self) -> &str {
-        &self.name
+        self.debug_name.get().unwrap_or(&self.name)
+    }
+
+    fn ty(&self) -> Type {
+        self.ty
     }
 
     fn is_lvalue(&self) -> bool {
-        matches!(&self.inst, InstKind::Alloca)
+        matches!(&*self.inst.borrow(), InstKind::Alloca(_))
+    }
+
+    fn use_list(&self) -> &UseList<'m> {
+        &self.uses
+    }
+
+    fn as_inst(&self) -> Option<&Inst<'m>> {
+        Some(self)
     }
 }
 
 impl fmt::Display for Inst<'_> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.inst {
-            InstKind::Alloca => write!(f, "{} = alloca", self.name),
+        match &*self.inst.borrow() {
+            InstKind::Alloca(ty) => write!(f, "{} = alloca {}", self.name(), ty),
             InstKind::Store(val, ptr) => {
-                write!(f, "store {}, {}", val.name(), ptr.name())
+                write!(f, "store {} {}, {}", val.ty(), val.name(), ptr.name())
             }
             InstKind::Load(ptr) => {
-                write!(f, "{} = load {}", self.name, ptr.name())
+                write!(f, "{} = load {}, {}", self.name(), self.ty, ptr.name())
+            }
+            InstKind::ZExt(val) => {
+                write!(
+                    f,
+                    "{} = zext {} {} to {}",
+                    self.name(),
+                    val.ty(),
+                    val.name(),
+                    self.ty
+                )
             }
             InstKind::Or(op0, op1) => {
-                write!(f, "{} = or {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = or {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Xor(op0, op1) => {
-                write!(f, "{} = xor {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = xor {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::And(op0, op1) => {
-                write!(f, "{} = and {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = and {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::LShl(op0, op1) => {
-                write!(f, "{} = lshl {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = lshl {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::LShr(op0, op1) => {
-                write!(f, "{} = lshr {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = lshr {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::AShr(op0, op1) => {
-                write!(f, "{} = ashr {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = ashr {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Eq(op0, op1) => {
-                write!(f, "{} = eq {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = eq {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Ne(op0, op1) => {
-                write!(f, "{} = ne {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = ne {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Gt(op0, op1) => {
-                write!(f, "{} = gt {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = gt {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Ge(op0, op1) => {
-                write!(f, "{} = ge {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = ge {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Lt(op0, op1) => {
-                write!(f, "{} = lt {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = lt {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Le(op0, op1) => {
-                write!(f, "{} = le {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = le {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Add(op0, op1) => {
-                write!(f, "{} = add {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = add {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Sub(op0, op1) => {
-                write!(f, "{} = sub {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = sub {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Mul(op0, op1) => {
-                write!(f, "{} = mul {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = mul {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Div(op0, op1) => {
-                write!(f, "{} = div {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = div {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Mod(op0, op1) => {
-                write!(f, "{} = mod {}, {}", self.name, op0.name(), op1.name())
+                write!(
+                    f,
+                    "{} = mod {} {}, {}",
+                    self.name(),
+                    op0.ty(),
+                    op0.name(),
+                    op1.name()
+                )
             }
             InstKind::Jump(target) => write!(f, "jump {}", target.name()),
             InstKind::CJump(cond, target1, target2) => {
                 write!(
                     f,
-                    "cjump {}, {}, {}",
+                    "cjump {} {}, {}, {}",
+                    cond.ty(),
                     cond.name(),
                     target1.name(),
                     target2.name()
                 )
             }
             InstKind::Call(callee, args) => {
-                write!(f, "{} = call @{}(", self.name, callee.name())?;
+                write!(f, "{} = ", self.name())?;
+                if self.is_tail_call() {
+                    write!(f, "tail ")?;
+                }
+                write!(f, "call {} @{}(", self.ty, callee.name())?;
                 for (i, arg) in args.iter().enumerate() {
                     if i > 0 {
                         write!(f, ", ")?;
                     }
-                    write!(f, "{}", arg.name())?;
+                    write!(f, "{} {}", arg.ty(), arg.name())?;
                 }
                 write!(f, ")")
             }
             InstKind::Return(val) => {
                 write!(f, "return")?;
                 if let Some(val) = val {
-                    write!(f, " {}", val.name())?;
+                    write!(f, " {} {}", val.ty(), val.name())?;
+                }
+                Ok(())
+            }
+            InstKind::Phi(incoming) => {
+                write!(f, "{} = phi {} ", self.name(), self.ty)?;
+                for (i, (val, block)) in incoming.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "[{}, {}]", val.name(), block.name())?;
                 }
                 Ok(())
             }
@@ -2,21 +2,32 @@ use std::cell::{Ref, RefCell};
 use std::collections::HashMap;
 use std::fmt;
 
+pub mod analysis;
 mod basicblock;
 mod constant;
 mod context;
+mod debugloc;
 mod func;
 mod inst;
 mod param;
+mod pass;
+mod transform;
+mod ty;
 mod value;
+mod verify;
 
+pub use analysis::CallGraph;
 pub use basicblock::BasicBlock;
 pub use constant::Constant;
 pub use context::Context;
-pub use func::Func;
+pub use debugloc::DebugLoc;
+pub use func::{Attribute, Func};
 pub use inst::{Inst, InstKind};
 pub use param::Param;
-pub use value::Value;
+pub use pass::{FunctionPass, ModulePass, PassManager};
+pub use ty::Type;
+pub use value::{UseList, Value};
+pub use verify::verify_module;
 
 mod codegen;
 pub use codegen::Codegen;
@@ -40,7 +51,7 @@ impl<'m> Module<'m> {
         &self.context
     }
 
-    pub fn functions(&self) -> Ref<Vec<&'m Func<'m>>> {
+    pub fn functions(&self) -> Ref<'_, Vec<&'m Func<'m>>> {
         self.functions.borrow()
     }
 
@@ -55,6 +66,17 @@ impl<'m> Module<'m> {
         funcs.push(func);
     }
 
+    /// Unlink `func` from this module and drop the operands of its
+    /// instructions. Nothing may call it anymore.
+    pub fn remove_function(&self, func: &'m Func<'m>) {
+        self.functions.borrow_mut().retain(|f| *f != func);
+        self.function_table.borrow_mut().remove(func.name());
+        let blocks = func.blocks().clone();
+        for block in blocks {
+            func.erase_block(block);
+        }
+    }
+
     pub fn get_function(&self, name: &str) -> Option<&'m Func<'m>> {
         self.function_table.borrow_mut().get(name).map(|f| &**f)
     }
@@ -76,6 +98,22 @@ impl fmt::Display for Module<'_> {
     }
 }
 
+/// Lower toy source to IR. The module is leaked, as the IR codegen has to
+/// borrow it for as long as it lives.
+#[cfg(test)]
+pub(crate) fn lower_source(src: &str) -> &'static Module<'static> {
+    use crate::frontend;
+
+    let mut parser = frontend::Parser::<frontend::Utf8Decoder<_>, _>::new(src.as_bytes());
+    let mut unit = crate::ast::Module::new();
+    parser.parse(&mut unit);
+
+    let module: &'static Module<'static> = Box::leak(Box::new(Module::new()));
+    let codegen: &'static Codegen<'static> = Box::leak(Box::new(Codegen::new(module)));
+    codegen.visit_unit(&unit);
+    module
+}
+
 impl Default for Module<'_> {
     fn default() -> Self {
         Self::new()
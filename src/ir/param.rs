@@ -1,17 +1,50 @@
-use super::Value;
+use std::cell::OnceCell;
 
-pub struct Param {
+use super::{Type, UseList, Value};
+
+pub struct Param<'m> {
     name: String,
+    // Replaces `name` when another parameter of the function has it
+    unique_name: OnceCell<String>,
+    ty: Type,
+    uses: UseList<'m>,
 }
 
-impl Param {
-    pub fn new(name: String) -> Param {
-        Param { name }
+impl<'m> Param<'m> {
+    pub fn new(name: String, ty: Type) -> Param<'m> {
+        Param {
+            name,
+            unique_name: OnceCell::new(),
+            ty,
+            uses: UseList::new(),
+        }
+    }
+
+    /// Set through `Func::new`, which makes the names of the parameters
+    /// unique.
+    pub(super) fn set_unique_name(&self, name: String) {
+        assert!(
+            self.unique_name.set(format!("%{name}")).is_ok(),
+            "{} is already renamed",
+            self.name
+        );
     }
 }
 
-impl Value for Param {
+impl<'m> Value<'m> for Param<'m> {
     fn name(&self) -> &str {
-        &self.name
+        self.unique_name.get().unwrap_or(&self.name)
+    }
+
+    fn ty(&self) -> Type {
+        self.ty
+    }
+
+    fn use_list(&self) -> &UseList<'m> {
+        &self.uses
+    }
+
+    fn as_param(&self) -> Option<&Param<'m>> {
+        Some(self)
     }
 }
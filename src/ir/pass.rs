@@ -0,0 +1,182 @@
+use super::analysis::AnalysisManager;
+use super::transform;
+use super::verify::Verifier;
+use super::{Func, Module};
+
+/// A transformation applied to one function at a time.
+pub trait FunctionPass<'m> {
+    fn name(&self) -> &'static str;
+
+    /// Run the pass on `func`, returning whether it changed anything.
+    fn run_on_function(&mut self, func: &'m Func<'m>, am: &mut AnalysisManager<'m>) -> bool;
+}
+
+/// A transformation that needs to see the whole module at once.
+pub trait ModulePass<'m> {
+    fn name(&self) -> &'static str;
+
+    /// Run the pass on `module`, returning whether it changed anything.
+    fn run_on_module(&mut self, module: &'m Module<'m>, am: &mut AnalysisManager<'m>) -> bool;
+}
+
+enum Pass<'m> {
+    Function(Box<dyn FunctionPass<'m>>),
+    Module(Box<dyn ModulePass<'m>>),
+}
+
+impl Pass<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Pass::Function(pass) => pass.name(),
+            Pass::Module(pass) => pass.name(),
+        }
+    }
+}
+
+/// Create the pass registered under `name`. Passes taking a parameter are
+/// written `name=value`.
+fn create_pass<'m>(name: &str) -> Option<Pass<'m>> {
+    if let Some(factor) = name.strip_prefix("unroll=") {
+        let factor = factor.parse().ok()?;
+        return Some(Pass::Function(Box::new(transform::Unroll::new(factor))));
+    }
+    let pass = match name {
+        "mem2reg" => Pass::Function(Box::new(transform::Mem2Reg)),
+        "constfold" => Pass::Function(Box::new(transform::ConstFold)),
+        "dce" => Pass::Function(Box::new(transform::Dce)),
+        "function-attrs" => Pass::Module(Box::new(transform::FunctionAttrs)),
+        "globaldce" => Pass::Module(Box::new(transform::GlobalDce)),
+        "gvn" => Pass::Function(Box::new(transform::Gvn)),
+        "inline" => Pass::Module(Box::new(transform::Inliner)),
+        "instcombine" => Pass::Function(Box::new(transform::InstCombine)),
+        "licm" => Pass::Function(Box::new(transform::Licm)),
+        "sccp" => Pass::Function(Box::new(transform::Sccp)),
+        "tailcall" => Pass::Function(Box::new(transform::TailCallElim)),
+        "unroll" => Pass::Function(Box::new(transform::Unroll::default())),
+        "verify" => Pass::Module(Box::new(Verifier)),
+        _ => return None,
+    };
+    Some(pass)
+}
+
+/// Runs a sequence of passes over a module.
+pub struct PassManager<'m> {
+    passes: Vec<Pass<'m>>,
+    print_after_all: bool,
+}
+
+impl<'m> PassManager<'m> {
+    pub fn new() -> PassManager<'m> {
+        PassManager {
+            passes: Vec::new(),
+            print_after_all: false,
+        }
+    }
+
+    /// The standard pipeline for `-O<level>`.
+    pub fn with_opt_level(level: u8) -> PassManager<'m> {
+        let pipeline = match level {
+            0 => "",
+            1 => "mem2reg,tailcall,function-attrs,sccp,instcombine,dce,globaldce",
+            _ => concat!(
+                "mem2reg,sccp,instcombine,function-attrs,dce,inline,globaldce,tailcall,",
+                "gvn,licm,unroll,sccp,instcombine,dce,function-attrs"
+            ),
+        };
+        Self::from_pipeline(pipeline).unwrap()
+    }
+
+    /// Build a pass manager from a comma-separated list of pass names, as
+    /// given to `--passes`.
+    pub fn from_pipeline(pipeline: &str) -> Result<PassManager<'m>, String> {
+        let mut pm = PassManager::new();
+        for name in pipeline.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match create_pass(name) {
+                Some(pass) => pm.passes.push(pass),
+                None => return Err(format!("unknown pass '{}'", name)),
+            }
+        }
+        Ok(pm)
+    }
+
+    /// Dump the module to stderr after every pass.
+    pub fn set_print_after_all(&mut self, print: bool) {
+        self.print_after_all = print;
+    }
+
+    /// Run every pass in order, returning whether any of them changed the
+    /// module.
+    pub fn run(&mut self, module: &'m Module<'m>) -> bool {
+        let mut am = AnalysisManager::new();
+        let mut changed = false;
+        for pass in self.passes.iter_mut() {
+            let name = pass.name();
+            match pass {
+                Pass::Function(pass) => {
+                    let funcs = module.functions().clone();
+                    for func in funcs {
+                        if pass.run_on_function(func, &mut am) {
+                            am.invalidate(func);
+                            changed = true;
+                        }
+                        debug_assert_eq!(
+                            super::verify::verify_function(func),
+                            Ok(()),
+                            "after {}",
+                            name
+                        );
+                    }
+                }
+                Pass::Module(pass) => {
+                    if pass.run_on_module(module, &mut am) {
+                        am.invalidate_all();
+                        changed = true;
+                    }
+                    debug_assert_eq!(super::verify_module(module), Ok(()), "after {}", name);
+                }
+            }
+            if self.print_after_all {
+                eprintln!("*** IR Dump After {} ***", name);
+                eprint!("{}", module);
+            }
+        }
+        changed
+    }
+}
+
+impl Default for PassManager<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PassManager;
+    use crate::ir::lower_source;
+
+    #[test]
+    fn parse_pipeline() {
+        assert!(PassManager::from_pipeline("").is_ok());
+        assert!(PassManager::from_pipeline("mem2reg, verify").is_ok());
+        assert_eq!(
+            PassManager::from_pipeline("mem2reg,foo").err(),
+            Some(String::from("unknown pass 'foo'"))
+        );
+    }
+
+    #[test]
+    fn opt_levels() {
+        let src = r"func f(n: Int64) : Int64 {
+    var x: Int64 = n + 1;
+    return x;
+}
+";
+        assert!(!PassManager::with_opt_level(0).run(lower_source(src)));
+        assert!(PassManager::with_opt_level(1).run(lower_source(src)));
+        // Nothing left to promote the second time around.
+        let module = lower_source(src);
+        assert!(PassManager::with_opt_level(2).run(module));
+        assert!(!PassManager::with_opt_level(2).run(module));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::ir::analysis::AnalysisManager;
+use crate::ir::pass::FunctionPass;
+use crate::ir::{Func, Inst, InstKind, Value};
+
+/// Fold instructions whose operands are all constants, and turn conditional
+/// jumps on a constant into plain jumps. Blocks that become unreachable are
+/// left for `dce` to remove.
+pub struct ConstFold;
+
+impl<'m> FunctionPass<'m> for ConstFold {
+    fn name(&self) -> &'static str {
+        "constfold"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let mut changed = false;
+        let mut worklist = func.instructions();
+        worklist.reverse();
+
+        while let Some(inst) = worklist.pop() {
+            // Skip instructions erased since they were queued.
+            if inst.parent().is_none() {
+                continue;
+            }
+            if let Some(value) = fold(inst) {
+                let constant = func.constant(inst.ty(), value);
+                let users = inst.users().clone();
+                inst.replace_all_uses_with(constant);
+                inst.erase();
+                worklist.extend(users);
+                changed = true;
+            } else if fold_branch(inst) {
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// The constant value of `inst`, if it can be computed at compile time.
+fn fold(inst: &Inst<'_>) -> Option<u64> {
+    let kind = inst.kind();
+    if let InstKind::Phi(incoming) = &*kind {
+        // A phi merging the same constant along every edge, ignoring the
+        // edges where it feeds itself.
+        let mut values = incoming
+            .iter()
+            .filter(|(val, _)| val.addr() != inst.addr())
+            .map(|(val, _)| val.as_constant().map(|c| c.value()));
+        let first = values.next()??;
+        return values.all(|v| v == Some(first)).then_some(first);
+    }
+
+    // Constants are kept zero-extended, so widening one does not change it.
+    if let InstKind::ZExt(val) = &*kind {
+        return val.as_constant().map(|c| c.value());
+    }
+
+    let (lhs, rhs) = kind.binary_operands()?;
+    let ty = lhs.ty();
+    let lhs = lhs.as_constant()?.value();
+    let rhs = rhs.as_constant()?.value();
+    kind.evaluate(ty, lhs, rhs)
+}
+
+/// Replace a conditional jump on a constant with a jump to the taken target.
+pub(super) fn fold_branch<'m>(inst: &'m Inst<'m>) -> bool {
+    let (taken, dropped) = match &*inst.kind() {
+        InstKind::CJump(cond, ifbb, elsebb) => match cond.as_constant() {
+            Some(c) if c.value() != 0 => (*ifbb, *elsebb),
+            Some(_) => (*elsebb, *ifbb),
+            None => return false,
+        },
+        _ => return false,
+    };
+    if taken != dropped {
+        dropped.remove_predecessor(inst.parent().unwrap());
+    }
+    inst.set_kind(InstKind::Jump(taken));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, InstKind, PassManager, Type};
+
+    fn instructions(src: &str, pipeline: &str) -> Vec<String> {
+        let module = lower_source(src);
+        PassManager::from_pipeline(pipeline).unwrap().run(module);
+        verify_module(module).unwrap();
+        let func = module.functions()[0];
+        func.instructions()
+            .iter()
+            .filter(|inst| !matches!(&*inst.kind(), InstKind::Jump(_)))
+            .map(|inst| inst.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn evaluate_like_the_backend() {
+        let min = i64::MIN as u64;
+        let neg1 = u64::MAX;
+        let x = crate::ir::Module::new();
+        let c = x.context().new_constant(Type::I64, 0);
+        let eval = |kind: InstKind, a: u64, b: u64| kind.evaluate(Type::I64, a, b).unwrap();
+
+        assert_eq!(eval(InstKind::Add(c, c), neg1, 2), 1);
+        assert_eq!(eval(InstKind::Sub(c, c), 0, 5), (-5i64) as u64);
+        assert_eq!(eval(InstKind::Mul(c, c), min, neg1), min);
+        assert_eq!(eval(InstKind::Div(c, c), 7, 0), 0);
+        assert_eq!(eval(InstKind::Div(c, c), (-7i64) as u64, 2), (-3i64) as u64);
+        assert_eq!(eval(InstKind::Div(c, c), min, neg1), min);
+        assert_eq!(eval(InstKind::Mod(c, c), 7, 0), 7);
+        assert_eq!(eval(InstKind::Mod(c, c), (-7i64) as u64, 2), neg1);
+        assert_eq!(eval(InstKind::Mod(c, c), min, neg1), 0);
+        assert_eq!(eval(InstKind::LShl(c, c), 1, 65), 2);
+        assert_eq!(eval(InstKind::LShr(c, c), neg1, 63), 1);
+        assert_eq!(eval(InstKind::AShr(c, c), min, 63), neg1);
+        assert_eq!(eval(InstKind::Lt(c, c), neg1, 0), 1);
+        assert_eq!(eval(InstKind::Gt(c, c), neg1, 0), 0);
+        assert_eq!(eval(InstKind::Xor(c, c), 5, neg1), !5);
+
+        // Narrow integers wrap to their width and compare as signed.
+        let eval8 = |kind: InstKind, a: u64, b: u64| kind.evaluate(Type::I8, a, b).unwrap();
+        assert_eq!(eval8(InstKind::Add(c, c), 0xff, 2), 1);
+        assert_eq!(eval8(InstKind::Lt(c, c), 0xff, 0), 1);
+        assert_eq!(eval8(InstKind::Div(c, c), 0xfe, 2), 0xff);
+        assert_eq!(eval8(InstKind::AShr(c, c), 0x80, 7), 0xff);
+    }
+
+    #[test]
+    fn fold_expressions() {
+        let src = r"func f() : Int64 {
+    return ((1 << 4) - 1) + -5 * ~0;
+}
+";
+        assert_eq!(instructions(src, "constfold"), vec!["return i64 $20"]);
+    }
+
+    #[test]
+    fn fold_branches_and_phis() {
+        let src = r"func f(n: Int64) : Int64 {
+    var x: Int64 = 1;
+    if 2 > 1 {
+        x = 3;
+    }
+    return x * 2;
+}
+";
+        // The edge around the `if` is dropped, leaving the phi for `x` with
+        // a single incoming value.
+        assert_eq!(
+            instructions(src, "mem2reg,constfold"),
+            vec!["return i64 $6"]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+
+use crate::ir::analysis::{reverse_post_order, AnalysisManager};
+use crate::ir::pass::FunctionPass;
+use crate::ir::{BasicBlock, Func, Inst, InstKind};
+
+/// Delete unreachable blocks, fold away blocks that only jump elsewhere, and
+/// remove instructions whose results are never used.
+pub struct Dce;
+
+impl<'m> FunctionPass<'m> for Dce {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let mut changed = false;
+        loop {
+            let simplified = remove_unreachable_blocks(func)
+                | fold_trivial_branches(func)
+                | remove_forwarding_blocks(func)
+                | merge_blocks(func);
+            if !simplified {
+                break;
+            }
+            changed = true;
+        }
+        remove_dead_instructions(func) || changed
+    }
+}
+
+#[allow(clippy::mutable_key_type)]
+pub(super) fn remove_unreachable_blocks(func: &Func<'_>) -> bool {
+    let reachable: HashSet<_> = reverse_post_order(func).into_iter().collect();
+    let dead: Vec<_> = func
+        .blocks()
+        .iter()
+        .filter(|block| !reachable.contains(*block))
+        .copied()
+        .collect();
+    for block in dead.iter() {
+        for succ in block.successors() {
+            if reachable.contains(&succ) {
+                succ.remove_predecessor(block);
+            }
+        }
+    }
+    for block in dead.iter() {
+        func.erase_block(block);
+    }
+    !dead.is_empty()
+}
+
+/// Turn `cjump %c, bb, bb` into `jump bb`.
+fn fold_trivial_branches(func: &Func<'_>) -> bool {
+    let mut changed = false;
+    for block in func.blocks().iter() {
+        let term = block.terminator().unwrap();
+        let target = match &*term.kind() {
+            InstKind::CJump(_, ifbb, elsebb) if ifbb == elsebb => *ifbb,
+            _ => continue,
+        };
+        term.set_kind(InstKind::Jump(target));
+        changed = true;
+    }
+    changed
+}
+
+/// Redirect the predecessors of blocks consisting of a single jump to the
+/// jump target.
+fn remove_forwarding_blocks<'m>(func: &Func<'m>) -> bool {
+    let mut changed = false;
+    let blocks = func.blocks().clone();
+    for block in blocks.into_iter().skip(1) {
+        let Some(target) = forwarding_target(block) else {
+            continue;
+        };
+        let preds = block.predecessors();
+        // A predecessor already branching to the target might need a
+        // different value for the phis there.
+        let target_preds = target.predecessors();
+        if !target.phis().is_empty() && preds.iter().any(|p| target_preds.contains(p)) {
+            continue;
+        }
+
+        for phi in target.phis() {
+            let val = phi.incoming_value(block).unwrap();
+            phi.remove_incoming(block);
+            for pred in preds.iter() {
+                phi.add_incoming(val, pred);
+            }
+        }
+        for pred in preds {
+            pred.terminator().unwrap().replace_successor(block, target);
+        }
+        func.erase_block(block);
+        changed = true;
+    }
+    changed
+}
+
+fn forwarding_target<'m>(block: &BasicBlock<'m>) -> Option<&'m BasicBlock<'m>> {
+    let insts = block.instructions();
+    match insts.as_slice() {
+        [inst] => match &*inst.kind() {
+            InstKind::Jump(target) if !std::ptr::eq(*target, block) => Some(*target),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn merge_blocks(func: &Func<'_>) -> bool {
+    let mut changed = false;
+    let blocks = func.blocks().clone();
+    for block in blocks {
+        changed |= func.merge_into_predecessor(block);
+    }
+    changed
+}
+
+/// Mark the instructions that side effects depend on and delete the rest.
+/// Unlike deleting unused instructions one by one, this also catches cycles
+/// of phis only feeding each other.
+fn remove_dead_instructions<'m>(func: &Func<'m>) -> bool {
+    let insts = func.instructions();
+    let mut live: HashSet<*const Inst<'m>> = HashSet::new();
+    let mut worklist: Vec<&'m Inst<'m>> = insts
+        .iter()
+        .filter(|inst| inst.has_side_effects())
+        .copied()
+        .collect();
+    while let Some(inst) = worklist.pop() {
+        if !live.insert(inst) {
+            continue;
+        }
+        worklist.extend(inst.operands().iter().filter_map(|op| op.as_inst()));
+    }
+
+    let dead: Vec<_> = insts
+        .into_iter()
+        .filter(|inst| !live.contains(&(*inst as *const _)))
+        .collect();
+    for inst in dead.iter() {
+        inst.remove_from_parent();
+    }
+    for inst in dead.iter() {
+        inst.drop_operands();
+    }
+    !dead.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, PassManager};
+
+    fn optimize(src: &str, pipeline: &str) -> String {
+        let module = lower_source(src);
+        PassManager::from_pipeline(pipeline).unwrap().run(module);
+        verify_module(module).unwrap();
+        module.functions()[0].to_string()
+    }
+
+    #[test]
+    fn remove_exit_block_and_unused_loads() {
+        let src = r"func f(n: Int64) : Int64 {
+    n;
+    if n {
+        return 1;
+    } else {
+        return 2;
+    }
+}
+";
+        let ir = optimize(src, "mem2reg,dce");
+        assert_eq!(ir.matches(":\n").count(), 3, "{}", ir);
+        assert!(!ir.contains("load"), "{}", ir);
+    }
+
+    #[test]
+    fn fold_constant_branches() {
+        let src = r"func f(n: Int64) : Int64 {
+    var x: Int64 = n;
+    while 0 {
+        x = x + 1;
+    }
+    if 1 {
+        x = x * 2;
+    }
+    return x;
+}
+";
+        let ir = optimize(src, "mem2reg,constfold,dce");
+        // Everything ends up in the entry block.
+        assert_eq!(ir.matches(":\n").count(), 1, "{}", ir);
+        assert!(!ir.contains("phi"), "{}", ir);
+    }
+
+    #[test]
+    fn keep_phis_of_forwarding_blocks() {
+        let src = r"func f(n: Int64) : Int64 {
+    var x: Int64 = 1;
+    if n {
+        if n - 1 {
+            x = 2;
+        }
+    } else {
+        x = 3;
+    }
+    return x;
+}
+";
+        let ir = optimize(src, "mem2reg,dce");
+        assert!(ir.contains("phi"), "{}", ir);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use crate::ir::analysis::{reverse_post_order, AnalysisManager};
+use crate::ir::pass::ModulePass;
+use crate::ir::{Attribute, CallGraph, Func, Inst, InstKind, Module};
+
+/// Infer `pure`, `nocalls`, `leaf`, `norecurse` and `willreturn` for every
+/// function, dropping the ones that no longer hold. Functions are visited
+/// callees first, and the members of a recursive cycle are pure only if all
+/// of them are.
+pub struct FunctionAttrs;
+
+impl<'m> ModulePass<'m> for FunctionAttrs {
+    fn name(&self) -> &'static str {
+        "function-attrs"
+    }
+
+    fn run_on_module(&mut self, module: &'m Module<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let call_graph = CallGraph::new(module);
+        let mut changed = false;
+        for scc in call_graph.sccs() {
+            let pure = scc
+                .iter()
+                .all(|func| func.instructions().iter().all(|inst| is_pure(inst, scc)));
+            for func in scc {
+                let calls: Vec<_> = func
+                    .instructions()
+                    .into_iter()
+                    .filter(|inst| matches!(&*inst.kind(), InstKind::Call(_, _)))
+                    .collect();
+                let leaf = calls.iter().all(|call| call.is_tail_call());
+                changed |= update_attribute(func, Attribute::Pure, pure);
+                changed |= update_attribute(func, Attribute::NoCalls, calls.is_empty());
+                changed |= update_attribute(func, Attribute::Leaf, leaf);
+                let recursive = call_graph.is_recursive(func);
+                changed |= update_attribute(func, Attribute::NoRecurse, !recursive);
+                let will_return = !recursive
+                    && !has_cycle(func)
+                    && calls.iter().all(|call| match &*call.kind() {
+                        InstKind::Call(callee, _) => callee.has_attribute(Attribute::WillReturn),
+                        _ => unreachable!(),
+                    });
+                changed |= update_attribute(func, Attribute::WillReturn, will_return);
+            }
+        }
+        changed
+    }
+}
+
+/// Whether `inst` leaves memory other than the stack slots of its function
+/// alone, calling only functions of `scc` or functions known to be pure.
+fn is_pure<'m>(inst: &Inst<'m>, scc: &[&'m Func<'m>]) -> bool {
+    match &*inst.kind() {
+        InstKind::Load(ptr) | InstKind::Store(_, ptr) => ptr
+            .as_inst()
+            .is_some_and(|ptr| ptr.allocated_type().is_some()),
+        InstKind::Call(callee, _) => scc.contains(callee) || callee.has_attribute(Attribute::Pure),
+        _ => true,
+    }
+}
+
+/// Whether control can come back to a block of `func` it has already been
+/// through. In reverse post-order, only edges of a cycle go backwards.
+#[allow(clippy::mutable_key_type)]
+fn has_cycle(func: &Func<'_>) -> bool {
+    let order = reverse_post_order(func);
+    let index: HashMap<_, _> = order.iter().enumerate().map(|(i, b)| (*b, i)).collect();
+    order.iter().enumerate().any(|(i, block)| {
+        block
+            .successors()
+            .iter()
+            .any(|succ| index.get(succ).is_some_and(|j| *j <= i))
+    })
+}
+
+/// Add or remove `attr`, returning whether that changed anything.
+fn update_attribute(func: &Func<'_>, attr: Attribute, holds: bool) -> bool {
+    if func.has_attribute(attr) == holds {
+        return false;
+    }
+    if holds {
+        func.add_attribute(attr);
+    } else {
+        func.remove_attribute(attr);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, Attribute, CallGraph, PassManager, Type};
+
+    #[test]
+    fn infer_attributes() {
+        let module = lower_source(
+            r"func square(x: Int64) : Int64 {
+    var y: Int64 = x * x;
+    return y;
+}
+
+func fact(n: Int64) : Int64 {
+    if n < 2 {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+
+func g(n: Int64) : Int64 {
+    return square(n);
+}
+
+func f(n: Int64) : Int64 {
+    return square(n) + fact(n);
+}
+",
+        );
+        let call_graph = CallGraph::new(module);
+        let [square, fact, g, f] = module.functions()[..] else {
+            panic!("expected four functions");
+        };
+        assert!(call_graph.is_recursive(fact));
+        assert!(!call_graph.is_recursive(square) && !call_graph.is_recursive(f));
+
+        assert!(PassManager::from_pipeline("function-attrs")
+            .unwrap()
+            .run(module));
+        for func in [square, fact, g, f] {
+            assert!(func.has_attribute(Attribute::Pure), "{}", func);
+        }
+        assert!(square.has_attribute(Attribute::NoCalls));
+        assert!(square.has_attribute(Attribute::Leaf));
+        assert!(!fact.has_attribute(Attribute::NoCalls));
+        assert!(!fact.has_attribute(Attribute::Leaf));
+        assert!(!g.has_attribute(Attribute::Leaf));
+        assert!(!fact.has_attribute(Attribute::NoRecurse));
+        assert!(f.has_attribute(Attribute::NoRecurse));
+        assert!(square.has_attribute(Attribute::WillReturn));
+        assert!(g.has_attribute(Attribute::WillReturn));
+        assert!(!fact.has_attribute(Attribute::WillReturn));
+        assert!(!f.has_attribute(Attribute::WillReturn));
+
+        // Only tail calls are left in `g` once they are marked as such.
+        assert!(PassManager::from_pipeline("tailcall,function-attrs")
+            .unwrap()
+            .run(module));
+        assert!(g.has_attribute(Attribute::Leaf));
+        assert!(!g.has_attribute(Attribute::NoCalls));
+        assert!(!f.has_attribute(Attribute::Leaf));
+        assert!(f
+            .to_string()
+            .starts_with("define i64 @f(i64 %n) pure norecurse {"));
+
+        // Storing through a pointer parameter is a side effect, so is calling
+        // a function that does.
+        let ctx = module.context();
+        let p = ctx.new_parameter(String::from("p"), Type::Ptr);
+        let set = ctx.new_function(String::from("set"), vec![p], Type::Void);
+        module.add_function(set);
+        set.add_instruction(ctx.store(set.constant(Type::I64, 1), p));
+        set.add_instruction(ctx.ret(None));
+        let q = ctx.new_parameter(String::from("q"), Type::Ptr);
+        let h = ctx.new_function(String::from("h"), vec![q], Type::Void);
+        module.add_function(h);
+        h.add_instruction(ctx.call(set, vec![q]));
+        h.add_instruction(ctx.ret(None));
+        assert!(PassManager::from_pipeline("function-attrs")
+            .unwrap()
+            .run(module));
+        assert!(!set.has_attribute(Attribute::Pure) && set.has_attribute(Attribute::NoCalls));
+        assert!(!h.has_attribute(Attribute::Pure));
+    }
+
+    #[test]
+    fn keep_calls_that_may_not_return() {
+        let module = lower_source(
+            r"func spin(n: Int64) : Int64 {
+    while n {
+        n = n + 0;
+    }
+    return 0;
+}
+
+func square(x: Int64) : Int64 {
+    return x * x;
+}
+
+func f(n: Int64) : Int64 {
+    spin(n);
+    square(n);
+    return 1;
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,function-attrs,dce")
+            .unwrap()
+            .run(module);
+        let [spin, square, f] = module.functions()[..] else {
+            panic!("expected three functions");
+        };
+        assert!(spin.has_attribute(Attribute::Pure));
+        assert!(!spin.has_attribute(Attribute::WillReturn));
+        assert!(square.has_attribute(Attribute::WillReturn));
+
+        // Only the call to `square` is deleted, as `spin` may loop forever.
+        let ir = f.to_string();
+        assert!(ir.contains("call i64 @spin"), "{}", ir);
+        assert!(!ir.contains("@square"), "{}", ir);
+    }
+}
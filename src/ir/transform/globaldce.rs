@@ -0,0 +1,79 @@
+use crate::ir::analysis::AnalysisManager;
+use crate::ir::pass::ModulePass;
+use crate::ir::{Attribute, CallGraph, Module};
+
+/// Delete `@internal` functions that cannot be reached through calls from
+/// any exported function.
+pub struct GlobalDce;
+
+impl<'m> ModulePass<'m> for GlobalDce {
+    fn name(&self) -> &'static str {
+        "globaldce"
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    fn run_on_module(&mut self, module: &'m Module<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let call_graph = CallGraph::new(module);
+        let funcs = module.functions().clone();
+        let live = call_graph.reachable_from(
+            funcs
+                .iter()
+                .copied()
+                .filter(|func| !func.has_attribute(Attribute::Internal)),
+        );
+        let dead: Vec<_> = funcs
+            .into_iter()
+            .filter(|func| !live.contains(func))
+            .collect();
+        for func in dead.iter() {
+            module.remove_function(func);
+        }
+        !dead.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, PassManager};
+
+    #[test]
+    fn remove_unreachable_internal_functions() {
+        let module = lower_source(
+            r"@internal func helper(x: Int64) : Int64 {
+    return x + 1;
+}
+
+@internal func unused(x: Int64) : Int64 {
+    return helper(x) * 2;
+}
+
+@internal @inline func inlined(x: Int64) : Int64 {
+    return helper(x) - 1;
+}
+
+func f(x: Int64) : Int64 {
+    return inlined(x);
+}
+
+func g(x: Int64) : Int64 {
+    return x;
+}
+",
+        );
+        assert!(PassManager::from_pipeline("globaldce").unwrap().run(module));
+        verify_module(module).unwrap();
+        let names: Vec<_> = module.functions().iter().map(|f| f.name()).collect();
+        assert_eq!(names, ["helper", "inlined", "f", "g"]);
+        assert!(module.get_function("unused").is_none());
+        assert!(module
+            .to_string()
+            .contains("define i64 @helper(i64 %x) internal {"));
+
+        // Nothing calls the internal functions once they are inlined.
+        assert!(PassManager::from_pipeline("inline,globaldce")
+            .unwrap()
+            .run(module));
+        let names: Vec<_> = module.functions().iter().map(|f| f.name()).collect();
+        assert_eq!(names, ["f", "g"]);
+    }
+}
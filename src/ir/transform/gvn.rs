@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::mem::Discriminant;
+
+use crate::ir::analysis::{AnalysisManager, DominatorTree};
+use crate::ir::pass::FunctionPass;
+use crate::ir::{BasicBlock, Func, Inst, InstKind, Value};
+
+/// Global value numbering: an instruction computing the same operation on
+/// the same operands as an instruction dominating it is replaced by the
+/// earlier one.
+///
+/// Loads are only reused within a block. A store replaces the known value
+/// of its pointer, and a call forgets every known value.
+pub struct Gvn;
+
+impl<'m> FunctionPass<'m> for Gvn {
+    fn name(&self) -> &'static str {
+        "gvn"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, am: &mut AnalysisManager<'m>) -> bool {
+        let dom = am.dominators(func);
+        let mut state = State {
+            dom: &dom,
+            exprs: HashMap::new(),
+            changed: false,
+        };
+        state.visit(dom.entry());
+        state.changed
+    }
+}
+
+/// An operation and the identities of its operands.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Expr<'m> {
+    op: Discriminant<InstKind<'m>>,
+    lhs: *const (),
+    rhs: *const (),
+}
+
+impl<'m> Expr<'m> {
+    fn new(inst: &Inst<'m>) -> Option<Expr<'m>> {
+        let kind = inst.kind();
+        let (lhs, rhs) = kind.binary_operands()?;
+        let (mut lhs, mut rhs) = (lhs.addr(), rhs.addr());
+        if kind.is_commutative() && lhs > rhs {
+            std::mem::swap(&mut lhs, &mut rhs);
+        }
+        Some(Expr {
+            op: std::mem::discriminant(&*kind),
+            lhs,
+            rhs,
+        })
+    }
+}
+
+struct State<'a, 'm> {
+    dom: &'a DominatorTree<'m>,
+    /// Expressions available in the current block, i.e. computed in one of
+    /// the blocks dominating it.
+    exprs: HashMap<Expr<'m>, &'m Inst<'m>>,
+    changed: bool,
+}
+
+impl<'m> State<'_, 'm> {
+    fn visit(&mut self, block: &'m BasicBlock<'m>) {
+        let mut added = vec![];
+        // Known contents of each alloca, valid up to the end of this block.
+        let mut memory: HashMap<*const (), &'m dyn Value<'m>> = HashMap::new();
+
+        let insts = block.instructions().clone();
+        for inst in insts {
+            let known = match &*inst.kind() {
+                InstKind::Store(val, ptr) => {
+                    memory.insert(ptr.addr(), *val);
+                    continue;
+                }
+                InstKind::Call(_, _) => {
+                    memory.clear();
+                    continue;
+                }
+                InstKind::Load(ptr) => match memory.get(&ptr.addr()) {
+                    Some(val) => Some(*val),
+                    None => {
+                        memory.insert(ptr.addr(), inst);
+                        None
+                    }
+                },
+                _ => {
+                    let Some(expr) = Expr::new(inst) else {
+                        continue;
+                    };
+                    match self.exprs.get(&expr) {
+                        Some(prev) => Some(*prev as &dyn Value<'m>),
+                        None => {
+                            self.exprs.insert(expr, inst);
+                            added.push(expr);
+                            None
+                        }
+                    }
+                }
+            };
+            if let Some(val) = known {
+                inst.replace_all_uses_with(val);
+                inst.erase();
+                self.changed = true;
+            }
+        }
+
+        for child in self.dom.children(block) {
+            self.visit(child);
+        }
+        for expr in added {
+            self.exprs.remove(&expr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, PassManager};
+
+    fn optimize(src: &str, pipeline: &str) -> String {
+        let module = lower_source(src);
+        PassManager::from_pipeline(pipeline).unwrap().run(module);
+        verify_module(module).unwrap();
+        module.functions()[0].to_string()
+    }
+
+    #[test]
+    fn reuse_dominating_expressions() {
+        let src = r"func f(a: Int64, b: Int64) : Int64 {
+    var x: Int64 = (a | b) + b * a;
+    if x {
+        x = x + (a | b) + a * b;
+    } else {
+        x = x - (b | a);
+    }
+    return x;
+}
+";
+        let ir = optimize(src, "mem2reg,gvn");
+        assert_eq!(ir.matches(" or ").count(), 1, "{}", ir);
+        assert_eq!(ir.matches(" mul ").count(), 1, "{}", ir);
+    }
+
+    #[test]
+    fn keep_expressions_of_other_branches() {
+        let src = r"func f(a: Int64) : Int64 {
+    if a {
+        return a * 3;
+    }
+    return a * 3;
+}
+";
+        let ir = optimize(src, "mem2reg,gvn");
+        assert_eq!(ir.matches(" mul ").count(), 2, "{}", ir);
+    }
+
+    #[test]
+    fn forward_loads_within_blocks() {
+        let src = r"func g() : Int64 {
+    return 1;
+}
+func f(a: Int64) : Int64 {
+    var x: Int64 = a;
+    x = x + x;
+    var y: Int64 = x * 2;
+    g();
+    return x + y;
+}
+";
+        let module = lower_source(src);
+        PassManager::from_pipeline("gvn").unwrap().run(module);
+        verify_module(module).unwrap();
+        let ir = module.get_function("f").unwrap().to_string();
+        // Only the loads after the call are left.
+        assert_eq!(ir.matches(" load ").count(), 2, "{}", ir);
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+
+use crate::ir::analysis::{reverse_post_order, AnalysisManager, CallGraph};
+use crate::ir::pass::ModulePass;
+use crate::ir::{Attribute, BasicBlock, Func, Inst, InstKind, Module, Value};
+
+/// Callees with at most this many instructions are inlined.
+const INLINE_THRESHOLD: usize = 32;
+
+/// Threshold for callees that make no calls. Their body is all there is to
+/// copy, and the call they replace is usually what keeps the caller from
+/// being a leaf itself.
+const NO_CALLS_INLINE_THRESHOLD: usize = 48;
+
+/// Replace calls to small functions, and to functions marked `@inline`, with
+/// a copy of the callee's body. Functions are visited callees first, so a
+/// callee has already absorbed its own callees when its size is measured.
+/// Calls within a recursive cycle are never inlined.
+pub struct Inliner;
+
+impl<'m> ModulePass<'m> for Inliner {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run_on_module(&mut self, module: &'m Module<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let call_graph = CallGraph::new(module);
+        let mut changed = false;
+        for scc in call_graph.sccs() {
+            for caller in scc {
+                // Calls copied in by inlining are not considered again, which
+                // bounds the growth of a caller.
+                let calls: Vec<_> = caller
+                    .instructions()
+                    .into_iter()
+                    .filter(|inst| matches!(&*inst.kind(), InstKind::Call(_, _)))
+                    .collect();
+                for call in calls {
+                    let InstKind::Call(callee, _) = &*call.kind() else {
+                        unreachable!();
+                    };
+                    let callee = *callee;
+                    if should_inline(&call_graph, caller, callee) {
+                        inline_call(caller, call, callee);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+}
+
+fn should_inline<'m>(
+    call_graph: &CallGraph<'m>,
+    caller: &'m Func<'m>,
+    callee: &'m Func<'m>,
+) -> bool {
+    if call_graph.in_same_scc(caller, callee) || callee.has_attribute(Attribute::NoInline) {
+        return false;
+    }
+    let threshold = if callee.has_attribute(Attribute::NoCalls) {
+        NO_CALLS_INLINE_THRESHOLD
+    } else {
+        INLINE_THRESHOLD
+    };
+    callee.has_attribute(Attribute::Inline) || cost(callee) <= threshold
+}
+
+/// Size of a function, not counting instructions that usually disappear
+/// once it is inlined.
+fn cost(func: &Func<'_>) -> usize {
+    func.instructions()
+        .iter()
+        .filter(|inst| {
+            !matches!(
+                &*inst.kind(),
+                InstKind::Alloca(_) | InstKind::Phi(_) | InstKind::Jump(_) | InstKind::Return(_)
+            )
+        })
+        .count()
+}
+
+/// Splice a copy of `callee` into `caller` in place of `call`.
+#[allow(clippy::mutable_key_type)]
+fn inline_call<'m>(caller: &'m Func<'m>, call: &'m Inst<'m>, callee: &'m Func<'m>) {
+    let ctx = caller.context();
+    let block = call.parent().unwrap();
+    // Everything from the call onwards moves to `tail`, where the inlined
+    // returns meet.
+    let tail = caller.split_block(block, call);
+
+    let mut values: HashMap<*const (), &'m dyn Value<'m>> = HashMap::new();
+    for (param, arg) in callee.params().iter().zip(call.operands()) {
+        values.insert(param.addr(), arg);
+    }
+    for constant in callee.constants().iter() {
+        values.insert(
+            constant.addr(),
+            caller.constant(constant.ty(), constant.value()),
+        );
+    }
+
+    // Unreachable blocks of the callee are not copied. In reverse post
+    // order, every operand apart from those of phis is copied before its
+    // users.
+    let order = reverse_post_order(callee);
+    let mut blocks: HashMap<&'m BasicBlock<'m>, &'m BasicBlock<'m>> = HashMap::new();
+    let mut after = block;
+    for orig in order.iter() {
+        let copy = ctx.new_basic_block();
+        caller.insert_block_after(after, copy);
+        blocks.insert(*orig, copy);
+        after = copy;
+    }
+
+    let mut phis = vec![];
+    let mut returns = vec![];
+    for orig in order.iter() {
+        let copy = blocks[orig];
+        for inst in orig.instructions().iter() {
+            let mut kind = inst.kind().clone();
+            match &mut kind {
+                InstKind::Return(val) => {
+                    returns.push((val.map(|v| values[&v.addr()]), copy));
+                    copy.add_instruction(ctx.jump(tail));
+                    continue;
+                }
+                InstKind::Phi(incoming) => incoming.clear(),
+                _ => kind.remap(|v| values[&v.addr()], |b| blocks[&b]),
+            }
+            let clone = ctx.inst(inst.ty(), kind);
+            caller.copy_debug_info(inst, clone);
+            if inst.is_phi() {
+                phis.push((*inst, clone));
+            }
+            if matches!(&*clone.kind(), InstKind::Alloca(_)) {
+                caller.entry().insert_instruction(0, clone);
+            } else {
+                copy.add_instruction(clone);
+            }
+            values.insert(inst.addr(), clone);
+        }
+    }
+    for (orig, clone) in phis {
+        let InstKind::Phi(incoming) = &*orig.kind() else {
+            unreachable!();
+        };
+        for (val, pred) in incoming.iter() {
+            if let Some(pred) = blocks.get(pred) {
+                clone.add_incoming(values[&val.addr()], pred);
+            }
+        }
+    }
+
+    if call.has_users() {
+        let ty = call.ty();
+        let result: &'m dyn Value<'m> = match returns.as_slice() {
+            [(Some(val), _)] => *val,
+            [] | [(None, _)] => caller.constant(ty, 0),
+            _ => {
+                let phi = ctx.phi(ty, vec![]);
+                tail.insert_instruction(0, phi);
+                for (val, pred) in returns {
+                    phi.add_incoming(val.unwrap_or_else(|| caller.constant(ty, 0)), pred);
+                }
+                phi
+            }
+        };
+        call.replace_all_uses_with(result);
+    }
+    call.erase();
+    block
+        .terminator()
+        .unwrap()
+        .set_kind(InstKind::Jump(blocks[&callee.entry()]));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, InstKind, Module, PassManager};
+
+    fn calls(module: &Module<'_>, name: &str) -> Vec<String> {
+        let func = module.get_function(name).unwrap();
+        func.instructions()
+            .iter()
+            .filter_map(|inst| match &*inst.kind() {
+                InstKind::Call(callee, _) => Some(callee.name().to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inline_small_helpers() {
+        let module = lower_source(
+            r"func helper(n: Int64, i: Int64) : Int64 {
+    if n % i == 0 {
+        return 0;
+    }
+    if i * i > n {
+        return 1;
+    }
+    return helper(n, i + 1);
+}
+func prime(n: Int64) : Int64 {
+    return helper(n, 2);
+}
+func twice(n: Int64) : Int64 {
+    return prime(n) + prime(n + 1);
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,inline")
+            .unwrap()
+            .run(module);
+        verify_module(module).unwrap();
+
+        // The recursive call stays, but the helper is inlined once into
+        // `prime`, which is then inlined into `twice`.
+        assert_eq!(calls(module, "helper"), vec!["helper"]);
+        assert_eq!(calls(module, "prime"), vec!["helper"]);
+        assert_eq!(calls(module, "twice"), vec!["helper", "helper"]);
+    }
+
+    #[test]
+    fn respect_hints_and_recursion() {
+        let module = lower_source(
+            r"@noinline func small(n: Int64) : Int64 {
+    return n + 1;
+}
+func fact(n: Int64) : Int64 {
+    if n == 0 {
+        return 1;
+    }
+    return n * fact(n - 1);
+}
+@inline func big(n: Int64) : Int64 {
+    var x: Int64 = n;
+    x = x * x + 1; x = x * x + 2; x = x * x + 3; x = x * x + 4;
+    x = x * x + 5; x = x * x + 6; x = x * x + 7; x = x * x + 8;
+    x = x * x + 9; x = x * x + 10; x = x * x + 11; x = x * x + 12;
+    return x;
+}
+func main(n: Int64) : Int64 {
+    return small(n) + fact(n) + big(n);
+}
+",
+        );
+        PassManager::from_pipeline("inline").unwrap().run(module);
+        verify_module(module).unwrap();
+
+        assert_eq!(calls(module, "fact"), vec!["fact"]);
+        assert_eq!(calls(module, "main"), vec!["small", "fact"]);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::ir::analysis::AnalysisManager;
+use crate::ir::pass::FunctionPass;
+use crate::ir::{Func, Inst, InstKind, Type, Value};
+
+/// Peephole simplification of single instructions: constants are moved to
+/// the right-hand side, where the backend can use them as immediates,
+/// algebraic identities are folded away, and multiplication, division and
+/// remainder by powers of two become shifts and masks.
+///
+/// Instructions with only constant operands are left to `constfold`.
+pub struct InstCombine;
+
+impl<'m> FunctionPass<'m> for InstCombine {
+    fn name(&self) -> &'static str {
+        "instcombine"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let mut changed = false;
+        let mut worklist = func.instructions();
+        worklist.reverse();
+
+        while let Some(inst) = worklist.pop() {
+            // Skip instructions erased since they were queued.
+            if inst.parent().is_none() {
+                continue;
+            }
+            changed |= canonicalize(inst);
+            if let Some(value) = simplify(func, inst) {
+                let users = inst.users().clone();
+                inst.replace_all_uses_with(value);
+                inst.erase();
+                worklist.extend(users);
+                changed = true;
+            } else if reduce_strength(func, inst) {
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn constant(val: &dyn Value<'_>) -> Option<u64> {
+    val.as_constant().map(|c| c.value())
+}
+
+/// Move a constant left operand to the right.
+fn canonicalize<'m>(inst: &'m Inst<'m>) -> bool {
+    let swapped = {
+        let kind = inst.kind();
+        let Some((lhs, rhs)) = kind.binary_operands() else {
+            return false;
+        };
+        if lhs.as_constant().is_none() || rhs.as_constant().is_some() {
+            return false;
+        }
+        kind.swapped()
+    };
+    match swapped {
+        Some(kind) => {
+            inst.set_kind(kind);
+            true
+        }
+        None => false,
+    }
+}
+
+/// A value that `inst` always equals.
+fn simplify<'m>(func: &'m Func<'m>, inst: &Inst<'m>) -> Option<&'m dyn Value<'m>> {
+    let kind = inst.kind();
+    let (x, y) = kind.binary_operands()?;
+    if x.as_constant().is_some() && y.as_constant().is_some() {
+        return None;
+    }
+    let zero = func.constant(inst.ty(), 0);
+    let one = func.constant(inst.ty(), 1);
+    let ones = x.ty().truncate(u64::MAX);
+    let same = x.addr() == y.addr();
+
+    use InstKind::*;
+    let value: &'m dyn Value<'m> = match (&*kind, constant(x), constant(y)) {
+        (Add(..) | Sub(..) | Or(..) | Xor(..), _, Some(0)) => x,
+        // Shift amounts are taken modulo 64.
+        (LShl(..) | LShr(..) | AShr(..), _, Some(c)) if c & 63 == 0 => x,
+        (Mul(..) | Div(..), _, Some(1)) => x,
+        (Mul(..) | And(..), _, Some(0)) => zero,
+        (And(..), _, Some(c)) if c == ones => x,
+        (Or(..), _, Some(c)) if c == ones => y,
+        (Mod(..), _, Some(c)) if c == 1 || c == ones => zero,
+        (LShl(..) | LShr(..) | AShr(..) | Div(..) | Mod(..), Some(0), _) => zero,
+        (Sub(..) | Xor(..) | Ne(..) | Gt(..) | Lt(..), _, _) if same => zero,
+        (Eq(..) | Ge(..) | Le(..), _, _) if same => one,
+        (And(..) | Or(..), _, _) if same => x,
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Replace multiplication, signed division and remainder by a power of two
+/// with cheaper instructions.
+fn reduce_strength<'m>(func: &'m Func<'m>, inst: &'m Inst<'m>) -> bool {
+    let kind = inst.kind().clone();
+    let Some((x, y)) = kind.binary_operands() else {
+        return false;
+    };
+    let Some(c) = constant(y) else {
+        return false;
+    };
+    // The rewrites below assume 64-bit arithmetic.
+    if !c.is_power_of_two() || c == 1 || x.ty() != Type::I64 {
+        return false;
+    }
+    let k = c.trailing_zeros() as u64;
+    let ctx = func.context();
+    let int = |value| func.constant(Type::I64, value);
+
+    match kind {
+        InstKind::Mul(_, _) => {
+            inst.set_kind(InstKind::LShl(x, int(k)));
+            true
+        }
+        // A divisor of 2^63 is negative.
+        InstKind::Div(_, _) | InstKind::Mod(_, _) if k < 63 => {
+            // Division truncates towards zero, so negative dividends are
+            // biased by 2^k - 1 before shifting.
+            let sign = ctx.ashr(x, int(63));
+            let bias = ctx.lshr(sign, int(64 - k));
+            let biased = ctx.add(x, bias);
+            let mut insts = vec![sign, bias, biased];
+            let result = if matches!(kind, InstKind::Div(_, _)) {
+                let quotient = ctx.ashr(biased, int(k));
+                insts.push(quotient);
+                quotient
+            } else {
+                // x - (x / 2^k) * 2^k
+                let rounded = ctx.and(biased, int(c.wrapping_neg()));
+                let remainder = ctx.sub(x, rounded);
+                insts.extend([rounded, remainder]);
+                remainder
+            };
+            for new in insts {
+                new.insert_before(inst);
+            }
+            inst.replace_all_uses_with(result);
+            inst.erase();
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, Module, PassManager};
+
+    fn optimize(src: &str, pipeline: &str) -> &'static Module<'static> {
+        let module = lower_source(src);
+        PassManager::from_pipeline(pipeline).unwrap().run(module);
+        verify_module(module).unwrap();
+        module
+    }
+
+    #[test]
+    fn canonicalize_and_simplify() {
+        let module = optimize(
+            r"func f(x: Int64) : Int64 {
+    var y: Int64 = (0 + x) * 1 | 0;
+    var z: Int64 = (y ^ y) + (x - x) + (y & -1);
+    if 3 < z + 7 {
+        return 1;
+    }
+    return z;
+}
+",
+            "mem2reg,constfold,instcombine",
+        );
+        let ir = module.functions()[0].to_string();
+        assert!(ir.contains("add i64 %x, $7"), "{}", ir);
+        assert!(ir.contains("gt i64 %"), "{}", ir);
+        assert!(!ir.contains("mul") && !ir.contains("xor") && !ir.contains("sub"));
+        assert!(ir.contains("return i64 %x"), "{}", ir);
+    }
+
+    #[test]
+    fn powers_of_two() {
+        let src = r"func f(x: Int64) : Int64 {
+    return x * 8 + x / 4 + x % 16;
+}
+";
+        let ir = optimize(src, "mem2reg,instcombine").functions()[0].to_string();
+        assert!(!ir.contains("mul") && !ir.contains("div") && !ir.contains("mod"));
+    }
+
+    #[test]
+    fn signed_division_semantics() {
+        for x in [-9i64, -8, -7, -1, 0, 1, 7, 8, 9, i64::MIN, i64::MAX] {
+            for (op, k) in [("/", 1), ("/", 4), ("%", 2), ("%", 8), ("/", 1 << 62)] {
+                // After inlining, constant folding evaluates the rewritten
+                // sequence.
+                let src = format!(
+                    r"func f(x: Int64) : Int64 {{
+    return x {op} {k};
+}}
+func main() : Int64 {{
+    return f({x});
+}}
+"
+                );
+                let module = optimize(&src, "mem2reg,instcombine,inline,constfold");
+                let expected = if op == "/" {
+                    x.wrapping_div(k)
+                } else {
+                    x.wrapping_rem(k)
+                };
+                let ir = module.get_function("main").unwrap().to_string();
+                assert!(
+                    ir.contains(&format!("return i64 ${}", expected as u64)),
+                    "{x} {op} {k}: {ir}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::ir::analysis::{AnalysisManager, Loop};
+use crate::ir::pass::FunctionPass;
+use crate::ir::{Func, Value};
+
+use super::util::insert_preheader;
+
+/// Loop-invariant code motion: move arithmetic whose operands are all
+/// defined outside a loop into the loop's preheader. Arithmetic never traps
+/// (division by zero yields zero), so it can be hoisted even when it only
+/// runs on some paths through the loop. Loads are left alone, since a store
+/// in the loop may change the value.
+pub struct Licm;
+
+impl<'m> FunctionPass<'m> for Licm {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, am: &mut AnalysisManager<'m>) -> bool {
+        // All preheaders are inserted first, so that the loops computed
+        // afterwards include the preheaders of the loops nested in them.
+        let mut changed = false;
+        for lp in am.loops(func).loops() {
+            if lp.preheader().is_none() {
+                insert_preheader(func, lp);
+                changed = true;
+            }
+        }
+        if changed {
+            am.invalidate(func);
+        }
+
+        // Inner loops come first, so code hoisted out of them can move on
+        // out of the enclosing loops.
+        for lp in am.loops(func).loops() {
+            changed |= hoist(lp);
+        }
+        changed
+    }
+}
+
+fn hoist<'m>(lp: &Loop<'m>) -> bool {
+    let term = lp.preheader().unwrap().terminator().unwrap();
+    let mut changed = false;
+    // In reverse post order, the definitions of the operands come before
+    // their users, so chains of invariant instructions are hoisted at once.
+    for block in lp.blocks() {
+        let insts = block.instructions().clone();
+        for inst in insts {
+            let invariant = inst.kind().binary_operands().is_some()
+                && inst.operands().iter().all(|op| is_defined_outside(lp, *op));
+            if invariant {
+                inst.move_before(term);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+fn is_defined_outside<'m>(lp: &Loop<'m>, val: &'m dyn Value<'m>) -> bool {
+    val.as_inst()
+        .and_then(|inst| inst.parent())
+        .is_none_or(|block| !lp.contains(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::analysis::AnalysisManager;
+    use crate::ir::{lower_source, verify_module, InstKind, Module, PassManager};
+
+    /// Names of the instructions of `f` that are inside a loop.
+    fn in_loops(module: &'static Module<'static>) -> Vec<String> {
+        let func = module.functions()[0];
+        let loops = AnalysisManager::new().loops(func);
+        func.instructions()
+            .iter()
+            .filter(|inst| {
+                loops
+                    .loops()
+                    .iter()
+                    .any(|l| l.contains(inst.parent().unwrap()))
+            })
+            .map(|inst| inst.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hoist_out_of_nested_loops() {
+        let module = lower_source(
+            r"func f(n: Int64) : Int64 {
+    var i: Int64 = 0;
+    var s: Int64 = 0;
+    while i < n {
+        var j: Int64 = 0;
+        while j * j < n * n + 1 {
+            s = s + i * 2;
+            j = j + 1;
+        }
+        i = i + 1;
+    }
+    return s;
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,licm")
+            .unwrap()
+            .run(module);
+        verify_module(module).unwrap();
+
+        let insts = in_loops(module);
+        // `n * n + 1` leaves both loops, `i * 2` only the inner one.
+        assert!(
+            insts.iter().all(|i| !i.contains("mul i64 %0, %0")),
+            "{:?}",
+            insts
+        );
+        assert_eq!(
+            insts.iter().filter(|i| i.contains("mul")).count(),
+            2,
+            "{:?}",
+            insts
+        );
+    }
+
+    #[test]
+    fn insert_preheader() {
+        let module = lower_source(
+            r"func f(n: Int64) : Int64 {
+    var x: Int64 = 0;
+    if n {
+        x = 1;
+    } else {
+        x = 2;
+    }
+    while x < n {
+        x = x + n * 3;
+    }
+    return x;
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,dce,licm")
+            .unwrap()
+            .run(module);
+        verify_module(module).unwrap();
+
+        let func = module.functions()[0];
+        let loops = AnalysisManager::new().loops(func);
+        let preheader = loops.loops()[0].preheader().unwrap();
+        assert!(preheader
+            .instructions()
+            .iter()
+            .any(|inst| matches!(&*inst.kind(), InstKind::Mul(_, _))));
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::analysis::{AnalysisManager, DominatorTree};
+use crate::ir::pass::FunctionPass;
+use crate::ir::{BasicBlock, Func, Inst, InstKind, Type, Value};
+
+/// Promote allocas that are only loaded and stored into SSA values, inserting
+/// phis where the stored values meet.
+pub struct Mem2Reg;
+
+impl<'m> FunctionPass<'m> for Mem2Reg {
+    fn name(&self) -> &'static str {
+        "mem2reg"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, am: &mut AnalysisManager<'m>) -> bool {
+        let allocas: Vec<&'m Inst<'m>> = func
+            .instructions()
+            .into_iter()
+            .filter(|inst| is_promotable(inst))
+            .collect();
+        if allocas.is_empty() {
+            return false;
+        }
+
+        let domtree = am.dominators(func);
+        let mut promoter = Promoter {
+            func,
+            domtree: &domtree,
+            allocas: allocas.clone(),
+            phis: HashMap::new(),
+        };
+        promoter.insert_phis();
+        promoter.rename();
+        promoter.cleanup_unreachable();
+
+        for alloca in allocas {
+            alloca.erase();
+        }
+        true
+    }
+}
+
+/// An alloca can be promoted if its address never escapes, i.e. it is only
+/// used as the pointer operand of loads and stores, and these all access it
+/// as the type it was allocated with.
+fn is_promotable(inst: &Inst<'_>) -> bool {
+    let Some(ty) = inst.allocated_type() else {
+        return false;
+    };
+    inst.users().iter().all(|user| match &*user.kind() {
+        InstKind::Load(_) => user.ty() == ty,
+        InstKind::Store(val, ptr) => {
+            ptr.addr() == inst.addr() && val.addr() != inst.addr() && val.ty() == ty
+        }
+        _ => false,
+    })
+}
+
+struct Promoter<'m, 'a> {
+    func: &'m Func<'m>,
+    domtree: &'a DominatorTree<'m>,
+    allocas: Vec<&'m Inst<'m>>,
+    // (block, alloca index) -> phi
+    phis: HashMap<(&'m BasicBlock<'m>, usize), &'m Inst<'m>>,
+}
+
+impl<'m> Promoter<'m, '_> {
+    #[allow(clippy::mutable_key_type)]
+    fn insert_phis(&mut self) {
+        let frontiers = self.domtree.frontiers();
+        let ctx = self.func.context();
+
+        for (index, alloca) in self.allocas.iter().enumerate() {
+            let mut def_blocks = Vec::new();
+            for user in alloca.users().iter() {
+                if matches!(&*user.kind(), InstKind::Store(_, _)) {
+                    def_blocks.push(user.parent().unwrap());
+                }
+            }
+            let live_in = self.live_in_blocks(alloca);
+
+            // Place phis on the iterated dominance frontier of the stores,
+            // skipping blocks where the variable is dead anyway.
+            let mut worklist = def_blocks.clone();
+            let mut visited: HashSet<&'m BasicBlock<'m>> = HashSet::new();
+            while let Some(block) = worklist.pop() {
+                for frontier in frontiers.get(&block).into_iter().flatten() {
+                    if !visited.insert(*frontier) || !live_in.contains(frontier) {
+                        continue;
+                    }
+                    let phi = ctx.phi(alloca.allocated_type().unwrap(), vec![]);
+                    if let Some(name) = alloca.debug_name() {
+                        let name = name.strip_suffix(".addr").unwrap_or(name);
+                        self.func.set_value_name(phi, name);
+                    }
+                    frontier.insert_instruction(0, phi);
+                    self.phis.insert((*frontier, index), phi);
+                    worklist.push(*frontier);
+                }
+            }
+        }
+    }
+
+    /// Blocks at whose entry the value of `alloca` may still be loaded.
+    #[allow(clippy::mutable_key_type)]
+    fn live_in_blocks(&self, alloca: &'m Inst<'m>) -> HashSet<&'m BasicBlock<'m>> {
+        let mut def_blocks = HashSet::new();
+        let mut worklist = Vec::new();
+        for user in alloca.users().iter() {
+            let block = user.parent().unwrap();
+            if matches!(&*user.kind(), InstKind::Store(_, _)) {
+                def_blocks.insert(block);
+            }
+        }
+        for user in alloca.users().iter() {
+            let block = user.parent().unwrap();
+            if !matches!(&*user.kind(), InstKind::Load(_)) {
+                continue;
+            }
+            // A load only makes the variable live-in if no store precedes it
+            // in the same block.
+            let exposed = !def_blocks.contains(&block)
+                || block
+                    .instructions()
+                    .iter()
+                    .find(|inst| alloca.users().iter().any(|u| std::ptr::eq(*u, **inst)))
+                    .is_some_and(|first| matches!(&*first.kind(), InstKind::Load(_)));
+            if exposed {
+                worklist.push(block);
+            }
+        }
+
+        let mut live_in = HashSet::new();
+        while let Some(block) = worklist.pop() {
+            if !live_in.insert(block) {
+                continue;
+            }
+            for pred in block.predecessors() {
+                if !def_blocks.contains(&pred) {
+                    worklist.push(pred);
+                }
+            }
+        }
+        live_in
+    }
+
+    fn rename(&mut self) {
+        let mut current: Vec<Option<&'m dyn Value<'m>>> = vec![None; self.allocas.len()];
+        // Walk the dominator tree, saving the current values before entering
+        // a subtree and restoring them when leaving it.
+        let mut stack = vec![(self.domtree.entry(), None)];
+        while let Some((block, saved)) = stack.pop() {
+            if let Some(saved) = saved {
+                current = saved;
+                continue;
+            }
+            let before = current.clone();
+            self.rename_block(block, &mut current);
+            stack.push((block, Some(before)));
+            for child in self.domtree.children(block).iter().rev() {
+                stack.push((*child, None));
+            }
+        }
+    }
+
+    fn rename_block(
+        &mut self,
+        block: &'m BasicBlock<'m>,
+        current: &mut [Option<&'m dyn Value<'m>>],
+    ) {
+        for (index, cur) in current.iter_mut().enumerate() {
+            if let Some(phi) = self.phis.get(&(block, index)) {
+                *cur = Some(*phi);
+            }
+        }
+
+        let insts = block.instructions().clone();
+        for inst in insts {
+            let (index, stored) = match &*inst.kind() {
+                InstKind::Load(ptr) => (self.alloca_index(*ptr), None),
+                InstKind::Store(val, ptr) => (self.alloca_index(*ptr), Some(*val)),
+                _ => (None, None),
+            };
+            let Some(index) = index else {
+                continue;
+            };
+            match stored {
+                Some(val) => current[index] = Some(val),
+                None => {
+                    let val = match current[index] {
+                        Some(val) => val,
+                        None => self.undef(inst.ty()),
+                    };
+                    inst.replace_all_uses_with(val);
+                }
+            }
+            inst.erase();
+        }
+
+        for succ in block.successors() {
+            for (index, cur) in current.iter().enumerate() {
+                if let Some(&phi) = self.phis.get(&(succ, index)) {
+                    let val = match cur {
+                        Some(val) => *val,
+                        None => self.undef(phi.ty()),
+                    };
+                    phi.add_incoming(val, block);
+                }
+            }
+        }
+    }
+
+    /// Loads and stores in unreachable blocks were not renamed, and phis have
+    /// no incoming value yet for unreachable predecessors.
+    fn cleanup_unreachable(&mut self) {
+        for alloca in self.allocas.clone() {
+            let users = alloca.users().clone();
+            for user in users {
+                if matches!(&*user.kind(), InstKind::Load(_)) {
+                    let undef = self.undef(user.ty());
+                    user.replace_all_uses_with(undef);
+                }
+                user.erase();
+            }
+        }
+
+        let phis: Vec<_> = self.phis.iter().map(|((b, _), p)| (*b, *p)).collect();
+        for (block, phi) in phis {
+            for pred in block.predecessors() {
+                if !self.domtree.is_reachable(pred) {
+                    let undef = self.undef(phi.ty());
+                    phi.add_incoming(undef, pred);
+                }
+            }
+        }
+    }
+
+    fn alloca_index(&self, ptr: &dyn Value<'m>) -> Option<usize> {
+        self.allocas.iter().position(|a| a.addr() == ptr.addr())
+    }
+
+    /// Value of a variable read before it is ever written.
+    fn undef(&self, ty: Type) -> &'m dyn Value<'m> {
+        self.func.constant(ty, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, InstKind, PassManager};
+
+    #[test]
+    fn promote_loop_variables() {
+        let module = lower_source(
+            r"func gcd(a: Int64, b: Int64) : Int64 {
+    var temp: Int64 = 0;
+    while b != 0 {
+        temp = b;
+        b = a % b;
+        a = temp;
+    }
+    return a;
+}
+",
+        );
+        assert!(PassManager::from_pipeline("mem2reg").unwrap().run(module));
+        verify_module(module).unwrap();
+
+        let func = module.get_function("gcd").unwrap();
+        let insts = func.instructions();
+        assert!(!insts.iter().any(|inst| matches!(
+            &*inst.kind(),
+            InstKind::Alloca(_) | InstKind::Load(_) | InstKind::Store(_, _)
+        )));
+        // `temp` is dead at the loop header, so only `a` and `b` need phis.
+        assert_eq!(insts.iter().filter(|inst| inst.is_phi()).count(), 2);
+    }
+
+    #[test]
+    fn unreachable_code_after_return() {
+        let module = lower_source(
+            r"func f(n: Int64) : Int64 {
+    var x: Int64 = n;
+    if n {
+        return x;
+    } else {
+        return 0;
+    }
+    return x + 1;
+}
+",
+        );
+        assert!(PassManager::from_pipeline("mem2reg").unwrap().run(module));
+        verify_module(module).unwrap();
+        let func = module.get_function("f").unwrap();
+        assert!(!func
+            .instructions()
+            .iter()
+            .any(|inst| matches!(&*inst.kind(), InstKind::Alloca(_))));
+    }
+}
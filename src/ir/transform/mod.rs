@@ -0,0 +1,26 @@
+mod constfold;
+mod dce;
+mod funcattrs;
+mod globaldce;
+mod gvn;
+mod inline;
+mod instcombine;
+mod licm;
+mod mem2reg;
+mod sccp;
+mod tailcall;
+mod unroll;
+mod util;
+
+pub use constfold::ConstFold;
+pub use dce::Dce;
+pub use funcattrs::FunctionAttrs;
+pub use globaldce::GlobalDce;
+pub use gvn::Gvn;
+pub use inline::Inliner;
+pub use instcombine::InstCombine;
+pub use licm::Licm;
+pub use mem2reg::Mem2Reg;
+pub use sccp::Sccp;
+pub use tailcall::TailCallElim;
+pub use unroll::Unroll;
@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::analysis::AnalysisManager;
+use crate::ir::pass::FunctionPass;
+use crate::ir::{BasicBlock, Func, Inst, InstKind, Value};
+
+use super::constfold::fold_branch;
+use super::dce::remove_unreachable_blocks;
+
+/// Sparse conditional constant propagation, after Wegman and Zadeck. Values
+/// are assumed constant until proven otherwise, and only the CFG edges that
+/// can be taken given the values found so far are followed. This finds
+/// constants that depend on themselves through loops, which `constfold`
+/// misses, and removes the branches that never run.
+pub struct Sccp;
+
+impl<'m> FunctionPass<'m> for Sccp {
+    fn name(&self) -> &'static str {
+        "sccp"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let mut solver = Solver::default();
+        solver.solve(func.entry());
+
+        let mut changed = false;
+        for inst in func.instructions() {
+            if inst.has_side_effects() {
+                continue;
+            }
+            if let Lattice::Constant(value) = solver.value(inst) {
+                inst.replace_all_uses_with(func.constant(inst.ty(), value));
+                inst.erase();
+                changed = true;
+            }
+        }
+        for block in solver.executable.iter() {
+            changed |= fold_branch(block.terminator().unwrap());
+        }
+        remove_unreachable_blocks(func) || changed
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Lattice {
+    /// No executable definition seen yet.
+    Undefined,
+    Constant(u64),
+    /// May take more than one value.
+    Overdefined,
+}
+
+impl Lattice {
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Undefined, x) | (x, Lattice::Undefined) => x,
+            (Lattice::Constant(a), Lattice::Constant(b)) if a == b => self,
+            _ => Lattice::Overdefined,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Solver<'m> {
+    values: HashMap<*const (), Lattice>,
+    executable: HashSet<&'m BasicBlock<'m>>,
+    edges: HashSet<(&'m BasicBlock<'m>, &'m BasicBlock<'m>)>,
+    edge_worklist: Vec<(&'m BasicBlock<'m>, &'m BasicBlock<'m>)>,
+    inst_worklist: Vec<&'m Inst<'m>>,
+}
+
+impl<'m> Solver<'m> {
+    fn solve(&mut self, entry: &'m BasicBlock<'m>) {
+        self.executable.insert(entry);
+        for inst in entry.instructions().iter() {
+            self.visit(inst);
+        }
+        loop {
+            if let Some((from, to)) = self.edge_worklist.pop() {
+                if !self.edges.insert((from, to)) {
+                    continue;
+                }
+                // The first time a block is reached all of it is evaluated.
+                // Later, only its phis can change.
+                let insts = if self.executable.insert(to) {
+                    to.instructions().clone()
+                } else {
+                    to.phis()
+                };
+                for inst in insts {
+                    self.visit(inst);
+                }
+            } else if let Some(inst) = self.inst_worklist.pop() {
+                if self.executable.contains(&inst.parent().unwrap()) {
+                    self.visit(inst);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn value(&self, val: &dyn Value<'m>) -> Lattice {
+        if let Some(c) = val.as_constant() {
+            return Lattice::Constant(c.value());
+        }
+        match val.as_inst() {
+            Some(_) => self
+                .values
+                .get(&val.addr())
+                .copied()
+                .unwrap_or(Lattice::Undefined),
+            // Parameters
+            None => Lattice::Overdefined,
+        }
+    }
+
+    fn visit(&mut self, inst: &'m Inst<'m>) {
+        let block = inst.parent().unwrap();
+        let kind = inst.kind();
+        let value = match &*kind {
+            InstKind::Phi(incoming) => incoming
+                .iter()
+                .filter(|(_, pred)| self.edges.contains(&(*pred, block)))
+                .fold(Lattice::Undefined, |acc, (val, _)| {
+                    acc.meet(self.value(*val))
+                }),
+            InstKind::Jump(target) => {
+                self.edge_worklist.push((block, *target));
+                return;
+            }
+            InstKind::CJump(cond, ifbb, elsebb) => {
+                match self.value(*cond) {
+                    Lattice::Undefined => {}
+                    Lattice::Constant(c) => {
+                        let taken = if c != 0 { ifbb } else { elsebb };
+                        self.edge_worklist.push((block, *taken));
+                    }
+                    Lattice::Overdefined => {
+                        self.edge_worklist.push((block, *ifbb));
+                        self.edge_worklist.push((block, *elsebb));
+                    }
+                }
+                return;
+            }
+            InstKind::Store(_, _) | InstKind::Return(_) => return,
+            InstKind::ZExt(val) => self.value(*val),
+            _ => match kind.binary_operands() {
+                Some((lhs, rhs)) => match (self.value(lhs), self.value(rhs)) {
+                    (Lattice::Constant(a), Lattice::Constant(b)) => kind
+                        .evaluate(lhs.ty(), a, b)
+                        .map_or(Lattice::Overdefined, Lattice::Constant),
+                    (Lattice::Overdefined, _) | (_, Lattice::Overdefined) => Lattice::Overdefined,
+                    _ => Lattice::Undefined,
+                },
+                // Loads, calls and allocas
+                None => Lattice::Overdefined,
+            },
+        };
+        drop(kind);
+
+        let old = self.value(inst);
+        let new = old.meet(value);
+        if new != old {
+            self.values.insert(inst.addr(), new);
+            self.inst_worklist.extend(inst.users().iter().copied());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, PassManager};
+
+    const SRC: &str = r"func f(n: Int64) : Int64 {
+    var x: Int64 = 1;
+    var i: Int64 = 0;
+    while i < n {
+        if x != 1 {
+            x = 2;
+        }
+        i = i + 1;
+    }
+    if x == 1 {
+        return x + 4;
+    }
+    return n;
+}
+";
+
+    fn optimize(pipeline: &str) -> String {
+        let module = lower_source(SRC);
+        PassManager::from_pipeline(pipeline).unwrap().run(module);
+        verify_module(module).unwrap();
+        module.functions()[0].to_string()
+    }
+
+    #[test]
+    fn propagate_through_loops() {
+        // `x` only depends on itself in the loop, so local folding is stuck.
+        let ir = optimize("mem2reg,constfold,dce");
+        assert!(ir.contains("$2"), "{}", ir);
+
+        let ir = optimize("mem2reg,sccp,dce");
+        assert!(ir.contains("return i64 $5"), "{}", ir);
+        assert!(
+            !ir.contains("$2") && !ir.contains("return i64 %0"),
+            "{}",
+            ir
+        );
+        // Only the loop over `i` is left.
+        assert_eq!(ir.matches("phi").count(), 1, "{}", ir);
+    }
+}
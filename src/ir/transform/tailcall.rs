@@ -0,0 +1,216 @@
+use crate::ir::analysis::AnalysisManager;
+use crate::ir::pass::FunctionPass;
+use crate::ir::{BasicBlock, Func, Inst, InstKind, Value};
+
+/// Mark calls whose result is returned right away as tail calls, and turn
+/// self-recursive tail calls into a loop back to the start of the function.
+/// A call jumping to a block that only returns the call's result, through a
+/// phi or not at all, returns directly instead.
+pub struct TailCallElim;
+
+impl<'m> FunctionPass<'m> for TailCallElim {
+    fn name(&self) -> &'static str {
+        "tailcall"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        let mut changed = false;
+        let mut recursive = vec![];
+        for block in func.blocks().iter() {
+            changed |= return_directly(block);
+            let Some(call) = tail_call(block) else {
+                continue;
+            };
+            if matches!(&*call.kind(), InstKind::Call(callee, _) if *callee == func) {
+                recursive.push(call);
+            } else if !call.is_tail_call() {
+                call.set_tail_call(true);
+                changed = true;
+            }
+        }
+        if !recursive.is_empty() {
+            eliminate_recursion(func, recursive);
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// The call right before the return of `block`, if its result is what the
+/// block returns.
+fn tail_call<'m>(block: &BasicBlock<'m>) -> Option<&'m Inst<'m>> {
+    let insts = block.instructions();
+    let [.., call, ret] = insts.as_slice() else {
+        return None;
+    };
+    let returned = match &*ret.kind() {
+        InstKind::Return(Some(val)) => val.addr() == call.addr(),
+        InstKind::Return(None) => true,
+        _ => false,
+    };
+    (returned && matches!(&*call.kind(), InstKind::Call(_, _))).then_some(*call)
+}
+
+/// Return from `block` if it ends in a call and a jump to a block doing
+/// nothing but return the call's result, or return nothing.
+fn return_directly<'m>(block: &'m BasicBlock<'m>) -> bool {
+    let insts = block.instructions().clone();
+    let [.., call, jump] = insts.as_slice() else {
+        return false;
+    };
+    let target = match &*jump.kind() {
+        InstKind::Jump(target) => *target,
+        _ => return false,
+    };
+    // A single predecessor is left for merging the blocks.
+    if !matches!(&*call.kind(), InstKind::Call(_, _)) || target.predecessors().len() < 2 {
+        return false;
+    }
+
+    let returned = match target.instructions().as_slice() {
+        [ret] if matches!(&*ret.kind(), InstKind::Return(None)) => None,
+        [phi, ret] => {
+            let returns_phi = matches!(&*ret.kind(),
+                InstKind::Return(Some(val)) if val.addr() == phi.addr());
+            let incoming = phi.is_phi().then(|| phi.incoming_value(block)).flatten();
+            if !returns_phi
+                || phi.users().len() != 1
+                || incoming.map(|val| val.addr()) != Some(call.addr())
+            {
+                return false;
+            }
+            Some(*call as &dyn Value<'m>)
+        }
+        _ => return false,
+    };
+    target.remove_predecessor(block);
+    jump.set_kind(InstKind::Return(returned));
+    true
+}
+
+/// Turn the old entry block into a loop header with a phi for each
+/// parameter, fed by the arguments of each recursive call. A new entry
+/// block enters the loop with the original parameters.
+fn eliminate_recursion<'m>(func: &'m Func<'m>, calls: Vec<&'m Inst<'m>>) {
+    let ctx = func.context();
+    let header = func.entry();
+    let entry = ctx.new_basic_block();
+    func.insert_block_before(header, entry);
+
+    // Stack slots are allocated once per call, not once per iteration.
+    let allocas: Vec<_> = header
+        .instructions()
+        .iter()
+        .filter(|inst| matches!(&*inst.kind(), InstKind::Alloca(_)))
+        .copied()
+        .collect();
+    for alloca in allocas {
+        alloca.remove_from_parent();
+        entry.add_instruction(alloca);
+    }
+    entry.add_instruction(ctx.jump(header));
+
+    let phis: Vec<_> = func
+        .params()
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            let phi = ctx.phi(param.ty(), vec![]);
+            header.insert_instruction(i, phi);
+            param.replace_all_uses_with(phi);
+            phi.add_incoming(*param, entry);
+            phi
+        })
+        .collect();
+
+    for call in calls {
+        let block = call.parent().unwrap();
+        for (phi, arg) in phis.iter().zip(call.operands()) {
+            phi.add_incoming(arg, block);
+        }
+        block.terminator().unwrap().set_kind(InstKind::Jump(header));
+        call.erase();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower_source, verify_module, InstKind, PassManager};
+
+    #[test]
+    fn self_recursion_becomes_a_loop() {
+        let module = lower_source(
+            r"func gcd(a: Int64, b: Int64) : Int64 {
+    if b == 0 {
+        return a;
+    }
+    return gcd(b, a % b);
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,tailcall,constfold,dce")
+            .unwrap()
+            .run(module);
+        verify_module(module).unwrap();
+
+        let func = module.get_function("gcd").unwrap();
+        let insts = func.instructions();
+        assert!(!insts
+            .iter()
+            .any(|inst| matches!(&*inst.kind(), InstKind::Call(_, _))));
+        assert_eq!(insts.iter().filter(|inst| inst.is_phi()).count(), 2);
+    }
+
+    #[test]
+    fn mark_tail_calls() {
+        let module = lower_source(
+            r"func id(n: Int64) : Int64 {
+    return n;
+}
+func f(n: Int64) : Int64 {
+    var x: Int64 = id(n);
+    return id(x + 1);
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,tailcall")
+            .unwrap()
+            .run(module);
+        verify_module(module).unwrap();
+
+        let ir = module.get_function("f").unwrap().to_string();
+        assert_eq!(ir.matches("tail call").count(), 1, "{}", ir);
+        assert_eq!(ir.matches("call").count(), 2, "{}", ir);
+    }
+
+    #[test]
+    fn return_through_phi() {
+        let module = lower_source(
+            r"@noinline
+func id(n: Int64) : Int64 {
+    return n;
+}
+@inline
+func g(n: Int64) : Int64 {
+    if n > 3 {
+        return 0;
+    }
+    return id(n);
+}
+func f(n: Int64) : Int64 {
+    return g(n);
+}
+",
+        );
+        PassManager::from_pipeline("mem2reg,inline,tailcall")
+            .unwrap()
+            .run(module);
+        verify_module(module).unwrap();
+
+        // The inlined call jumped to the block returning the phi of both
+        // results.
+        let ir = module.get_function("f").unwrap().to_string();
+        assert_eq!(ir.matches("tail call").count(), 1, "{}", ir);
+        assert_eq!(ir.matches("return").count(), 2, "{}", ir);
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+
+use crate::ir::analysis::{AnalysisManager, Loop};
+use crate::ir::pass::FunctionPass;
+use crate::ir::{BasicBlock, Func, Inst, InstKind, Type, Value};
+
+use super::dce::remove_unreachable_blocks;
+use super::util::insert_preheader;
+
+/// Loops are fully unrolled if this leaves at most this many instructions.
+const FULL_UNROLL_MAX_SIZE: usize = 128;
+/// Loops are partially unrolled if the unrolled body has at most this many
+/// instructions.
+const PARTIAL_UNROLL_MAX_SIZE: usize = 64;
+
+/// Unroll innermost loops counted by a simple induction variable. Loops
+/// with a small constant trip count are replaced by straight-line copies of
+/// their body. Other loops get a main loop running `factor` iterations at a
+/// time, followed by the original loop for the remaining iterations.
+pub struct Unroll {
+    factor: usize,
+}
+
+impl Unroll {
+    pub fn new(factor: usize) -> Unroll {
+        Unroll { factor }
+    }
+}
+
+impl Default for Unroll {
+    fn default() -> Self {
+        Unroll::new(4)
+    }
+}
+
+impl<'m> FunctionPass<'m> for Unroll {
+    fn name(&self) -> &'static str {
+        "unroll"
+    }
+
+    fn run_on_function(&mut self, func: &'m Func<'m>, am: &mut AnalysisManager<'m>) -> bool {
+        let mut changed = false;
+        for lp in am.loops(func).loops() {
+            if lp.preheader().is_none() {
+                insert_preheader(func, lp);
+                changed = true;
+            }
+        }
+        if changed {
+            am.invalidate(func);
+        }
+
+        // Innermost loops do not overlap, so unrolling one leaves the others
+        // intact.
+        let loops = am.loops(func);
+        let innermost = loops.loops().iter().filter(|lp| {
+            !loops
+                .loops()
+                .iter()
+                .any(|other| other.header() != lp.header() && lp.contains(other.header()))
+        });
+        let mut unrolled = false;
+        for lp in innermost {
+            let Some(iv) = InductionVariable::find(lp) else {
+                continue;
+            };
+            let size: usize = lp.blocks().iter().map(|b| b.instructions().len()).sum();
+            let trips = iv.trip_count(FULL_UNROLL_MAX_SIZE / size.max(1));
+            if let Some(trips) = trips {
+                unroll_fully(func, lp, trips);
+                unrolled = true;
+            } else if self.factor > 1 && size * self.factor <= PARTIAL_UNROLL_MAX_SIZE {
+                unrolled |= unroll_partially(func, lp, &iv, self.factor);
+            }
+        }
+        if unrolled {
+            remove_unreachable_blocks(func);
+        }
+        changed || unrolled
+    }
+}
+
+/// A header phi stepping by a constant each iteration, and the comparison
+/// against a loop-invariant bound that keeps the loop running.
+struct InductionVariable<'m> {
+    phi: &'m Inst<'m>,
+    init: &'m dyn Value<'m>,
+    step: i64,
+    /// `phi <cmp> bound`, with `phi` on the left.
+    cmp: InstKind<'m>,
+    bound: &'m dyn Value<'m>,
+}
+
+impl<'m> InductionVariable<'m> {
+    /// Only loops with a single latch that can be left only from the header
+    /// are recognised.
+    fn find(lp: &Loop<'m>) -> Option<InductionVariable<'m>> {
+        let header = lp.header();
+        let preheader = lp.preheader()?;
+        let latches: Vec<_> = header
+            .predecessors()
+            .into_iter()
+            .filter(|pred| lp.contains(pred))
+            .collect();
+        let [latch] = latches.as_slice() else {
+            return None;
+        };
+        if *latch == header {
+            return None;
+        }
+        let exits_elsewhere = lp.blocks()[1..]
+            .iter()
+            .any(|block| block.successors().iter().any(|succ| !lp.contains(succ)));
+        if exits_elsewhere {
+            return None;
+        }
+        let cond = match &*header.terminator()?.kind() {
+            InstKind::CJump(cond, body, exit) if lp.contains(body) && !lp.contains(exit) => {
+                cond.as_inst()?
+            }
+            _ => return None,
+        };
+
+        let is_header_phi = |val: &dyn Value<'m>| {
+            val.as_inst()
+                .is_some_and(|i| i.is_phi() && i.parent() == Some(header))
+        };
+        let mut cmp = cond.kind().clone();
+        if !is_header_phi(cmp.binary_operands()?.0) {
+            cmp = cmp.swapped()?;
+        }
+        let (lhs, bound) = cmp.binary_operands()?;
+        // Steps and trip counts are worked out in 64 bits.
+        let phi = lhs
+            .as_inst()
+            .filter(|phi| is_header_phi(lhs) && phi.ty() == Type::I64)?;
+        if bound
+            .as_inst()
+            .and_then(|inst| inst.parent())
+            .is_some_and(|block| lp.contains(block))
+        {
+            return None;
+        }
+
+        let step = step_from(phi, phi.incoming_value(latch)?)?;
+        // The variable has to move towards the bound.
+        let upwards = match cmp {
+            InstKind::Lt(_, _) | InstKind::Le(_, _) => true,
+            InstKind::Gt(_, _) | InstKind::Ge(_, _) => false,
+            _ => return None,
+        };
+        if step == 0 || (step > 0) != upwards {
+            return None;
+        }
+
+        Some(InductionVariable {
+            phi,
+            init: phi.incoming_value(preheader)?,
+            step,
+            cmp,
+            bound,
+        })
+    }
+
+    /// Number of iterations if it is a compile-time constant of at most
+    /// `max`.
+    fn trip_count(&self, max: usize) -> Option<usize> {
+        let mut iv = self.init.as_constant()?.value();
+        let bound = self.bound.as_constant()?.value();
+        for trips in 0..=max {
+            if self.cmp.evaluate(Type::I64, iv, bound)? == 0 {
+                return Some(trips);
+            }
+            iv = iv.wrapping_add(self.step as u64);
+        }
+        None
+    }
+}
+
+/// The constant added to `phi` to get `next`, through a chain of additions
+/// and subtractions of constants.
+fn step_from<'m>(phi: &Inst<'m>, next: &'m dyn Value<'m>) -> Option<i64> {
+    let mut step: i64 = 0;
+    let mut val = next;
+    while val.addr() != phi.addr() {
+        let inst = val.as_inst()?;
+        let kind = inst.kind();
+        let (x, c) = match &*kind {
+            InstKind::Add(x, c) => (*x, c.as_constant()?.value() as i64),
+            InstKind::Sub(x, c) => (*x, (c.as_constant()?.value() as i64).checked_neg()?),
+            _ => return None,
+        };
+        step = step.checked_add(c)?;
+        val = x;
+    }
+    Some(step)
+}
+
+/// Replace the loop by `trips` copies of its body. The old header is kept
+/// for the values used after the loop, but its phis take the values after
+/// the last iteration and it goes straight to the exit.
+fn unroll_fully<'m>(func: &'m Func<'m>, lp: &Loop<'m>, trips: usize) {
+    let header = lp.header();
+    let preheader = lp.preheader().unwrap();
+    let mut values = initial_values(lp, preheader);
+    let mut prev = preheader;
+    let mut after = preheader;
+    for _ in 0..trips {
+        let (first, latch, last) = clone_iteration(func, lp, &mut values, after);
+        prev.terminator().unwrap().replace_successor(header, first);
+        prev = latch;
+        after = last;
+    }
+
+    for phi in header.phis() {
+        phi.replace_all_uses_with(values[&phi.addr()]);
+        phi.erase();
+    }
+    let term = header.terminator().unwrap();
+    let (body, exit) = match &*term.kind() {
+        InstKind::CJump(_, body, exit) => (*body, *exit),
+        _ => unreachable!(),
+    };
+    body.remove_predecessor(header);
+    term.set_kind(InstKind::Jump(exit));
+}
+
+/// Put a loop running `factor` copies of the body per iteration in front of
+/// the loop, which is left to run the remaining iterations.
+fn unroll_partially<'m>(
+    func: &'m Func<'m>,
+    lp: &Loop<'m>,
+    iv: &InductionVariable<'m>,
+    factor: usize,
+) -> bool {
+    let ctx = func.context();
+    let header = lp.header();
+    let preheader = lp.preheader().unwrap();
+    let entry_term = preheader.terminator().unwrap();
+
+    // At least `factor` iterations are left while the comparison still
+    // holds for `phi + distance`. Moving the distance to the bound instead
+    // avoids overflowing the variable. Where the bound itself could
+    // overflow, the main loop is skipped.
+    let Some(distance) = (factor as i64 - 1).checked_mul(iv.step) else {
+        return false;
+    };
+    let guard: Option<&'m Inst<'m>> = match iv.bound.as_constant() {
+        Some(bound) => {
+            let bound = bound.value() as i64;
+            if (iv.step > 0 && bound < i64::MIN + distance)
+                || (iv.step < 0 && bound > i64::MAX + distance)
+            {
+                return false;
+            }
+            None
+        }
+        None => {
+            let guard = if iv.step > 0 {
+                ctx.ge(
+                    iv.bound,
+                    func.constant(Type::I64, (i64::MIN + distance) as u64),
+                )
+            } else {
+                ctx.le(
+                    iv.bound,
+                    func.constant(Type::I64, (i64::MAX + distance) as u64),
+                )
+            };
+            guard.insert_before(entry_term);
+            Some(guard)
+        }
+    };
+    let limit = ctx.sub(iv.bound, func.constant(Type::I64, distance as u64));
+    limit.insert_before(entry_term);
+
+    let main = ctx.new_basic_block();
+    func.insert_block_before(header, main);
+    let mut values = HashMap::new();
+    let mut phis = vec![];
+    for phi in header.phis() {
+        let copy = ctx.phi(phi.ty(), vec![]);
+        func.copy_debug_info(phi, copy);
+        main.add_instruction(copy);
+        copy.add_incoming(phi.incoming_value(preheader).unwrap(), preheader);
+        values.insert(phi.addr(), copy as &dyn Value<'m>);
+        phis.push((phi, copy));
+    }
+    let mut cmp = iv.cmp.clone();
+    let phi = values[&iv.phi.addr()];
+    cmp.remap(
+        |v| {
+            if v.addr() == iv.phi.addr() {
+                phi
+            } else {
+                limit
+            }
+        },
+        |b| b,
+    );
+    let cond = ctx.inst(Type::I1, cmp);
+    main.add_instruction(cond);
+
+    let mut prev: Option<&'m BasicBlock<'m>> = None;
+    let mut after = main;
+    for _ in 0..factor {
+        let (first, latch, last) = clone_iteration(func, lp, &mut values, after);
+        match prev {
+            None => main.add_instruction(ctx.cjump(cond, first, header)),
+            Some(prev) => prev.terminator().unwrap().replace_successor(header, first),
+        }
+        prev = Some(latch);
+        after = last;
+    }
+    let latch = prev.unwrap();
+    latch.terminator().unwrap().replace_successor(header, main);
+    for (phi, copy) in phis {
+        copy.add_incoming(values[&phi.addr()], latch);
+        phi.add_incoming(copy, main);
+    }
+
+    // Without the guard, the remainder loop does all the work.
+    match guard {
+        Some(guard) => entry_term.set_kind(InstKind::CJump(guard, main, header)),
+        None => {
+            header.remove_predecessor(preheader);
+            entry_term.set_kind(InstKind::Jump(main));
+        }
+    }
+    true
+}
+
+/// The values of the header phis in the first iteration.
+fn initial_values<'m>(
+    lp: &Loop<'m>,
+    preheader: &'m BasicBlock<'m>,
+) -> HashMap<*const (), &'m dyn Value<'m>> {
+    lp.header()
+        .phis()
+        .into_iter()
+        .map(|phi| (phi.addr(), phi.incoming_value(preheader).unwrap()))
+        .collect()
+}
+
+/// Copy one iteration of the loop after `after`: the header without its
+/// phis and branch, then the body. `values` maps the header phis to their
+/// values in this iteration, and is updated to their values in the next.
+/// Returns the first block of the copy, the copy of the latch, which still
+/// jumps to the original header, and the last block of the copy.
+#[allow(clippy::mutable_key_type)]
+fn clone_iteration<'m>(
+    func: &'m Func<'m>,
+    lp: &Loop<'m>,
+    values: &mut HashMap<*const (), &'m dyn Value<'m>>,
+    after: &'m BasicBlock<'m>,
+) -> (&'m BasicBlock<'m>, &'m BasicBlock<'m>, &'m BasicBlock<'m>) {
+    let ctx = func.context();
+    let header = lp.header();
+    let latch = header
+        .predecessors()
+        .into_iter()
+        .find(|pred| lp.contains(pred))
+        .unwrap();
+
+    let mut blocks: HashMap<&'m BasicBlock<'m>, &'m BasicBlock<'m>> = HashMap::new();
+    let mut last = after;
+    for block in lp.blocks() {
+        let copy = ctx.new_basic_block();
+        func.insert_block_after(last, copy);
+        blocks.insert(*block, copy);
+        last = copy;
+    }
+
+    let mut map = values.clone();
+    let lookup = |map: &HashMap<*const (), &'m dyn Value<'m>>, v: &'m dyn Value<'m>| {
+        map.get(&v.addr()).copied().unwrap_or(v)
+    };
+    let mut phis = vec![];
+    for block in lp.blocks() {
+        for inst in block.instructions().iter() {
+            let mut kind = inst.kind().clone();
+            match &mut kind {
+                InstKind::Phi(_) if *block == header => continue,
+                InstKind::CJump(_, body, _) if *block == header => {
+                    kind = InstKind::Jump(blocks[body]);
+                }
+                InstKind::Phi(incoming) => {
+                    incoming.clear();
+                    phis.push(*inst);
+                }
+                _ => kind.remap(
+                    |v| lookup(&map, v),
+                    |b| if b == header { header } else { blocks[&b] },
+                ),
+            }
+            let clone = ctx.inst(inst.ty(), kind);
+            func.copy_debug_info(inst, clone);
+            blocks[block].add_instruction(clone);
+            map.insert(inst.addr(), clone);
+        }
+    }
+    for phi in phis {
+        let clone = map[&phi.addr()].as_inst().unwrap();
+        let InstKind::Phi(incoming) = &*phi.kind() else {
+            unreachable!();
+        };
+        for (val, pred) in incoming.iter() {
+            clone.add_incoming(lookup(&map, *val), blocks[pred]);
+        }
+    }
+
+    *values = header
+        .phis()
+        .into_iter()
+        .map(|phi| (phi.addr(), lookup(&map, phi.incoming_value(latch).unwrap())))
+        .collect();
+    (blocks[&header], blocks[&latch], last)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::analysis::AnalysisManager;
+    use crate::ir::{lower_source, verify_module, Module, PassManager};
+
+    fn optimize(src: &str, pipeline: &str) -> &'static Module<'static> {
+        let module = lower_source(src);
+        PassManager::from_pipeline(pipeline).unwrap().run(module);
+        verify_module(module).unwrap();
+        module
+    }
+
+    fn loop_count(module: &'static Module<'static>, name: &str) -> usize {
+        let func = module.get_function(name).unwrap();
+        AnalysisManager::new().loops(func).loops().len()
+    }
+
+    #[test]
+    fn unroll_constant_trip_counts() {
+        let module = optimize(
+            r"func f(n: Int64) : Int64 {
+    var s: Int64 = 0;
+    var i: Int64 = 10;
+    while i > 0 {
+        s = s * n + i;
+        i = i - 3;
+    }
+    return s;
+}
+func main() : Int64 {
+    return f(2);
+}
+",
+            "mem2reg,unroll,sccp,dce,inline,sccp,dce",
+        );
+        assert_eq!(loop_count(module, "f"), 0);
+        // ((10 * 2 + 7) * 2 + 4) * 2 + 1
+        let ir = module.get_function("main").unwrap().to_string();
+        assert!(ir.contains("return i64 $117"), "{}", ir);
+    }
+
+    #[test]
+    fn unroll_with_remainder() {
+        let src = r"func sum(n: Int64) : Int64 {
+    var s: Int64 = 0;
+    var i: Int64 = 0;
+    while i < n {
+        s = s + i * i;
+        i = i + 1;
+    }
+    return s;
+}
+";
+        let module = optimize(src, "mem2reg,unroll=3,sccp,dce");
+        // The unrolled loop and the remainder loop
+        assert_eq!(loop_count(module, "sum"), 2);
+        let ir = module.get_function("sum").unwrap().to_string();
+        assert_eq!(ir.matches(" mul ").count(), 4, "{}", ir);
+
+        // Check the result for trip counts around multiples of the factor
+        // by folding calls with constant arguments.
+        for n in [-1i64, 0, 1, 2, 3, 4, 7, 8] {
+            let src = format!("{src}func main() : Int64 {{\n    return sum({n});\n}}\n");
+            let module = optimize(
+                &src,
+                "mem2reg,unroll=3,sccp,dce,inline,sccp,dce,unroll=1,sccp,dce,unroll=1,sccp,dce",
+            );
+            let expected: i64 = (0..n).map(|i| i * i).sum();
+            let ir = module.get_function("main").unwrap().to_string();
+            assert!(
+                ir.contains(&format!("return i64 ${}", expected)),
+                "{n}: {ir}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_factor() {
+        assert!(PassManager::from_pipeline("unroll=8").is_ok());
+        assert!(PassManager::from_pipeline("unroll=x").is_err());
+    }
+}
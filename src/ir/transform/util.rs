@@ -0,0 +1,41 @@
+use crate::ir::analysis::Loop;
+use crate::ir::{BasicBlock, Func, Value};
+
+/// Route every entry into `lp` through a new block that just jumps to the
+/// header, and return that block. Phi inputs from the entering blocks are
+/// merged in the new block.
+pub fn insert_preheader<'m>(func: &'m Func<'m>, lp: &Loop<'m>) -> &'m BasicBlock<'m> {
+    let ctx = func.context();
+    let header = lp.header();
+    let entering = lp.entering_blocks();
+    let preheader = ctx.new_basic_block();
+    func.insert_block_before(header, preheader);
+
+    for phi in header.phis() {
+        let incoming: Vec<_> = entering
+            .iter()
+            .map(|pred| (phi.incoming_value(pred).unwrap(), *pred))
+            .collect();
+        for pred in entering.iter() {
+            phi.remove_incoming(pred);
+        }
+        let Some(&(first, _)) = incoming.first() else {
+            continue;
+        };
+        let val = if incoming.iter().all(|(v, _)| v.addr() == first.addr()) {
+            first
+        } else {
+            let merged = ctx.phi(phi.ty(), incoming);
+            preheader.add_instruction(merged);
+            merged
+        };
+        phi.add_incoming(val, preheader);
+    }
+    for pred in entering.iter() {
+        pred.terminator()
+            .unwrap()
+            .replace_successor(header, preheader);
+    }
+    preheader.add_instruction(ctx.jump(header));
+    preheader
+}
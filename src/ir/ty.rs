@@ -0,0 +1,88 @@
+use std::fmt;
+
+/// The type of an IR value. Pointers are opaque: what they point to is
+/// decided by the instruction that loads or stores through them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Type {
+    Void,
+    I1,
+    I8,
+    I16,
+    I32,
+    I64,
+    Ptr,
+}
+
+impl Type {
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            Type::I1 | Type::I8 | Type::I16 | Type::I32 | Type::I64
+        )
+    }
+
+    /// Width in bits of an integer or pointer.
+    pub fn bits(&self) -> u32 {
+        match self {
+            Type::Void => 0,
+            Type::I1 => 1,
+            Type::I8 => 8,
+            Type::I16 => 16,
+            Type::I32 => 32,
+            Type::I64 | Type::Ptr => 64,
+        }
+    }
+
+    /// Number of bytes a value of this type occupies in memory.
+    pub fn size_in_bytes(&self) -> u64 {
+        (self.bits() as u64).div_ceil(8)
+    }
+
+    /// Keep the low `bits()` bits of `value`. Constants of narrow types are
+    /// stored this way.
+    pub fn truncate(&self, value: u64) -> u64 {
+        match self.bits() {
+            0 => 0,
+            64 => value,
+            bits => value & ((1 << bits) - 1),
+        }
+    }
+
+    /// Interpret the low `bits()` bits of `value` as a signed number.
+    pub fn sign_extend(&self, value: u64) -> i64 {
+        match self.bits() {
+            0 => 0,
+            bits => ((value << (64 - bits)) as i64) >> (64 - bits),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Void => write!(f, "void"),
+            Type::I1 => write!(f, "i1"),
+            Type::I8 => write!(f, "i8"),
+            Type::I16 => write!(f, "i16"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::Ptr => write!(f, "ptr"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Type;
+
+    #[test]
+    fn widths() {
+        assert_eq!(Type::I1.size_in_bytes(), 1);
+        assert_eq!(Type::I32.size_in_bytes(), 4);
+        assert_eq!(Type::Ptr.size_in_bytes(), 8);
+        assert_eq!(Type::I8.truncate(0x1ff), 0xff);
+        assert_eq!(Type::I8.sign_extend(0xff), -1);
+        assert_eq!(Type::I1.sign_extend(1), -1);
+        assert_eq!(Type::I64.sign_extend(u64::MAX), -1);
+    }
+}
@@ -1,8 +1,13 @@
+use std::cell::{Ref, RefCell};
 use std::hash::{Hash, Hasher};
 
-pub trait Value {
+use super::{Constant, Inst, Param, Type};
+
+pub trait Value<'m> {
     fn name(&self) -> &str;
 
+    fn ty(&self) -> Type;
+
     fn is_lvalue(&self) -> bool {
         false
     }
@@ -10,19 +15,86 @@ pub trait Value {
     fn addr(&self) -> *const () {
         self as *const Self as *const ()
     }
+
+    fn use_list(&self) -> &UseList<'m>;
+
+    /// Instructions using this value, once per operand slot.
+    fn users(&self) -> Ref<'_, Vec<&'m Inst<'m>>> {
+        self.use_list().users()
+    }
+
+    fn has_users(&self) -> bool {
+        !self.users().is_empty()
+    }
+
+    fn replace_all_uses_with(&self, new: &'m dyn Value<'m>) {
+        assert!(
+            self.addr() != new.addr(),
+            "Cannot replace {} with itself",
+            self.name()
+        );
+        let users = self.users().clone();
+        for user in users {
+            user.replace_operand(self.addr(), new);
+        }
+    }
+
+    fn as_inst(&self) -> Option<&Inst<'m>> {
+        None
+    }
+
+    fn as_constant(&self) -> Option<&Constant<'m>> {
+        None
+    }
+
+    fn as_param(&self) -> Option<&Param<'m>> {
+        None
+    }
 }
 
-impl PartialEq for &dyn Value {
+impl<'m> PartialEq for &dyn Value<'m> {
     fn eq(&self, other: &Self) -> bool {
         // NOTE DO NOT USE `std::ptr::eq`: https://doc.rust-lang.org/std/ptr/fn.eq.html
         std::ptr::addr_eq(*self, *other)
     }
 }
 
-impl Eq for &dyn Value {}
+impl<'m> Eq for &dyn Value<'m> {}
 
-impl Hash for &dyn Value {
+impl<'m> Hash for &dyn Value<'m> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         std::ptr::hash(self.addr(), state)
     }
 }
+
+/// Reverse edges from a value to the instructions that use it. An instruction
+/// using the same value in two operand slots appears twice.
+#[derive(Default)]
+pub struct UseList<'m> {
+    users: RefCell<Vec<&'m Inst<'m>>>,
+}
+
+impl<'m> UseList<'m> {
+    pub fn new() -> UseList<'m> {
+        UseList {
+            users: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn users(&self) -> Ref<'_, Vec<&'m Inst<'m>>> {
+        self.users.borrow()
+    }
+
+    pub fn add(&self, user: &'m Inst<'m>) {
+        self.users.borrow_mut().push(user);
+    }
+
+    pub fn remove(&self, user: &'m Inst<'m>) {
+        let mut users = self.users.borrow_mut();
+        let pos = users
+            .iter()
+            .position(|u| std::ptr::eq(*u, user))
+            .expect("user is not in the use list");
+        users.swap_remove(pos);
+    }
+}
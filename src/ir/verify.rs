@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+
+use super::analysis::AnalysisManager;
+use super::pass::ModulePass;
+use super::{BasicBlock, Func, Inst, InstKind, Module, Type, Value};
+
+/// Pass that aborts compilation if the module is malformed, for checking a
+/// custom pipeline in release builds.
+pub struct Verifier;
+
+impl<'m> ModulePass<'m> for Verifier {
+    fn name(&self) -> &'static str {
+        "verify"
+    }
+
+    fn run_on_module(&mut self, module: &'m Module<'m>, _: &mut AnalysisManager<'m>) -> bool {
+        if let Err(msg) = verify_module(module) {
+            panic!("IR verification failed: {}", msg);
+        }
+        false
+    }
+}
+
+/// Check the structural invariants of every function in the module.
+pub fn verify_module<'m>(module: &Module<'m>) -> Result<(), String> {
+    for func in module.functions().iter() {
+        verify_function(func)?;
+    }
+    Ok(())
+}
+
+/// Check that every block ends with exactly one terminator, that parent links
+/// are consistent, that operand types agree with each instruction, and that
+/// use lists mirror the operands of the instructions in the function.
+#[allow(clippy::mutable_key_type)]
+pub fn verify_function<'m>(func: &'m Func<'m>) -> Result<(), String> {
+    let err = |msg: String| Err(format!("in function @{}: {}", func.name(), msg));
+
+    let blocks: HashSet<&'m BasicBlock<'m>> = func.blocks().iter().copied().collect();
+    let mut insts = HashSet::<*const ()>::new();
+    for block in func.blocks().iter() {
+        if !block.parent().is_some_and(|f| f == func) {
+            return err(format!("{} has a wrong parent", block.name()));
+        }
+        let block_insts = block.instructions();
+        let Some(last) = block_insts.last() else {
+            return err(format!("{} is empty", block.name()));
+        };
+        if !last.is_terminator() {
+            return err(format!("{} does not end with a terminator", block.name()));
+        }
+        for inst in block_insts.iter() {
+            if !inst.parent().is_some_and(|b| b == *block) {
+                return err(format!("'{}' has a wrong parent", inst));
+            }
+            if inst.is_terminator() && !std::ptr::eq(*inst, *last) {
+                return err(format!("terminator in the middle of {}", block.name()));
+            }
+            if inst.is_phi() {
+                check_phi(inst, block).or_else(err)?;
+            }
+            if inst.is_tail_call() {
+                check_tail_call(inst, &block_insts).or_else(err)?;
+            }
+            check_types(func, inst).or_else(err)?;
+            for succ in inst.successors() {
+                if !blocks.contains(&succ) {
+                    return err(format!("'{}' jumps out of the function", inst));
+                }
+            }
+            insts.insert(inst.addr());
+        }
+    }
+
+    // Every operand slot must be mirrored by exactly one entry in the use
+    // list of the operand, and use lists must not mention anything else.
+    let mut expected = HashMap::<*const (), Vec<*const ()>>::new();
+    for inst in func.instructions() {
+        for op in inst.operands() {
+            if let Some(def) = op.as_inst() {
+                if !insts.contains(&def.addr()) {
+                    return err(format!("'{}' uses a value not in the function", inst));
+                }
+            }
+            expected.entry(op.addr()).or_default().push(inst.addr());
+        }
+    }
+    for inst in func.instructions() {
+        check_users(inst, &insts, &expected).or_else(err)?;
+    }
+    for param in func.params().iter() {
+        check_users(*param, &insts, &expected).or_else(err)?;
+    }
+    for constant in func.constants().iter() {
+        check_users(*constant, &insts, &expected).or_else(err)?;
+    }
+    Ok(())
+}
+
+/// Operands must have the types the instruction expects, and the result the
+/// type the operands imply.
+fn check_types<'m>(func: &'m Func<'m>, inst: &'m Inst<'m>) -> Result<(), String> {
+    let mismatch = || Err(format!("type mismatch in '{}'", inst));
+    if inst.operands().iter().any(|op| op.ty() == Type::Void) {
+        return mismatch();
+    }
+    let ok = match &*inst.kind() {
+        InstKind::Alloca(ty) => *ty != Type::Void && inst.ty() == Type::Ptr,
+        InstKind::Store(_, ptr) => ptr.ty() == Type::Ptr && inst.ty() == Type::Void,
+        InstKind::Load(ptr) => ptr.ty() == Type::Ptr && inst.ty() != Type::Void,
+        InstKind::ZExt(val) => {
+            val.ty().is_integer() && inst.ty().is_integer() && val.ty().bits() < inst.ty().bits()
+        }
+        InstKind::Eq(lhs, rhs)
+        | InstKind::Ne(lhs, rhs)
+        | InstKind::Gt(lhs, rhs)
+        | InstKind::Ge(lhs, rhs)
+        | InstKind::Lt(lhs, rhs)
+        | InstKind::Le(lhs, rhs) => lhs.ty() == rhs.ty() && inst.ty() == Type::I1,
+        InstKind::Jump(_) => inst.ty() == Type::Void,
+        InstKind::CJump(cond, _, _) => cond.ty().is_integer() && inst.ty() == Type::Void,
+        InstKind::Call(callee, args) => {
+            args.len() == callee.params().len()
+                && args
+                    .iter()
+                    .zip(callee.params().iter())
+                    .all(|(arg, param)| arg.ty() == param.ty())
+                && inst.ty() == callee.return_type()
+        }
+        InstKind::Return(val) => {
+            val.map_or(Type::Void, |v| v.ty()) == func.return_type() && inst.ty() == Type::Void
+        }
+        InstKind::Phi(incoming) => incoming.iter().all(|(val, _)| val.ty() == inst.ty()),
+        // Arithmetic and bitwise operations
+        kind => {
+            let (lhs, rhs) = kind.binary_operands().unwrap();
+            lhs.ty().is_integer() && lhs.ty() == rhs.ty() && inst.ty() == lhs.ty()
+        }
+    };
+    if !ok {
+        return mismatch();
+    }
+    Ok(())
+}
+
+/// A tail call must be a call followed by a return of its result.
+fn check_tail_call<'m>(call: &'m Inst<'m>, insts: &[&'m Inst<'m>]) -> Result<(), String> {
+    let index = insts.iter().position(|i| std::ptr::eq(*i, call)).unwrap();
+    let returned = insts
+        .get(index + 1)
+        .is_some_and(|next| match &*next.kind() {
+            InstKind::Return(Some(val)) => val.addr() == call.addr(),
+            InstKind::Return(None) => true,
+            _ => false,
+        });
+    if !matches!(&*call.kind(), InstKind::Call(_, _)) || !returned {
+        return Err(format!("'{}' is not in tail position", call));
+    }
+    Ok(())
+}
+
+/// A phi must sit at the top of its block and have exactly one incoming
+/// value for each predecessor.
+fn check_phi<'m>(phi: &'m Inst<'m>, block: &'m BasicBlock<'m>) -> Result<(), String> {
+    if block.index_of(phi).unwrap() >= block.first_non_phi() {
+        return Err(format!("'{}' is not at the top of {}", phi, block.name()));
+    }
+    let InstKind::Phi(incoming) = &*phi.kind() else {
+        unreachable!();
+    };
+    let mut froms: Vec<*const BasicBlock<'m>> =
+        incoming.iter().map(|(_, b)| *b as *const _).collect();
+    let mut preds: Vec<*const BasicBlock<'m>> = block
+        .predecessors()
+        .into_iter()
+        .map(|b| b as *const _)
+        .collect();
+    froms.sort();
+    preds.sort();
+    if froms != preds {
+        return Err(format!(
+            "'{}' does not match the predecessors of {}",
+            phi,
+            block.name()
+        ));
+    }
+    Ok(())
+}
+
+fn check_users<'m>(
+    value: &dyn Value<'m>,
+    insts: &HashSet<*const ()>,
+    expected: &HashMap<*const (), Vec<*const ()>>,
+) -> Result<(), String> {
+    let mut users: Vec<*const ()> = value
+        .users()
+        .iter()
+        .map(|user: &&'m Inst<'m>| user.addr())
+        .filter(|user| insts.contains(user))
+        .collect();
+    let mut want = expected.get(&value.addr()).cloned().unwrap_or_default();
+    users.sort();
+    want.sort();
+    if users != want {
+        return Err(format!("use list of {} is out of date", value.name()));
+    }
+    Ok(())
+}
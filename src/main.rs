@@ -1,10 +1,11 @@
 use std::env;
 use std::fs::File;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 mod aarch64;
 mod ast;
+mod dot;
 mod frontend;
 mod ir;
 
@@ -19,9 +20,50 @@ struct Args {
     /// Dump intermediate representation only
     dump_ir: bool,
 
+    #[arg(long = "emit", value_enum, default_value_t = Emit::Asm)]
+    /// Kind of output to write
+    emit: Emit,
+
     #[arg(long = "no-regalloc")]
     /// Disable register allocation
     no_regalloc: bool,
+
+    #[arg(long = "regalloc", value_enum)]
+    /// Register allocator [default: naive at -O0, linear at -O1, coloring at
+    /// -O2]
+    regalloc: Option<RegAlloc>,
+
+    #[arg(short = 'O', default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    /// Optimization level
+    opt_level: u8,
+
+    #[arg(long = "passes")]
+    /// Comma-separated list of IR passes to run instead of the -O pipeline
+    passes: Option<String>,
+
+    #[arg(long = "print-after-all")]
+    /// Print the IR to stderr after every pass
+    print_after_all: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum Emit {
+    /// Assembly, to `<file>.s`
+    Asm,
+    /// The control-flow graph of each IR function, to `<file>.<func>.dot`
+    CfgDot,
+    /// The label graph of each aarch64 function, to `<file>.<func>.s.dot`
+    AsmCfgDot,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum RegAlloc {
+    /// Keep every value in a stack slot
+    Naive,
+    /// Linear scan, spilling only under register pressure
+    Linear,
+    /// Graph colouring with register coalescing
+    Coloring,
 }
 
 fn get_exec_name() -> String {
@@ -31,6 +73,21 @@ fn get_exec_name() -> String {
     }
 }
 
+fn optimize<'m>(opt: &Args, module: &'m ir::Module<'m>) {
+    let mut pass_manager = match &opt.passes {
+        Some(passes) => match ir::PassManager::from_pipeline(passes) {
+            Ok(pm) => pm,
+            Err(msg) => {
+                eprintln!("{}: error: {}", get_exec_name(), msg);
+                std::process::exit(1);
+            }
+        },
+        None => ir::PassManager::with_opt_level(opt.opt_level),
+    };
+    pass_manager.set_print_after_all(opt.print_after_all);
+    pass_manager.run(module);
+}
+
 fn compile(opt: &Args, file: &str) {
     let src = File::open(file).unwrap();
 
@@ -41,6 +98,9 @@ fn compile(opt: &Args, file: &str) {
     let ir_module = ir::Module::new();
     let ir_codegen = ir::Codegen::new(&ir_module);
     ir_codegen.visit_unit(&unit);
+    debug_assert_eq!(ir::verify_module(&ir_module), Ok(()));
+
+    optimize(opt, &ir_module);
 
     if opt.dump_ir {
         let out = File::create(format!("{}.ir", file)).unwrap();
@@ -49,15 +109,45 @@ fn compile(opt: &Args, file: &str) {
         return;
     }
 
+    if opt.emit == Emit::CfgDot {
+        for func in ir_module.functions().iter() {
+            write_dot(&format!("{}.{}.dot", file, func.name()), func.cfg_graph());
+        }
+        return;
+    }
+
     let aarch64_module = aarch64::Module::new();
     let mut codegen = aarch64::Codegen::new(&aarch64_module);
-    codegen.visit_unit(&ir_module, opt.no_regalloc);
+    let regalloc = match opt.regalloc {
+        Some(RegAlloc::Naive) => aarch64::RegAlloc::Naive,
+        Some(RegAlloc::Linear) => aarch64::RegAlloc::Linear,
+        Some(RegAlloc::Coloring) => aarch64::RegAlloc::Coloring,
+        None => match opt.opt_level {
+            0 => aarch64::RegAlloc::Naive,
+            1 => aarch64::RegAlloc::Linear,
+            _ => aarch64::RegAlloc::Coloring,
+        },
+    };
+    codegen.visit_unit(&ir_module, (!opt.no_regalloc).then_some(regalloc));
+
+    if opt.emit == Emit::AsmCfgDot {
+        for func in codegen.unit().functions().iter() {
+            write_dot(&format!("{}.{}.s.dot", file, func.name()), func.cfg_graph());
+        }
+        return;
+    }
 
     let out = File::create(format!("{}.s", file)).unwrap();
     let mut out = std::io::BufWriter::new(out);
     codegen.unit().dump(&mut out).unwrap();
 }
 
+fn write_dot(path: &str, graph: dot::Graph) {
+    let out = File::create(path).unwrap();
+    let mut out = std::io::BufWriter::new(out);
+    graph.write(&mut out).unwrap();
+}
+
 fn main() {
     let cli = Args::parse();
 